@@ -1,6 +1,7 @@
 #version 430 core
 
 in layout(location = 0) vec4 vertexColor;
+in layout(location = 1) vec3 normal;
 
 out vec4 color;
 
@@ -20,6 +21,11 @@ void main()
     }
     */
 
-    color = vertexColor;
+    vec3 light_direction = normalize(vec3(0.8, -0.5, 0.6));
+    float ambient = 0.2;
+    vec3 surface_normal = length(normal) > 0.0 ? normalize(normal) : vec3(0.0, 1.0, 0.0);
+    float diffuse = max(0.0, dot(surface_normal, -light_direction));
+
+    color = vec4(vertexColor.rgb * min(1.0, ambient + diffuse), vertexColor.a);
 }
 
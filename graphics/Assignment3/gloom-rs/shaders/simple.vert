@@ -2,20 +2,22 @@
 
 in layout(location = 0) vec3 position;
 in layout(location = 1) vec4 color;
-uniform layout(location = 2) mat4 matrix;
-//uniform layout(location = 5) mat4 test;
+in layout(location = 2) vec3 normal;
 
-out layout(location=0) vec4 outVertexColor;
+uniform layout(location = 5) mat4 mvp;
+// The inverse transpose of the model matrix, which keeps normals perpendicular to the surface
+// when the model is scaled differently along each axis
+uniform layout(location = 7) mat3 normal_matrix;
+
+out layout(location = 0) vec4 outVertexColor;
+out layout(location = 1) vec3 outNormal;
 
 void main()
 {
     outVertexColor = color;
+    // Meshes without normals read (0, 0, 0) here, which can't be normalized
+    vec3 world_normal = normal_matrix * normal;
+    outNormal = length(world_normal) > 0.0 ? normalize(world_normal) : vec3(0.0, 1.0, 0.0);
 
-    mat4 mat;
-    mat[0] = vec4(1, 0, 0, 0);
-    mat[1] = vec4(0, 1, 0, 0);
-    mat[2] = vec4(0, 0, 1, 0);
-    mat[3] = vec4(0, 0, 0, 1);
-
-    gl_Position = matrix * vec4(position, 1.0f);
-}
\ No newline at end of file
+    gl_Position = mvp * vec4(position, 1.0f);
+}
@@ -0,0 +1,437 @@
+pub mod blend;
+
+use std::rc::Rc;
+
+use crate::rotation;
+use crate::scene_graph::SceneNode;
+
+// Keyframe animation of scene nodes. An `AnimationClip` is a set of tracks, each moving one
+// property of one node, named by its path below the node the clip is played on. An `Animator`
+// plays a clip on a particular subtree: it finds the nodes once, keeps the time, and writes the
+// sampled values into their `position`, `rotation` and `scale` every time it's applied.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Property {
+    Translation,
+    Rotation,
+    Scale,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Interpolation {
+    Step,         // Hold each key until the next
+    Linear,       // Straight between keys. Rotations are normalized afterwards, but don't turn at an even rate.
+    Slerp,        // Rotations at an even rate along the shorter arc. The same as `Linear` for anything else.
+    CubicHermite, // Smooth through the keys, with a tangent going in and out of each
+}
+
+// What happens outside of a clip's duration
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlaybackMode {
+    Loop,     // Start over from the beginning
+    PingPong, // Play backwards to the beginning, then forwards again
+    Clamp,    // Hold the last pose
+}
+
+// Keyframes for one property
+#[derive(Clone, Debug)]
+pub struct Keyframes {
+    pub property      : Property,
+    pub interpolation : Interpolation,
+    pub times         : Vec<f32>,        // In seconds, increasing
+    pub values        : Vec<glm::Vec4>,  // XYZ, or a quaternion as XYZW for rotations. Cubic Hermite
+                                         // has three per key: in-tangent, value and out-tangent,
+                                         // with the tangents per second.
+}
+
+// Keyframes for the node at `target`, a path as for `SceneNode::find`. Empty for the node the clip is played on.
+#[derive(Clone, Debug)]
+pub struct Track {
+    pub target    : String,
+    pub keyframes : Keyframes,
+}
+
+#[derive(Clone, Debug)]
+pub struct AnimationClip {
+    pub name     : Option<String>,
+    pub tracks   : Vec<Track>,
+    pub duration : f32,   // In seconds, up to the last key of any track
+}
+
+fn quat_to_vec4(q: &glm::Quat) -> glm::Vec4 {
+    q.coords
+}
+
+fn vec4_to_quat(v: &glm::Vec4) -> glm::Quat {
+    glm::quat(v.x, v.y, v.z, v.w)
+}
+
+impl Keyframes {
+    // Keys in the order of their times, which have to be increasing. Cubic Hermite gets Catmull-Rom
+    // tangents, which make the curve go smoothly through every key, flattening out at the first and the last.
+    pub fn new(property: Property, interpolation: Interpolation, keys: &[(f32, glm::Vec4)]) -> Result<Self, String> {
+        if keys.is_empty() {
+            return Err(format!("keyframes for {:?} need at least one key", property));
+        }
+        if let Some(i) = (1..keys.len()).find(|&i| keys[i].0 <= keys[i - 1].0) {
+            return Err(format!("key {} at {}s doesn't come after the one before it, at {}s", i, keys[i].0, keys[i - 1].0));
+        }
+        let times: Vec<f32> = keys.iter().map(|(time, _)| *time).collect();
+        let mut values: Vec<glm::Vec4> = keys.iter().map(|(_, value)| *value).collect();
+        if property == Property::Rotation {
+            // Neighbouring keys on the same side of the quaternion sphere, so nothing takes the long way round
+            for i in 1..values.len() {
+                if glm::dot(&values[i - 1], &values[i]) < 0.0 {
+                    values[i] = -values[i];
+                }
+            }
+        }
+
+        if interpolation == Interpolation::CubicHermite {
+            let last = values.len().saturating_sub(1);
+            let tangent = |i: usize| {
+                if i == 0 || i == last {
+                    return glm::vec4(0.0, 0.0, 0.0, 0.0);
+                }
+                (values[i + 1] - values[i - 1]) / (times[i + 1] - times[i - 1])
+            };
+            values = (0..values.len())
+                .flat_map(|i| [tangent(i), values[i], tangent(i)])
+                .collect();
+        }
+        Ok(Keyframes { property, interpolation, times, values })
+    }
+
+    pub fn translation(interpolation: Interpolation, keys: &[(f32, glm::Vec3)]) -> Result<Self, String> {
+        let keys: Vec<(f32, glm::Vec4)> = keys.iter().map(|(time, v)| (*time, glm::vec4(v.x, v.y, v.z, 0.0))).collect();
+        Keyframes::new(Property::Translation, interpolation, &keys)
+    }
+
+    pub fn rotation(interpolation: Interpolation, keys: &[(f32, glm::Quat)]) -> Result<Self, String> {
+        let keys: Vec<(f32, glm::Vec4)> = keys.iter().map(|(time, q)| (*time, quat_to_vec4(q))).collect();
+        Keyframes::new(Property::Rotation, interpolation, &keys)
+    }
+
+    pub fn scale(interpolation: Interpolation, keys: &[(f32, glm::Vec3)]) -> Result<Self, String> {
+        let keys: Vec<(f32, glm::Vec4)> = keys.iter().map(|(time, v)| (*time, glm::vec4(v.x, v.y, v.z, 0.0))).collect();
+        Keyframes::new(Property::Scale, interpolation, &keys)
+    }
+
+    // Turning steadily about `axis`, `turns` whole times over `duration` seconds, for things like
+    // rotors and wheels. Looped, it goes round and round without a hitch. The duration has to be positive.
+    pub fn spin(axis: &glm::Vec3, turns: f32, duration: f32) -> Self {
+        if duration <= 0.0 {
+            panic!("A spin has to last a while, not {}s", duration);
+        }
+        // A third of a turn between keys, as slerp can't tell which way to go for half a turn or more
+        let keys = (3.0 * turns).ceil().max(1.0) as usize;
+        let keys: Vec<(f32, glm::Quat)> = (0..=keys)
+            .map(|i| {
+                let t = i as f32 / keys as f32;
+                (t * duration, glm::quat_angle_axis(t * turns * std::f32::consts::TAU, axis))
+            })
+            .collect();
+        Keyframes::rotation(Interpolation::Slerp, &keys).unwrap()
+    }
+
+    pub fn duration(&self) -> f32 {
+        self.times.last().cloned().unwrap_or(0.0)
+    }
+
+    // The value at `time`, holding the first and last keys outside of the animated range
+    pub fn sample(&self, time: f32) -> glm::Vec4 {
+        let value = |key: usize| match self.interpolation {
+            Interpolation::CubicHermite => self.values[key * 3 + 1],
+            _ => self.values[key],
+        };
+        let last = self.times.len() - 1;
+        if time <= self.times[0] {
+            return value(0);
+        }
+        if time >= self.times[last] {
+            return value(last);
+        }
+
+        let next = self.times.partition_point(|&t| t <= time);
+        let key = next - 1;
+        let dt = self.times[next] - self.times[key];
+        let s = (time - self.times[key]) / dt;
+        let is_rotation = self.property == Property::Rotation;
+
+        match self.interpolation {
+            Interpolation::Step => value(key),
+            Interpolation::Slerp if is_rotation => {
+                quat_to_vec4(&rotation::slerp(&vec4_to_quat(&value(key)), &vec4_to_quat(&value(next)), s))
+            }
+            Interpolation::Linear | Interpolation::Slerp => {
+                let result = glm::lerp(&value(key), &value(next), s);
+                if is_rotation { glm::normalize(&result) } else { result }
+            }
+            Interpolation::CubicHermite => {
+                // The tangents are per second, so they're scaled by the length of the interval
+                let out_tangent = self.values[key * 3 + 2];
+                let in_tangent = self.values[next * 3];
+                let (s2, s3) = (s * s, s * s * s);
+                let result = value(key) * (2.0 * s3 - 3.0 * s2 + 1.0)
+                    + out_tangent * (dt * (s3 - 2.0 * s2 + s))
+                    + value(next) * (-2.0 * s3 + 3.0 * s2)
+                    + in_tangent * (dt * (s3 - s2));
+                if is_rotation { glm::normalize(&result) } else { result }
+            }
+        }
+    }
+
+    // Set the property on the node to its value at `time`
+    pub fn apply(&self, time: f32, node: &mut SceneNode) {
+        let value = self.sample(time);
+        match self.property {
+            Property::Translation => node.position = value.xyz(),
+            Property::Rotation    => node.rotation = glm::quat_normalize(&vec4_to_quat(&value)),
+            Property::Scale       => node.scale = value.xyz(),
+        }
+    }
+}
+
+impl Track {
+    pub fn new(target: &str, keyframes: Keyframes) -> Self {
+        Track { target: target.to_string(), keyframes }
+    }
+}
+
+impl AnimationClip {
+    pub fn new(name: &str, tracks: Vec<Track>) -> Self {
+        let duration = tracks.iter().map(|track| track.keyframes.duration()).fold(0.0, f32::max);
+        AnimationClip { name: Some(name.to_string()), tracks, duration }
+    }
+}
+
+impl PlaybackMode {
+    // Where in a clip of length `duration` we are, `time` seconds after starting it
+    pub fn clip_time(&self, time: f32, duration: f32) -> f32 {
+        if duration <= 0.0 {
+            return 0.0;
+        }
+        match self {
+            PlaybackMode::Loop => time.rem_euclid(duration),
+            PlaybackMode::PingPong => {
+                let t = time.rem_euclid(2.0 * duration);
+                if t > duration { 2.0 * duration - t } else { t }
+            }
+            PlaybackMode::Clamp => time.clamp(0.0, duration),
+        }
+    }
+}
+
+pub struct Animator {
+    pub clip    : Rc<AnimationClip>,
+    pub mode    : PlaybackMode,
+    pub time    : f32,    // Seconds since the clip started, scaled by `speed`
+    pub speed   : f32,    // 1 for as fast as it was made, negative to play it backwards
+    targets     : Vec<Option<*mut SceneNode>>, // The node each track moves, None if it wasn't found
+}
+
+impl Animator {
+    // Play `clip` on `root` and the nodes below it. The nodes have to outlive the animator,
+    // which scene nodes do, as they're never freed.
+    pub fn new(clip: Rc<AnimationClip>, root: &mut SceneNode, mode: PlaybackMode) -> Self {
+        let targets = clip.tracks.iter()
+            .map(|track| {
+                let node = if track.target.is_empty() { Some(&mut *root) } else { root.find(&track.target) };
+                node.map(|node| node as *mut SceneNode)
+            })
+            .collect();
+        Animator { clip, mode, time: 0.0, speed: 1.0, targets }
+    }
+
+    // The targets of the tracks that don't move anything, as no node was found for them
+    pub fn missing_targets(&self) -> Vec<&str> {
+        self.clip.tracks.iter().zip(&self.targets)
+            .filter(|(_, target)| target.is_none())
+            .map(|(track, _)| track.target.as_str())
+            .collect()
+    }
+
+    pub fn advance(&mut self, delta_time: f32) {
+        self.time += delta_time * self.speed;
+    }
+
+    // Where in the clip we are, after looping or clamping
+    pub fn clip_time(&self) -> f32 {
+        self.mode.clip_time(self.time, self.clip.duration)
+    }
+
+    // Whether a clamped clip has played to its end. Looping ones never finish.
+    pub fn is_finished(&self) -> bool {
+        self.mode == PlaybackMode::Clamp && (self.time >= self.clip.duration || (self.speed < 0.0 && self.time <= 0.0))
+    }
+
+    // The node and value of every track that found its node, at `time` into the clip
+    fn samples(&self, time: f32) -> impl Iterator<Item = (*mut SceneNode, Property, glm::Vec4)> + '_ {
+        self.clip.tracks.iter().zip(&self.targets)
+            .filter_map(move |(track, target)| target.map(|node| (node, track.keyframes.property, track.keyframes.sample(time))))
+    }
+
+    // Pose the nodes as they are at the current time
+    pub fn apply(&self) {
+        let time = self.clip_time();
+        for (track, target) in self.clip.tracks.iter().zip(&self.targets) {
+            if let Some(node) = target {
+                track.keyframes.apply(time, unsafe { &mut **node });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: &glm::Vec4, b: &glm::Vec4) -> bool {
+        glm::distance(a, b) < 1e-5
+    }
+
+    fn ramp(interpolation: Interpolation) -> Keyframes {
+        Keyframes::translation(interpolation, &[
+            (0.0, glm::vec3(0.0, 0.0, 0.0)),
+            (1.0, glm::vec3(2.0, 0.0, 0.0)),
+            (3.0, glm::vec3(2.0, 4.0, 0.0)),
+        ]).unwrap()
+    }
+
+    #[test]
+    fn step_holds_each_key_until_the_next() {
+        let keys = ramp(Interpolation::Step);
+        assert!(close(&keys.sample(0.5), &glm::vec4(0.0, 0.0, 0.0, 0.0)));
+        assert!(close(&keys.sample(1.0), &glm::vec4(2.0, 0.0, 0.0, 0.0)));
+        assert!(close(&keys.sample(2.9), &glm::vec4(2.0, 0.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn linear_goes_straight_between_keys_and_holds_the_ends() {
+        let keys = ramp(Interpolation::Linear);
+        assert!(close(&keys.sample(0.25), &glm::vec4(0.5, 0.0, 0.0, 0.0)));
+        assert!(close(&keys.sample(2.0), &glm::vec4(2.0, 2.0, 0.0, 0.0)));
+        assert!(close(&keys.sample(-1.0), &glm::vec4(0.0, 0.0, 0.0, 0.0)));
+        assert!(close(&keys.sample(10.0), &glm::vec4(2.0, 4.0, 0.0, 0.0)));
+    }
+
+    #[test]
+    fn slerp_turns_at_an_even_rate() {
+        let axis = glm::vec3(0.0, 1.0, 0.0);
+        let keys = Keyframes::rotation(Interpolation::Slerp, &[
+            (0.0, glm::quat_identity()),
+            (1.0, glm::quat_angle_axis(2.0, &axis)),
+        ]).unwrap();
+        for t in [0.1, 0.25, 0.5, 0.8] {
+            let expected = quat_to_vec4(&glm::quat_angle_axis(2.0 * t, &axis));
+            assert!(close(&keys.sample(t), &expected), "{}", t);
+        }
+
+        // Linear gets there too, but not evenly
+        let linear = Keyframes::rotation(Interpolation::Linear, &[
+            (0.0, glm::quat_identity()),
+            (1.0, glm::quat_angle_axis(2.0, &axis)),
+        ]).unwrap();
+        assert!((glm::length(&linear.sample(0.25)) - 1.0).abs() < 1e-5);
+        assert!(!close(&linear.sample(0.25), &quat_to_vec4(&glm::quat_angle_axis(0.5, &axis))));
+    }
+
+    #[test]
+    fn rotations_take_the_short_way_round() {
+        // The same orientation as the first key, from the other side of the quaternion sphere
+        let q = glm::quat_angle_axis(0.5, &glm::vec3(1.0, 0.0, 0.0));
+        let keys = Keyframes::rotation(Interpolation::Slerp, &[(0.0, q), (1.0, glm::quat(-q.i, -q.j, -q.k, -q.w))]).unwrap();
+        assert!(close(&keys.sample(0.5), &quat_to_vec4(&q)));
+    }
+
+    #[test]
+    fn cubic_hermite_goes_smoothly_through_the_keys() {
+        let keys = ramp(Interpolation::CubicHermite);
+        assert_eq!(keys.values.len(), 9);
+        for (time, value) in [(0.0, glm::vec4(0.0, 0.0, 0.0, 0.0)), (1.0, glm::vec4(2.0, 0.0, 0.0, 0.0)), (3.0, glm::vec4(2.0, 4.0, 0.0, 0.0))] {
+            assert!(close(&keys.sample(time), &value), "{}", time);
+        }
+
+        // Catmull-Rom tangent at the middle key, and flat at the ends
+        assert!(close(&keys.values[3], &glm::vec4(2.0 / 3.0, 4.0 / 3.0, 0.0, 0.0)));
+        assert!(close(&keys.values[0], &glm::vec4(0.0, 0.0, 0.0, 0.0)));
+        let slope = |t: f32| (keys.sample(t + 1e-3) - keys.sample(t - 1e-3)) / 2e-3;
+        assert!(glm::distance(&slope(1.0), &keys.values[5]) < 1e-2, "{}", slope(1.0));
+        assert!(glm::length(&(keys.sample(1e-3) - keys.sample(0.0))) / 1e-3 < 0.05);
+
+        // Halfway between two keys with flat tangents is halfway between the values
+        let flat = Keyframes::translation(Interpolation::CubicHermite, &[(0.0, glm::vec3(0.0, 0.0, 0.0)), (2.0, glm::vec3(4.0, 0.0, 0.0))]).unwrap();
+        assert!(close(&flat.sample(1.0), &glm::vec4(2.0, 0.0, 0.0, 0.0)));
+        assert!(flat.sample(0.5).x < 1.0);
+    }
+
+    #[test]
+    fn keys_have_to_be_there_and_in_order() {
+        assert!(Keyframes::translation(Interpolation::Linear, &[]).is_err());
+        let v = glm::vec3(0.0, 0.0, 0.0);
+        assert!(Keyframes::translation(Interpolation::Linear, &[(0.0, v)]).is_ok());
+        let error = Keyframes::scale(Interpolation::Step, &[(0.0, v), (1.0, v), (0.5, v)]).unwrap_err();
+        assert!(error.contains("key 2"), "{}", error);
+        assert!(Keyframes::rotation(Interpolation::Slerp, &[(1.0, glm::quat_identity()), (1.0, glm::quat_identity())]).is_err());
+    }
+
+    #[test]
+    fn animator_poses_its_nodes() {
+        let mut root = SceneNode::named("root");
+        let arm = SceneNode::named("arm");
+        root.add_child(&arm);
+        let turn = glm::quat_angle_axis(1.0, &glm::vec3(0.0, 0.0, 1.0));
+        let clip = Rc::new(AnimationClip::new("wave", vec![
+            Track::new("", Keyframes::translation(Interpolation::Linear, &[
+                (0.0, glm::vec3(0.0, 0.0, 0.0)),
+                (2.0, glm::vec3(4.0, 0.0, 0.0)),
+            ]).unwrap()),
+            Track::new("arm", Keyframes::rotation(Interpolation::Slerp, &[(0.0, glm::quat_identity()), (2.0, turn)]).unwrap()),
+            Track::new("arm", Keyframes::scale(Interpolation::Step, &[
+                (0.0, glm::vec3(1.0, 1.0, 1.0)),
+                (1.0, glm::vec3(2.0, 3.0, 4.0)),
+            ]).unwrap()),
+            Track::new("leg", Keyframes::translation(Interpolation::Linear, &[(0.0, glm::vec3(1.0, 1.0, 1.0))]).unwrap()),
+        ]));
+        assert_eq!(clip.duration, 2.0);
+
+        let mut animator = Animator::new(clip, &mut root, PlaybackMode::Loop);
+        assert_eq!(animator.missing_targets(), vec!["leg"]);
+        animator.advance(3.0);
+        animator.apply();
+
+        // A second into the loop
+        assert!(glm::distance(&root.position, &glm::vec3(2.0, 0.0, 0.0)) < 1e-5, "{}", root.position);
+        let halfway = glm::quat_angle_axis(0.5, &glm::vec3(0.0, 0.0, 1.0));
+        assert!(glm::distance(&arm.rotation.coords, &halfway.coords) < 1e-5, "{:?}", arm.rotation);
+        assert_eq!(arm.scale, glm::vec3(2.0, 3.0, 4.0));
+        assert_eq!(arm.position, glm::vec3(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn loop_starts_over() {
+        let mode = PlaybackMode::Loop;
+        assert!((mode.clip_time(0.5, 2.0) - 0.5).abs() < 1e-6);
+        assert!((mode.clip_time(5.0, 2.0) - 1.0).abs() < 1e-6);
+        assert!((mode.clip_time(-0.5, 2.0) - 1.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn ping_pong_plays_back_and_forth() {
+        let mode = PlaybackMode::PingPong;
+        assert!((mode.clip_time(1.5, 2.0) - 1.5).abs() < 1e-6);
+        assert!((mode.clip_time(2.5, 2.0) - 1.5).abs() < 1e-6);
+        assert!((mode.clip_time(4.5, 2.0) - 0.5).abs() < 1e-6);
+        assert!((mode.clip_time(-0.5, 2.0) - 0.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn clamp_holds_the_ends() {
+        let mode = PlaybackMode::Clamp;
+        assert_eq!(mode.clip_time(1.0, 2.0), 1.0);
+        assert_eq!(mode.clip_time(7.0, 2.0), 2.0);
+        assert_eq!(mode.clip_time(-1.0, 2.0), 0.0);
+        // Nothing to play at all
+        assert_eq!(PlaybackMode::Loop.clip_time(3.0, 0.0), 0.0);
+    }
+}
@@ -0,0 +1,318 @@
+use std::collections::HashMap;
+
+use super::{quat_to_vec4, vec4_to_quat, Animator, Property};
+use crate::rotation;
+use crate::scene_graph::SceneNode;
+
+// Playing several clips on the same nodes at once. An `AnimationMixer` has layers, applied from
+// the first to the last. An override layer blends its clips together by their weights and lays the
+// result over what the layers before it made, as much as its own weight says. An additive layer
+// adds how far each of its clips has moved from its first keys, for things like a wobble on top of
+// whatever else is going on.
+//
+// Clips are faded in and out over a while rather than switched, so nothing snaps from one pose to
+// the next. Where nothing is playing, a node goes back to how it was before the mixer first moved it.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LayerMode {
+    Override,
+    Additive,
+}
+
+// A weight going smoothly from `from` to `to` over `duration` seconds
+#[derive(Clone, Copy, Debug)]
+struct Fade {
+    from     : f32,
+    to       : f32,
+    duration : f32,
+    elapsed  : f32,
+}
+
+impl Fade {
+    fn weight(&self) -> f32 {
+        let t = if self.duration > 0.0 { (self.elapsed / self.duration).min(1.0) } else { 1.0 };
+        self.from + (self.to - self.from) * t * t * (3.0 - 2.0 * t)
+    }
+
+    fn is_done(&self) -> bool {
+        self.elapsed >= self.duration
+    }
+}
+
+pub struct Playing {
+    pub animator : Animator,
+    pub weight   : f32,          // How much it counts right now
+    fade         : Option<Fade>,
+    stopping     : bool,         // Removed once faded out
+}
+
+pub struct Layer {
+    pub mode    : LayerMode,
+    pub weight  : f32,          // 0 to 1
+    pub playing : Vec<Playing>,
+}
+
+type Key = (*mut SceneNode, Property);
+
+pub struct AnimationMixer {
+    pub layers : Vec<Layer>,
+    rest       : HashMap<Key, glm::Vec4>, // Each property as it was before it was first animated
+}
+
+impl Layer {
+    pub fn new(mode: LayerMode, weight: f32) -> Self {
+        Layer { mode, weight, playing: vec![] }
+    }
+
+    // Start playing the animator's clip at `weight`, alongside any others
+    pub fn play(&mut self, animator: Animator, weight: f32) {
+        self.playing.push(Playing { animator, weight, fade: None, stopping: false });
+    }
+
+    // Start playing the animator's clip, fading it in over `duration` seconds
+    pub fn fade_in(&mut self, animator: Animator, duration: f32) {
+        self.play(animator, 0.0);
+        self.fade_to(self.playing.len() - 1, 1.0, duration);
+    }
+
+    // The index of the playing clip with the given name
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.playing.iter().position(|playing| playing.animator.clip.name.as_deref() == Some(name))
+    }
+
+    // Change the weight of the clip at `index` to `weight` over `duration` seconds
+    pub fn fade_to(&mut self, index: usize, weight: f32, duration: f32) {
+        let playing = &mut self.playing[index];
+        playing.fade = Some(Fade { from: playing.weight, to: weight, duration, elapsed: 0.0 });
+        playing.weight = playing.fade.unwrap().weight();
+    }
+
+    // Fade the clip at `index` out over `duration` seconds, then stop playing it
+    pub fn stop(&mut self, index: usize, duration: f32) {
+        self.fade_to(index, 0.0, duration);
+        self.playing[index].stopping = true;
+    }
+
+    // Switch to the animator's clip, fading everything else out as it fades in
+    pub fn crossfade(&mut self, animator: Animator, duration: f32) {
+        for index in 0..self.playing.len() {
+            self.stop(index, duration);
+        }
+        self.fade_in(animator, duration);
+    }
+
+    pub fn advance(&mut self, delta_time: f32) {
+        for playing in &mut self.playing {
+            playing.animator.advance(delta_time);
+            if let Some(fade) = playing.fade.as_mut() {
+                fade.elapsed += delta_time;
+                playing.weight = fade.weight();
+                if fade.is_done() {
+                    playing.fade = None;
+                }
+            }
+        }
+        self.playing.retain(|playing| !(playing.stopping && playing.fade.is_none()));
+    }
+}
+
+fn read(node: *mut SceneNode, property: Property) -> glm::Vec4 {
+    let node = unsafe { &*node };
+    match property {
+        Property::Translation => glm::vec4(node.position.x, node.position.y, node.position.z, 0.0),
+        Property::Rotation    => quat_to_vec4(&node.rotation),
+        Property::Scale       => glm::vec4(node.scale.x, node.scale.y, node.scale.z, 0.0),
+    }
+}
+
+fn write(node: *mut SceneNode, property: Property, value: &glm::Vec4) {
+    let node = unsafe { &mut *node };
+    match property {
+        Property::Translation => node.position = value.xyz(),
+        Property::Rotation    => node.rotation = glm::quat_normalize(&vec4_to_quat(value)),
+        Property::Scale       => node.scale = value.xyz(),
+    }
+}
+
+// From `a` at t = 0 to `b` at t = 1
+fn mix(property: Property, a: &glm::Vec4, b: &glm::Vec4, t: f32) -> glm::Vec4 {
+    match property {
+        Property::Rotation => quat_to_vec4(&rotation::slerp(&vec4_to_quat(a), &vec4_to_quat(b), t)),
+        _ => glm::lerp(a, b, t),
+    }
+}
+
+// `base` moved `weight` times as far as `reference` is from `value`
+fn add(property: Property, base: &glm::Vec4, value: &glm::Vec4, reference: &glm::Vec4, weight: f32) -> glm::Vec4 {
+    match property {
+        Property::Rotation => {
+            let difference = glm::quat_inverse(&vec4_to_quat(reference)) * vec4_to_quat(value);
+            let difference = rotation::slerp(&glm::quat_identity(), &difference, weight);
+            quat_to_vec4(&glm::quat_normalize(&(vec4_to_quat(base) * difference)))
+        }
+        _ => base + (value - reference) * weight,
+    }
+}
+
+impl AnimationMixer {
+    pub fn new() -> Self {
+        AnimationMixer { layers: vec![], rest: HashMap::new() }
+    }
+
+    // Add a layer over the others, returning its index
+    pub fn add_layer(&mut self, mode: LayerMode, weight: f32) -> usize {
+        self.layers.push(Layer::new(mode, weight));
+        self.layers.len() - 1
+    }
+
+    pub fn advance(&mut self, delta_time: f32) {
+        for layer in &mut self.layers {
+            layer.advance(delta_time);
+        }
+    }
+
+    // Pose the nodes with all the layers blended together
+    pub fn apply(&mut self) {
+        let rest = &mut self.rest;
+        let mut pose: HashMap<Key, glm::Vec4> = HashMap::new();
+        let mut base = |pose: &HashMap<Key, glm::Vec4>, key: Key| match pose.get(&key) {
+            Some(value) => *value,
+            None => *rest.entry(key).or_insert_with(|| read(key.0, key.1)),
+        };
+
+        for layer in &self.layers {
+            match layer.mode {
+                LayerMode::Override => {
+                    // The weighted average of the clips, rotations kept on one side of the quaternion sphere
+                    let mut sums: HashMap<Key, (glm::Vec4, f32)> = HashMap::new();
+                    for playing in layer.playing.iter().filter(|playing| playing.weight > 0.0) {
+                        for (node, property, value) in playing.animator.samples(playing.animator.clip_time()) {
+                            let (sum, total) = sums.entry((node, property)).or_insert((glm::vec4(0.0, 0.0, 0.0, 0.0), 0.0));
+                            let value = if property == Property::Rotation && glm::dot(sum, &value) < 0.0 { -value } else { value };
+                            *sum += value * playing.weight;
+                            *total += playing.weight;
+                        }
+                    }
+                    for (key, (sum, total)) in sums {
+                        let average = sum / total;
+                        let average = if key.1 == Property::Rotation { glm::normalize(&average) } else { average };
+                        // Clips fading in or out on their own only count for as much as they weigh
+                        let amount = (layer.weight * total.min(1.0)).clamp(0.0, 1.0);
+                        let value = mix(key.1, &base(&pose, key), &average, amount);
+                        pose.insert(key, value);
+                    }
+                }
+                LayerMode::Additive => {
+                    for playing in &layer.playing {
+                        let weight = layer.weight * playing.weight;
+                        let samples = playing.animator.samples(playing.animator.clip_time());
+                        for ((node, property, value), (_, _, reference)) in samples.zip(playing.animator.samples(0.0)) {
+                            let key = (node, property);
+                            let value = add(property, &base(&pose, key), &value, &reference, weight);
+                            pose.insert(key, value);
+                        }
+                    }
+                }
+            }
+        }
+
+        // Whatever was animated before but isn't any more goes back to how it was
+        for (key, value) in self.rest.iter() {
+            pose.entry(*key).or_insert(*value);
+        }
+        for ((node, property), value) in pose {
+            write(node, property, &value);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::animation::{AnimationClip, Interpolation, Keyframes, PlaybackMode, Track};
+    use crate::scene_graph::{Node, SceneNode};
+
+    // A root with an arm below it, which the clips move
+    fn arm() -> (Node, Node) {
+        let mut root = SceneNode::named("root");
+        let mut arm = SceneNode::named("arm");
+        arm.position = glm::vec3(5.0, 0.0, 0.0);
+        root.add_child(&arm);
+        (root, arm)
+    }
+
+    // Moves the arm from `from` to `to` over a second
+    fn slide(name: &str, from: glm::Vec3, to: glm::Vec3) -> Rc<AnimationClip> {
+        Rc::new(AnimationClip::new(name, vec![
+            Track::new("arm", Keyframes::translation(Interpolation::Linear, &[(0.0, from), (1.0, to)]).unwrap()),
+        ]))
+    }
+
+    fn hold(name: &str, at: glm::Vec3) -> Rc<AnimationClip> {
+        slide(name, at, at)
+    }
+
+    #[test]
+    fn crossfade_weights_add_up_to_one() {
+        let (mut root, arm) = arm();
+        let mut mixer = AnimationMixer::new();
+        let layer = mixer.add_layer(LayerMode::Override, 1.0);
+        mixer.layers[layer].play(Animator::new(hold("a", glm::vec3(1.0, 0.0, 0.0)), &mut root, PlaybackMode::Loop), 1.0);
+        mixer.layers[layer].crossfade(Animator::new(hold("b", glm::vec3(3.0, 0.0, 0.0)), &mut root, PlaybackMode::Loop), 1.0);
+
+        for step in 0..12 {
+            let total: f32 = mixer.layers[layer].playing.iter().map(|playing| playing.weight).sum();
+            assert!((total - 1.0).abs() < 1e-5, "step {}: {}", step, total);
+            mixer.apply();
+            let weight_b = mixer.layers[layer].find("b").map_or(0.0, |b| mixer.layers[layer].playing[b].weight);
+            assert!((arm.position.x - (1.0 + 2.0 * weight_b)).abs() < 1e-5, "step {}: {}", step, arm.position.x);
+            mixer.advance(0.1);
+        }
+
+        // The one faded out is gone
+        assert_eq!(mixer.layers[layer].playing.len(), 1);
+        assert_eq!(mixer.layers[layer].find("b"), Some(0));
+    }
+
+    #[test]
+    fn additive_layer_at_no_weight_changes_nothing() {
+        let (mut root, arm) = arm();
+        let mut mixer = AnimationMixer::new();
+        let base = mixer.add_layer(LayerMode::Override, 1.0);
+        let wobble = mixer.add_layer(LayerMode::Additive, 0.0);
+        mixer.layers[base].play(Animator::new(slide("base", glm::vec3(0.0, 0.0, 0.0), glm::vec3(2.0, 0.0, 0.0)), &mut root, PlaybackMode::Loop), 1.0);
+        mixer.layers[wobble].play(Animator::new(slide("wobble", glm::vec3(0.0, 0.0, 0.0), glm::vec3(0.0, 1.0, 0.0)), &mut root, PlaybackMode::Loop), 1.0);
+
+        mixer.advance(0.5);
+        mixer.apply();
+        assert!(glm::distance(&arm.position, &glm::vec3(1.0, 0.0, 0.0)) < 1e-5, "{}", arm.position);
+
+        // And at full weight, it adds how far it has moved from its first key
+        mixer.layers[wobble].weight = 1.0;
+        mixer.apply();
+        assert!(glm::distance(&arm.position, &glm::vec3(1.0, 0.5, 0.0)) < 1e-5, "{}", arm.position);
+    }
+
+    #[test]
+    fn nodes_go_back_to_rest_once_a_clip_stops() {
+        let (mut root, arm) = arm();
+        let mut mixer = AnimationMixer::new();
+        let layer = mixer.add_layer(LayerMode::Override, 1.0);
+        mixer.layers[layer].play(Animator::new(hold("away", glm::vec3(-4.0, 2.0, 0.0)), &mut root, PlaybackMode::Loop), 1.0);
+        mixer.apply();
+        assert!(glm::distance(&arm.position, &glm::vec3(-4.0, 2.0, 0.0)) < 1e-5, "{}", arm.position);
+
+        mixer.layers[layer].stop(0, 0.5);
+        mixer.advance(0.25);
+        mixer.apply();
+        assert!(arm.position.x > -4.0 && arm.position.x < 5.0, "{}", arm.position);
+
+        mixer.advance(0.5);
+        assert!(mixer.layers[layer].playing.is_empty());
+        mixer.apply();
+        assert!(glm::distance(&arm.position, &glm::vec3(5.0, 0.0, 0.0)) < 1e-5, "{}", arm.position);
+    }
+}
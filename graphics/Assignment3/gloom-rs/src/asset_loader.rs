@@ -0,0 +1,188 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+use crate::bvh::Bvh;
+use crate::gltf_import::{self, GltfScene};
+use crate::mesh::{self, Helicopter, Mesh, Terrain};
+
+// Parses models on a pool of worker threads, so the render thread never has to wait on the disk.
+// Nothing in here touches OpenGL: the finished meshes are handed back through `poll`, and it is
+// up to the render thread to upload them to a VAO whenever it suits the frame.
+
+pub type AssetId = usize;
+
+// What to load, and where to load it from
+pub enum AssetRequest {
+    Terrain(String),
+    Helicopter(String),
+    // A simplified version of a terrain, keeping about `ratio` of its triangles
+    TerrainLod { path: String, ratio: f32 },
+    // A .gltf or .glb file, with its node hierarchy and animations
+    Gltf(String),
+}
+
+// The CPU-side result of a request, ready to be uploaded
+pub enum Asset {
+    Terrain(Box<LoadedTerrain>),
+    Helicopter(Box<Helicopter>),
+    Gltf(Box<GltfScene>),
+}
+
+// Terrain comes with a BVH, built here so the render thread doesn't have to
+pub struct LoadedTerrain {
+    pub mesh : Mesh,
+    pub bvh  : Bvh,
+}
+
+pub struct LoadedAsset {
+    pub id     : AssetId,
+    pub result : Result<Asset, String>,
+}
+
+struct Job {
+    id      : AssetId,
+    request : AssetRequest,
+}
+
+pub struct AssetLoader {
+    jobs     : Option<mpsc::Sender<Job>>,
+    finished : mpsc::Receiver<LoadedAsset>,
+    workers  : Vec<thread::JoinHandle<()>>,
+    next_id  : AssetId,
+    pending  : usize,
+}
+
+impl AssetLoader {
+    pub fn new(num_workers: usize) -> Self {
+        let (job_sender, job_receiver) = mpsc::channel::<Job>();
+        let (result_sender, result_receiver) = mpsc::channel::<LoadedAsset>();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+
+        let workers = (0..num_workers.max(1)).map(|i| {
+            let jobs = Arc::clone(&job_receiver);
+            let results = result_sender.clone();
+            thread::Builder::new()
+                .name(format!("asset-worker-{}", i))
+                .spawn(move || worker_loop(jobs, results))
+                .expect("Failed to spawn asset worker")
+        }).collect();
+
+        AssetLoader {
+            jobs: Some(job_sender),
+            finished: result_receiver,
+            workers,
+            next_id: 0,
+            pending: 0,
+        }
+    }
+
+    // Queue a model for loading. The returned id is echoed back in the matching `LoadedAsset`.
+    pub fn request(&mut self, request: AssetRequest) -> AssetId {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending += 1;
+        self.jobs.as_ref()
+            .expect("Asset loader has been shut down")
+            .send(Job { id, request })
+            .expect("All asset workers have exited");
+        id
+    }
+
+    // Number of requests that have not been handed back by `poll` yet
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    // Never blocks. Hands back at most `budget` finished assets, so that the GPU uploads they
+    // lead to can be spread out over several frames instead of all landing on the same one.
+    pub fn poll(&mut self, budget: usize) -> Vec<LoadedAsset> {
+        let finished: Vec<LoadedAsset> = self.finished.try_iter().take(budget).collect();
+        self.pending -= finished.len();
+        finished
+    }
+}
+
+impl Drop for AssetLoader {
+    fn drop(&mut self) {
+        // Closing the job queue makes every idle worker return from `recv`
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn worker_loop(jobs: Arc<Mutex<mpsc::Receiver<Job>>>, results: mpsc::Sender<LoadedAsset>) {
+    loop {
+        // Only hold the lock while waiting for a job, not while working on it
+        let job = match jobs.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        let Job { id, request } = match job {
+            Ok(job) => job,
+            Err(_) => return, // The loader was dropped
+        };
+
+        // The loaders panic on bad files. Catch that here, so one broken model doesn't take
+        // the worker down with it, and report it back as an error instead.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| match request {
+            AssetRequest::Terrain(path) => {
+                let mut terrain = Terrain::load(&path);
+                check_mesh(&mut terrain, &path);
+                let bvh = Bvh::build(&terrain);
+                Ok(Asset::Terrain(Box::new(LoadedTerrain { mesh: terrain, bvh })))
+            }
+            AssetRequest::TerrainLod { path, ratio } => {
+                let mut terrain = Terrain::load(&path);
+                check_mesh(&mut terrain, &path);
+                let lod = mesh::simplify(&terrain, ratio);
+                println!("{}: simplified from {} to {} triangles, with an error of {:.3}",
+                    path, lod.triangles_before, lod.triangles_after, lod.error);
+                let bvh = Bvh::build(&lod.mesh);
+                Ok(Asset::Terrain(Box::new(LoadedTerrain { mesh: lod.mesh, bvh })))
+            }
+            AssetRequest::Helicopter(path) => {
+                let mut helicopter = Helicopter::load(&path);
+                for part in [&mut helicopter.body, &mut helicopter.door, &mut helicopter.main_rotor, &mut helicopter.tail_rotor] {
+                    check_mesh(part, &path);
+                }
+                Ok(Asset::Helicopter(Box::new(helicopter)))
+            }
+            AssetRequest::Gltf(path) => {
+                let mut scene = gltf_import::load(&path)?;
+                for mesh in &mut scene.meshes {
+                    check_mesh(mesh, &path);
+                }
+                Ok(Asset::Gltf(Box::new(scene)))
+            }
+        })).map_err(|payload| {
+            payload.downcast_ref::<String>().cloned()
+                .or_else(|| payload.downcast_ref::<&str>().map(|s| s.to_string()))
+                .unwrap_or_else(|| "Unknown error while loading asset".to_string())
+        }).and_then(|result| result);
+
+        if results.send(LoadedAsset { id, result }).is_err() {
+            return;
+        }
+    }
+}
+
+// Broken exports would otherwise turn into GL errors or garbage on the render thread,
+// so fix up what we can while we're still on a worker
+fn check_mesh(mesh: &mut Mesh, path: &str) {
+    let report = mesh.validate();
+    if !report.is_ok() {
+        println!("{}: {}", path, report);
+        let summary = mesh.repair();
+        println!("{}: repaired, {:?}", path, summary);
+    }
+
+    // Textured models get tangents for normal mapping
+    if !mesh.uvs.is_empty() {
+        if let Err(message) = mesh.generate_tangents() {
+            println!("{}: no tangents, {}", path, message);
+        }
+    }
+}
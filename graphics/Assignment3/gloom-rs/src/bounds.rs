@@ -0,0 +1,262 @@
+// Bounding volumes, and the view frustum to test them against
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min : glm::Vec3,
+    pub max : glm::Vec3,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sphere {
+    pub center : glm::Vec3,
+    pub radius : f32,
+}
+
+impl Aabb {
+    // Contains nothing, not even the origin. Growing it by a point gives a box around just that point.
+    pub fn empty() -> Self {
+        Aabb {
+            min : glm::vec3(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max : glm::vec3(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    // The box around a flat list of XYZ coordinates, like `Mesh::vertices`
+    pub fn from_positions(positions: &[f32]) -> Self {
+        let mut aabb = Aabb::empty();
+        for p in positions.chunks_exact(3) {
+            aabb.include_point(&glm::vec3(p[0], p[1], p[2]));
+        }
+        aabb
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.min.x > self.max.x || self.min.y > self.max.y || self.min.z > self.max.z
+    }
+
+    pub fn include_point(&mut self, point: &glm::Vec3) {
+        self.min = glm::min2(&self.min, point);
+        self.max = glm::max2(&self.max, point);
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min : glm::min2(&self.min, &other.min),
+            max : glm::max2(&self.max, &other.max),
+        }
+    }
+
+    pub fn center(&self) -> glm::Vec3 {
+        (self.min + self.max) * 0.5
+    }
+
+    // Half the size along each axis
+    pub fn extents(&self) -> glm::Vec3 {
+        (self.max - self.min) * 0.5
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        if self.is_empty() {
+            return 0.0;
+        }
+        let size = self.max - self.min;
+        2.0 * (size.x * size.y + size.y * size.z + size.z * size.x)
+    }
+
+    pub fn contains(&self, point: &glm::Vec3) -> bool {
+        point.x >= self.min.x && point.y >= self.min.y && point.z >= self.min.z
+            && point.x <= self.max.x && point.y <= self.max.y && point.z <= self.max.z
+    }
+
+    pub fn intersects(&self, other: &Aabb) -> bool {
+        self.min.x <= other.max.x && self.min.y <= other.max.y && self.min.z <= other.max.z
+            && other.min.x <= self.max.x && other.min.y <= self.max.y && other.min.z <= self.max.z
+    }
+
+    // The axis aligned box around this box after moving it by `matrix`.
+    // It's larger than it has to be when rotated, but never smaller.
+    pub fn transformed(&self, matrix: &glm::Mat4) -> Aabb {
+        if self.is_empty() {
+            return *self;
+        }
+        let mut aabb = Aabb::empty();
+        for corner in 0..8 {
+            let x = if corner & 1 == 0 { self.min.x } else { self.max.x };
+            let y = if corner & 2 == 0 { self.min.y } else { self.max.y };
+            let z = if corner & 4 == 0 { self.min.z } else { self.max.z };
+            aabb.include_point(&(matrix * glm::vec4(x, y, z, 1.0)).xyz());
+        }
+        aabb
+    }
+}
+
+impl Sphere {
+    // Centered on the box, reaching all of its corners
+    pub fn around(aabb: &Aabb) -> Self {
+        Sphere { center: aabb.center(), radius: glm::length(&aabb.extents()) }
+    }
+
+    // Centered on the bounding box of the positions, but just large enough to reach the
+    // farthest of them, which is often a fair bit smaller than `around`
+    pub fn from_positions(positions: &[f32]) -> Self {
+        let center = Aabb::from_positions(positions).center();
+        let radius = positions.chunks_exact(3)
+            .map(|p| glm::distance(&center, &glm::vec3(p[0], p[1], p[2])))
+            .fold(0.0, f32::max);
+        Sphere { center, radius }
+    }
+}
+
+// The six planes around what the camera can see, each as (normal, distance) with the normal
+// pointing into the frustum, so that `dot(normal, point) + distance >= 0` for points inside
+pub struct Frustum {
+    pub planes : [glm::Vec4; 6],
+}
+
+impl Frustum {
+    // Pulled out of a view-projection matrix (Gribb & Hartmann). Planes from the projection alone
+    // are in view space, from view-projection in world space, and from a full MVP in model space.
+    pub fn from_matrix(matrix: &glm::Mat4) -> Self {
+        let row = |i: usize| glm::vec4(matrix[(i, 0)], matrix[(i, 1)], matrix[(i, 2)], matrix[(i, 3)]);
+        let (x, y, z, w) = (row(0), row(1), row(2), row(3));
+        let mut planes = [w + x, w - x, w + y, w - y, w + z, w - z];
+        for plane in planes.iter_mut() {
+            let length = glm::length(&plane.xyz());
+            if length > 0.0 {
+                *plane /= length;
+            }
+        }
+        Frustum { planes }
+    }
+
+    fn distance(plane: &glm::Vec4, point: &glm::Vec3) -> f32 {
+        glm::dot(&plane.xyz(), point) + plane.w
+    }
+
+    pub fn intersects_sphere(&self, sphere: &Sphere) -> bool {
+        self.planes.iter().all(|plane| Frustum::distance(plane, &sphere.center) >= -sphere.radius)
+    }
+
+    // Conservative: boxes near a corner of the frustum may pass without being visible
+    pub fn intersects_aabb(&self, aabb: &Aabb) -> bool {
+        if aabb.is_empty() {
+            return false;
+        }
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane's normal
+            let corner = glm::vec3(
+                if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+            Frustum::distance(plane, &corner) >= 0.0
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cube_at(center: glm::Vec3, half: f32) -> Aabb {
+        let h = glm::vec3(half, half, half);
+        Aabb { min: center - h, max: center + h }
+    }
+
+    // A camera at the origin looking down -Z, seeing from 1 to 100 units away, 90 degrees across
+    fn frustum() -> Frustum {
+        let projection = glm::perspective(1.0, std::f32::consts::FRAC_PI_2, 1.0, 100.0);
+        let view = glm::look_at(&glm::vec3(0.0, 0.0, 0.0), &glm::vec3(0.0, 0.0, -1.0), &glm::vec3(0.0, 1.0, 0.0));
+        Frustum::from_matrix(&(projection * view))
+    }
+
+    #[test]
+    fn frustum_keeps_what_is_in_front() {
+        let frustum = frustum();
+        assert!(frustum.intersects_aabb(&cube_at(glm::vec3(0.0, 0.0, -10.0), 1.0)));
+        assert!(frustum.intersects_aabb(&cube_at(glm::vec3(0.0, 0.0, -99.5), 1.0)));
+        // Straddling an edge still counts
+        assert!(frustum.intersects_aabb(&cube_at(glm::vec3(10.5, 0.0, -10.0), 1.0)));
+        assert!(frustum.intersects_sphere(&Sphere { center: glm::vec3(0.0, 5.0, -20.0), radius: 1.0 }));
+    }
+
+    #[test]
+    fn frustum_culls_what_is_outside() {
+        let frustum = frustum();
+        let outside = [
+            glm::vec3(0.0, 0.0, 10.0),     // Behind
+            glm::vec3(0.0, 0.0, -0.2),     // In front of the near plane
+            glm::vec3(0.0, 0.0, -110.0),   // Beyond the far plane
+            glm::vec3(-20.0, 0.0, -10.0),  // Left
+            glm::vec3(20.0, 0.0, -10.0),   // Right
+            glm::vec3(0.0, -20.0, -10.0),  // Below
+            glm::vec3(0.0, 20.0, -10.0),   // Above
+        ];
+        for center in outside {
+            assert!(!frustum.intersects_aabb(&cube_at(center, 0.5)), "{}", center);
+            assert!(!frustum.intersects_sphere(&Sphere { center, radius: 0.5 }), "{}", center);
+        }
+        assert!(!frustum.intersects_aabb(&Aabb::empty()));
+    }
+
+    #[test]
+    fn frustum_planes_follow_the_camera() {
+        // Looking along +X from 5 up, so what's at -Z is now off to the left
+        let projection = glm::perspective(1.0, std::f32::consts::FRAC_PI_2, 1.0, 100.0);
+        let view = glm::look_at(&glm::vec3(0.0, 5.0, 0.0), &glm::vec3(1.0, 5.0, 0.0), &glm::vec3(0.0, 1.0, 0.0));
+        let frustum = Frustum::from_matrix(&(projection * view));
+        assert!(frustum.intersects_aabb(&cube_at(glm::vec3(10.0, 5.0, 0.0), 1.0)));
+        assert!(!frustum.intersects_aabb(&cube_at(glm::vec3(0.0, 5.0, -10.0), 1.0)));
+    }
+
+    #[test]
+    fn empty_boxes_and_unions() {
+        let empty = Aabb::empty();
+        assert!(empty.is_empty());
+        assert_eq!(empty.surface_area(), 0.0);
+        assert!(!empty.contains(&glm::vec3(0.0, 0.0, 0.0)));
+
+        let mut point = Aabb::empty();
+        point.include_point(&glm::vec3(1.0, 2.0, 3.0));
+        assert!(!point.is_empty());
+        assert_eq!(point.min, point.max);
+
+        // An empty box adds nothing to a union
+        let a = cube_at(glm::vec3(0.0, 0.0, 0.0), 1.0);
+        let b = cube_at(glm::vec3(3.0, 0.0, 0.0), 1.0);
+        assert_eq!(a.union(&empty), a);
+        assert_eq!(empty.union(&a), a);
+        let both = a.union(&b);
+        assert_eq!(both.min, glm::vec3(-1.0, -1.0, -1.0));
+        assert_eq!(both.max, glm::vec3(4.0, 1.0, 1.0));
+        assert!(both.intersects(&a) && both.intersects(&b));
+        assert!(!a.intersects(&b));
+    }
+
+    #[test]
+    fn transformed_boxes_hold_every_corner() {
+        let a = Aabb { min: glm::vec3(0.0, 0.0, 0.0), max: glm::vec3(2.0, 1.0, 1.0) };
+
+        let moved = a.transformed(&glm::translation(&glm::vec3(1.0, 2.0, 3.0)));
+        assert_eq!(moved, Aabb { min: glm::vec3(1.0, 2.0, 3.0), max: glm::vec3(3.0, 3.0, 4.0) });
+
+        let scaled = a.transformed(&glm::scaling(&glm::vec3(-1.0, 2.0, 1.0)));
+        assert_eq!(scaled, Aabb { min: glm::vec3(-2.0, 0.0, 0.0), max: glm::vec3(0.0, 2.0, 1.0) });
+
+        // A quarter turn about Y swaps X and Z over, and X flips sign
+        let turned = a.transformed(&glm::rotation(std::f32::consts::FRAC_PI_2, &glm::vec3(0.0, 1.0, 0.0)));
+        assert!(glm::distance(&turned.min, &glm::vec3(0.0, 0.0, -2.0)) < 1e-5, "{:?}", turned);
+        assert!(glm::distance(&turned.max, &glm::vec3(1.0, 1.0, 0.0)) < 1e-5, "{:?}", turned);
+
+        // Turned by 45 degrees it grows, but still holds every corner
+        let matrix = glm::rotation(std::f32::consts::FRAC_PI_4, &glm::vec3(0.0, 0.0, 1.0));
+        let tilted = a.transformed(&matrix);
+        for corner in [glm::vec3(0.0, 0.0, 0.0), glm::vec3(2.0, 1.0, 1.0), glm::vec3(2.0, 0.0, 0.0), glm::vec3(0.0, 1.0, 1.0)] {
+            let p = (matrix * glm::vec4(corner.x, corner.y, corner.z, 1.0)).xyz();
+            let grown = Aabb { min: tilted.min - glm::vec3(1e-5, 1e-5, 1e-5), max: tilted.max + glm::vec3(1e-5, 1e-5, 1e-5) };
+            assert!(grown.contains(&p), "{}", p);
+        }
+
+        assert!(Aabb::empty().transformed(&matrix).is_empty());
+    }
+}
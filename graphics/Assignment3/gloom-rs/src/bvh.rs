@@ -0,0 +1,535 @@
+use crate::bounds::{Aabb, Sphere};
+use crate::mesh::Mesh;
+use crate::picking::Ray;
+
+// A bounding volume hierarchy over the triangles of a mesh, for asking where a ray hits it,
+// which of its points is closest to some point, and which triangles touch a sphere or a box,
+// without going through every triangle each time.
+//
+// It's built top down, splitting each node where the surface area heuristic says rays will
+// have the least work to do, with the candidate splits binned along the longest axes.
+// The triangles are copied in, so the mesh can be changed or dropped afterwards.
+
+const BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;    // Always split larger nodes when possible
+const LEAF_LIMIT: usize = 16;      // Never keep nodes larger than this as leaves
+
+pub struct Bvh {
+    nodes     : Vec<BvhNode>,
+    triangles : Vec<[glm::Vec3; 3]>,  // In the same order as in the mesh
+    order     : Vec<u32>,             // Triangle indices, grouped so each leaf's are next to each other
+}
+
+struct BvhNode {
+    bounds : Aabb,
+    first  : u32,   // Leaves: where in `order` the triangles start. Otherwise: the left child, with the right one after it.
+    count  : u32,   // Number of triangles, 0 if not a leaf
+}
+
+pub struct RayHit {
+    pub triangle    : usize,
+    pub distance    : f32,
+    pub barycentric : glm::Vec3,
+}
+
+pub struct ClosestPoint {
+    pub triangle : usize,
+    pub point    : glm::Vec3,
+    pub distance : f32,
+}
+
+impl Bvh {
+    pub fn build(mesh: &Mesh) -> Self {
+        let position = |v: u32| glm::make_vec3(&mesh.vertices[v as usize * 3..v as usize * 3 + 3]);
+        let triangles: Vec<[glm::Vec3; 3]> = mesh.indices.chunks_exact(3)
+            .map(|corners| [position(corners[0]), position(corners[1]), position(corners[2])])
+            .collect();
+        let bounds: Vec<Aabb> = triangles.iter().map(triangle_bounds).collect();
+        let centroids: Vec<glm::Vec3> = bounds.iter().map(|b| b.center()).collect();
+
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(triangles.len() * 2),
+            order: (0..triangles.len() as u32).collect(),
+            triangles,
+        };
+        if !bvh.triangles.is_empty() {
+            bvh.nodes.push(BvhNode { bounds: Aabb::empty(), first: 0, count: 0 });
+            bvh.build_node(0, 0, bvh.triangles.len(), &bounds, &centroids);
+        }
+        bvh
+    }
+
+    // Fill in node `index`, covering `order[start..end]`
+    fn build_node(&mut self, index: usize, start: usize, end: usize, bounds: &[Aabb], centroids: &[glm::Vec3]) {
+        let node_bounds = self.order[start..end].iter().fold(Aabb::empty(), |b, &t| b.union(&bounds[t as usize]));
+        let count = end - start;
+        self.nodes[index].bounds = node_bounds;
+
+        let split = if count > MAX_LEAF_SIZE { self.find_split(start, end, bounds, centroids) } else { None };
+        let middle = match split {
+            Some((axis, position, cost)) => {
+                let leaf_cost = count as f32 * node_bounds.surface_area();
+                if cost >= leaf_cost && count <= LEAF_LIMIT {
+                    None
+                } else {
+                    Some(self.partition(start, end, |t| centroids[t as usize][axis] < position))
+                }
+            }
+            // Every centroid is in the same spot, so split down the middle if it's too large
+            None if count > LEAF_LIMIT => Some(start + count / 2),
+            None => None,
+        };
+        // A split that leaves one side empty is no split at all
+        let middle = middle.map(|m| if m == start || m == end { start + count / 2 } else { m });
+
+        match middle {
+            None => {
+                self.nodes[index].first = start as u32;
+                self.nodes[index].count = count as u32;
+            }
+            Some(middle) => {
+                let left = self.nodes.len();
+                self.nodes.push(BvhNode { bounds: Aabb::empty(), first: 0, count: 0 });
+                self.nodes.push(BvhNode { bounds: Aabb::empty(), first: 0, count: 0 });
+                self.nodes[index].first = left as u32;
+                self.nodes[index].count = 0;
+                self.build_node(left, start, middle, bounds, centroids);
+                self.build_node(left + 1, middle, end, bounds, centroids);
+            }
+        }
+    }
+
+    // The best axis and position to split at, and the surface area cost of doing so
+    #[allow(clippy::needless_range_loop)]
+    fn find_split(&self, start: usize, end: usize, bounds: &[Aabb], centroids: &[glm::Vec3]) -> Option<(usize, f32, f32)> {
+        let mut centroid_bounds = Aabb::empty();
+        for &t in &self.order[start..end] {
+            centroid_bounds.include_point(&centroids[t as usize]);
+        }
+
+        let mut best: Option<(usize, f32, f32)> = None;
+        for axis in 0..3 {
+            let low = centroid_bounds.min[axis];
+            let extent = centroid_bounds.max[axis] - low;
+            if extent <= f32::EPSILON {
+                continue;
+            }
+            let bin_of = |t: u32| (((centroids[t as usize][axis] - low) / extent * BINS as f32) as usize).min(BINS - 1);
+
+            let mut bin_bounds = [Aabb::empty(); BINS];
+            let mut bin_counts = [0usize; BINS];
+            for &t in &self.order[start..end] {
+                let bin = bin_of(t);
+                bin_bounds[bin] = bin_bounds[bin].union(&bounds[t as usize]);
+                bin_counts[bin] += 1;
+            }
+
+            // Sweep from the right first, then from the left, to cost every split between bins
+            let mut right_costs = [0.0f32; BINS];
+            let (mut right_bounds, mut right_count) = (Aabb::empty(), 0);
+            for split in (1..BINS).rev() {
+                right_bounds = right_bounds.union(&bin_bounds[split]);
+                right_count += bin_counts[split];
+                right_costs[split] = if right_count > 0 { right_count as f32 * right_bounds.surface_area() } else { 0.0 };
+            }
+            let (mut left_bounds, mut left_count) = (Aabb::empty(), 0);
+            for split in 1..BINS {
+                left_bounds = left_bounds.union(&bin_bounds[split - 1]);
+                left_count += bin_counts[split - 1];
+                if left_count == 0 || left_count == end - start {
+                    continue;
+                }
+                let cost = left_count as f32 * left_bounds.surface_area() + right_costs[split];
+                if best.is_none_or(|(_, _, best_cost)| cost < best_cost) {
+                    best = Some((axis, low + extent * split as f32 / BINS as f32, cost));
+                }
+            }
+        }
+        best
+    }
+
+    // Move the triangles for which `left` holds to the front, and return where the rest begin
+    fn partition(&mut self, start: usize, end: usize, left: impl Fn(u32) -> bool) -> usize {
+        let mut middle = start;
+        for i in start..end {
+            if left(self.order[i]) {
+                self.order.swap(i, middle);
+                middle += 1;
+            }
+        }
+        middle
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        self.nodes.first().map_or(Aabb::empty(), |root| root.bounds)
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.triangles.len()
+    }
+
+    pub fn triangle(&self, index: usize) -> &[glm::Vec3; 3] {
+        &self.triangles[index]
+    }
+
+    fn leaf_triangles(&self, node: &BvhNode) -> impl Iterator<Item = usize> + '_ {
+        self.order[node.first as usize..(node.first + node.count) as usize].iter().map(|&t| t as usize)
+    }
+
+    // The nearest triangle the ray hits no further than `max_distance` along it
+    pub fn ray_cast(&self, ray: &Ray, max_distance: f32) -> Option<RayHit> {
+        let mut best: Option<RayHit> = None;
+        let mut limit = max_distance;
+        let mut stack = vec![0usize];
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            match ray.intersect_aabb(&node.bounds) {
+                Some(distance) if distance <= limit => {}
+                _ => continue,
+            }
+            if node.count > 0 {
+                for triangle in self.leaf_triangles(node) {
+                    let [a, b, c] = &self.triangles[triangle];
+                    if let Some((distance, barycentric)) = ray.intersect_triangle(a, b, c) {
+                        if distance <= limit {
+                            limit = distance;
+                            best = Some(RayHit { triangle, distance, barycentric });
+                        }
+                    }
+                }
+            } else {
+                // Visit the nearer child first, so that its hits can rule out the other one
+                let (left, right) = (node.first as usize, node.first as usize + 1);
+                let left_distance = ray.intersect_aabb(&self.nodes[left].bounds).unwrap_or(f32::INFINITY);
+                let right_distance = ray.intersect_aabb(&self.nodes[right].bounds).unwrap_or(f32::INFINITY);
+                if left_distance <= right_distance {
+                    stack.push(right);
+                    stack.push(left);
+                } else {
+                    stack.push(left);
+                    stack.push(right);
+                }
+            }
+        }
+        best
+    }
+
+    // The height of the surface under (x, z), looking down from above. Handy for terrain.
+    pub fn height_at(&self, x: f32, z: f32) -> Option<f32> {
+        let bounds = self.bounds();
+        let top = bounds.max.y + 1.0;
+        let ray = Ray::new(glm::vec3(x, top, z), glm::vec3(0.0, -1.0, 0.0));
+        self.ray_cast(&ray, f32::INFINITY).map(|hit| top - hit.distance)
+    }
+
+    // The point on the mesh nearest to `point`
+    pub fn closest_point(&self, point: &glm::Vec3) -> Option<ClosestPoint> {
+        let mut best: Option<ClosestPoint> = None;
+        let mut best_squared = f32::INFINITY;
+        let mut stack = vec![0usize];
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if squared_distance_to_aabb(point, &node.bounds) > best_squared {
+                continue;
+            }
+            if node.count > 0 {
+                for triangle in self.leaf_triangles(node) {
+                    let candidate = closest_point_on_triangle(point, &self.triangles[triangle]);
+                    let squared = glm::distance2(point, &candidate);
+                    if squared < best_squared {
+                        best_squared = squared;
+                        best = Some(ClosestPoint { triangle, point: candidate, distance: squared.sqrt() });
+                    }
+                }
+            } else {
+                let (left, right) = (node.first as usize, node.first as usize + 1);
+                if squared_distance_to_aabb(point, &self.nodes[left].bounds) <= squared_distance_to_aabb(point, &self.nodes[right].bounds) {
+                    stack.push(right);
+                    stack.push(left);
+                } else {
+                    stack.push(left);
+                    stack.push(right);
+                }
+            }
+        }
+        best
+    }
+
+    // Every triangle touching the sphere
+    pub fn triangles_in_sphere(&self, sphere: &Sphere) -> Vec<usize> {
+        let squared_radius = sphere.radius * sphere.radius;
+        self.collect(
+            |bounds| squared_distance_to_aabb(&sphere.center, bounds) <= squared_radius,
+            |triangle| glm::distance2(&sphere.center, &closest_point_on_triangle(&sphere.center, triangle)) <= squared_radius,
+        )
+    }
+
+    // Every triangle touching the box
+    pub fn triangles_in_aabb(&self, aabb: &Aabb) -> Vec<usize> {
+        self.collect(|bounds| bounds.intersects(aabb), |triangle| triangle_overlaps_aabb(triangle, aabb))
+    }
+
+    fn collect(&self, node_test: impl Fn(&Aabb) -> bool, triangle_test: impl Fn(&[glm::Vec3; 3]) -> bool) -> Vec<usize> {
+        let mut found = vec![];
+        let mut stack = if self.nodes.is_empty() { vec![] } else { vec![0usize] };
+        while let Some(index) = stack.pop() {
+            let node = &self.nodes[index];
+            if !node_test(&node.bounds) {
+                continue;
+            }
+            if node.count > 0 {
+                found.extend(self.leaf_triangles(node).filter(|&t| triangle_test(&self.triangles[t])));
+            } else {
+                stack.push(node.first as usize);
+                stack.push(node.first as usize + 1);
+            }
+        }
+        found
+    }
+}
+
+fn triangle_bounds(triangle: &[glm::Vec3; 3]) -> Aabb {
+    let mut bounds = Aabb::empty();
+    for corner in triangle {
+        bounds.include_point(corner);
+    }
+    bounds
+}
+
+fn squared_distance_to_aabb(point: &glm::Vec3, aabb: &Aabb) -> f32 {
+    let clamped = glm::clamp_vec(point, &aabb.min, &aabb.max);
+    glm::distance2(point, &clamped)
+}
+
+// From Ericson's Real-Time Collision Detection, going through which of the triangle's
+// corners, edges or face the point is closest to
+pub fn closest_point_on_triangle(p: &glm::Vec3, [a, b, c]: &[glm::Vec3; 3]) -> glm::Vec3 {
+    let ab = b - a;
+    let ac = c - a;
+    let ap = p - a;
+    let d1 = glm::dot(&ab, &ap);
+    let d2 = glm::dot(&ac, &ap);
+    if d1 <= 0.0 && d2 <= 0.0 {
+        return *a;
+    }
+
+    let bp = p - b;
+    let d3 = glm::dot(&ab, &bp);
+    let d4 = glm::dot(&ac, &bp);
+    if d3 >= 0.0 && d4 <= d3 {
+        return *b;
+    }
+
+    let vc = d1 * d4 - d3 * d2;
+    if vc <= 0.0 && d1 >= 0.0 && d3 <= 0.0 {
+        return a + ab * (d1 / (d1 - d3));
+    }
+
+    let cp = p - c;
+    let d5 = glm::dot(&ab, &cp);
+    let d6 = glm::dot(&ac, &cp);
+    if d6 >= 0.0 && d5 <= d6 {
+        return *c;
+    }
+
+    let vb = d5 * d2 - d1 * d6;
+    if vb <= 0.0 && d2 >= 0.0 && d6 <= 0.0 {
+        return a + ac * (d2 / (d2 - d6));
+    }
+
+    let va = d3 * d6 - d5 * d4;
+    if va <= 0.0 && (d4 - d3) >= 0.0 && (d5 - d6) >= 0.0 {
+        return b + (c - b) * ((d4 - d3) / ((d4 - d3) + (d5 - d6)));
+    }
+
+    let denominator = va + vb + vc;
+    if denominator.abs() <= f32::EPSILON {
+        return *a; // Degenerate triangle
+    }
+    a + ab * (vb / denominator) + ac * (vc / denominator)
+}
+
+// Separating axis test between a triangle and a box (Akenine-Möller): the box's three axes,
+// the triangle's normal, and the nine cross products of their edges
+fn triangle_overlaps_aabb(triangle: &[glm::Vec3; 3], aabb: &Aabb) -> bool {
+    let center = aabb.center();
+    let half = aabb.extents();
+    let v = [triangle[0] - center, triangle[1] - center, triangle[2] - center];
+    let edges = [v[1] - v[0], v[2] - v[1], v[0] - v[2]];
+
+    let separated_along = |axis: &glm::Vec3| {
+        let projections = [glm::dot(&v[0], axis), glm::dot(&v[1], axis), glm::dot(&v[2], axis)];
+        let min = projections[0].min(projections[1]).min(projections[2]);
+        let max = projections[0].max(projections[1]).max(projections[2]);
+        let radius = half.x * axis.x.abs() + half.y * axis.y.abs() + half.z * axis.z.abs();
+        min > radius || max < -radius
+    };
+
+    let box_axes = [glm::vec3(1.0, 0.0, 0.0), glm::vec3(0.0, 1.0, 0.0), glm::vec3(0.0, 0.0, 1.0)];
+    for box_axis in &box_axes {
+        for edge in &edges {
+            let axis = glm::cross(box_axis, edge);
+            if glm::length2(&axis) > f32::EPSILON && separated_along(&axis) {
+                return false;
+            }
+        }
+    }
+    if box_axes.iter().any(&separated_along) {
+        return false;
+    }
+    !separated_along(&glm::cross(&edges[0], &edges[1]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn random_point(rng: &mut StdRng, size: f32) -> glm::Vec3 {
+        glm::vec3(rng.gen_range(-size..size), rng.gen_range(-size..size), rng.gen_range(-size..size))
+    }
+
+    // Small triangles scattered through a box, as a soup with nothing shared
+    fn random_mesh(rng: &mut StdRng, triangles: usize) -> Mesh {
+        let mut vertices = vec![];
+        for _ in 0..triangles {
+            let center = random_point(rng, 10.0);
+            for _ in 0..3 {
+                vertices.extend((center + random_point(rng, 1.5)).iter());
+            }
+        }
+        let indices: Vec<u32> = (0..triangles as u32 * 3).collect();
+        Mesh {
+            vertices,
+            normals: vec![],
+            colors: vec![],
+            uvs: vec![],
+            tangents: vec![],
+            index_count: indices.len() as i32,
+            indices,
+        }
+    }
+
+    fn setup() -> (StdRng, Bvh) {
+        let mut rng = StdRng::seed_from_u64(43);
+        let mesh = random_mesh(&mut rng, 400);
+        let bvh = Bvh::build(&mesh);
+        assert_eq!(bvh.triangle_count(), 400);
+        (rng, bvh)
+    }
+
+    #[test]
+    fn ray_cast_finds_the_nearest_hit() {
+        let (mut rng, bvh) = setup();
+        let mut hits = 0;
+        for _ in 0..500 {
+            let ray = Ray::new(random_point(&mut rng, 15.0), glm::normalize(&random_point(&mut rng, 1.0)));
+            let expected = (0..bvh.triangle_count())
+                .filter_map(|t| {
+                    let [a, b, c] = bvh.triangle(t);
+                    ray.intersect_triangle(a, b, c).map(|(distance, _)| (t, distance))
+                })
+                .min_by(|x, y| x.1.total_cmp(&y.1));
+            let found = bvh.ray_cast(&ray, f32::INFINITY).map(|hit| (hit.triangle, hit.distance));
+            assert_eq!(found, expected);
+            hits += found.is_some() as usize;
+        }
+        assert!(hits > 50, "{}", hits);
+    }
+
+    #[test]
+    fn ray_cast_stops_at_the_max_distance() {
+        let (_, bvh) = setup();
+        // Straight down onto the middle of the first triangle, so something is bound to be hit
+        let [a, b, c] = bvh.triangle(0);
+        let middle = (a + b + c) / 3.0;
+        let ray = Ray::new(glm::vec3(middle.x, 50.0, middle.z), glm::vec3(0.0, -1.0, 0.0));
+        let hit = bvh.ray_cast(&ray, f32::INFINITY).unwrap();
+        assert!(bvh.ray_cast(&ray, hit.distance * 0.99).is_none());
+        let height = bvh.height_at(middle.x, middle.z).unwrap();
+        assert!((height - ray.at(hit.distance).y).abs() < 1e-4, "{} {}", height, ray.at(hit.distance).y);
+    }
+
+    #[test]
+    fn closest_point_matches_brute_force() {
+        let (mut rng, bvh) = setup();
+        for _ in 0..500 {
+            let point = random_point(&mut rng, 15.0);
+            let expected = (0..bvh.triangle_count())
+                .map(|t| glm::distance(&point, &closest_point_on_triangle(&point, bvh.triangle(t))))
+                .fold(f32::INFINITY, f32::min);
+            let found = bvh.closest_point(&point).unwrap();
+            assert!((found.distance - expected).abs() < 1e-5, "{} {}", found.distance, expected);
+            assert!((glm::distance(&point, &found.point) - found.distance).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn closest_point_on_triangle_beats_every_sample_of_it() {
+        let mut rng = StdRng::seed_from_u64(7);
+        for _ in 0..200 {
+            let triangle = [random_point(&mut rng, 2.0), random_point(&mut rng, 2.0), random_point(&mut rng, 2.0)];
+            let point = random_point(&mut rng, 4.0);
+            let closest = glm::distance(&point, &closest_point_on_triangle(&point, &triangle));
+            for i in 0..=20 {
+                for j in 0..=20 - i {
+                    let (u, v) = (i as f32 / 20.0, j as f32 / 20.0);
+                    let sample = triangle[0] + (triangle[1] - triangle[0]) * u + (triangle[2] - triangle[0]) * v;
+                    assert!(closest <= glm::distance(&point, &sample) + 1e-5);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn overlap_queries_match_brute_force() {
+        let (mut rng, bvh) = setup();
+        for _ in 0..200 {
+            let corner = random_point(&mut rng, 12.0);
+            let aabb = Aabb { min: corner, max: corner + glm::abs(&random_point(&mut rng, 4.0)) };
+            let mut found = bvh.triangles_in_aabb(&aabb);
+            found.sort_unstable();
+            let expected: Vec<usize> = (0..bvh.triangle_count()).filter(|&t| triangle_overlaps_aabb(bvh.triangle(t), &aabb)).collect();
+            assert_eq!(found, expected);
+
+            let sphere = Sphere { center: random_point(&mut rng, 12.0), radius: rng.gen_range(0.5..4.0) };
+            let mut found = bvh.triangles_in_sphere(&sphere);
+            found.sort_unstable();
+            let expected: Vec<usize> = (0..bvh.triangle_count())
+                .filter(|&t| glm::distance(&sphere.center, &closest_point_on_triangle(&sphere.center, bvh.triangle(t))) <= sphere.radius)
+                .collect();
+            assert_eq!(found, expected);
+        }
+    }
+
+    #[test]
+    fn triangle_box_overlap_agrees_with_samples() {
+        let mut rng = StdRng::seed_from_u64(11);
+        for _ in 0..500 {
+            let triangle = [random_point(&mut rng, 2.0), random_point(&mut rng, 2.0), random_point(&mut rng, 2.0)];
+            let corner = random_point(&mut rng, 2.0);
+            let aabb = Aabb { min: corner, max: corner + glm::abs(&random_point(&mut rng, 1.0)) };
+            let overlaps = triangle_overlaps_aabb(&triangle, &aabb);
+            // Any sample of the triangle inside the box means they overlap
+            let sampled_inside = (0..=20).any(|i| (0..=20 - i).any(|j| {
+                let (u, v) = (i as f32 / 20.0, j as f32 / 20.0);
+                aabb.contains(&(triangle[0] + (triangle[1] - triangle[0]) * u + (triangle[2] - triangle[0]) * v))
+            }));
+            if sampled_inside {
+                assert!(overlaps);
+            }
+            if !triangle_bounds(&triangle).intersects(&aabb) {
+                assert!(!overlaps);
+            }
+        }
+    }
+}
@@ -0,0 +1,474 @@
+use crate::bounds::{Aabb, Sphere};
+use crate::bvh::{closest_point_on_triangle, Bvh};
+use crate::picking::Ray;
+
+// Keeping things like the helicopter out of the terrain. A `Collider` is a handful of spheres and
+// capsules in a node's own space. Each step they're tested against the terrain's BVH, the node is
+// pushed back out of anything it has sunk into, and what kind of contact it was is reported:
+// setting down gently on the skids, sliding along on them, or hitting the ground hard.
+//
+// The terrain's transform may move and rotate it, but not scale it, as the shapes are taken into
+// the terrain's space and their radii are left as they are.
+
+const MAX_ITERATIONS: usize = 4; // Pushes per step, for when getting out of one triangle puts us into another
+
+#[derive(Clone, Copy, Debug)]
+pub enum Shape {
+    Sphere  { center: glm::Vec3, radius: f32 },
+    Capsule { a: glm::Vec3, b: glm::Vec3, radius: f32 }, // Everything within `radius` of the segment from `a` to `b`
+}
+
+// Which part of the vehicle a shape belongs to. Only the skids are meant to touch the ground.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Part {
+    Body,
+    Skid,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct CollisionShape {
+    pub shape : Shape,
+    pub part  : Part,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContactKind {
+    Landed,  // Resting on the skids
+    Skid,    // On the skids, but sliding along the ground
+    Crashed, // Hit the ground too fast, too steep, or with something other than the skids
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ContactEvent {
+    pub kind   : ContactKind,
+    pub point  : glm::Vec3, // On the terrain, in world space
+    pub normal : glm::Vec3, // Out of the terrain
+    pub speed  : f32,       // Into the ground, when it happened
+}
+
+// The outcome of one `Collider::resolve`
+pub struct Resolution {
+    pub correction    : glm::Vec3,          // Add this to the node's world position to get it out of the ground
+    pub ground_normal : Option<glm::Vec3>,  // Of the deepest contact, None when in the air
+    pub events        : Vec<ContactEvent>,  // Only when the kind of contact changes, not every step
+}
+
+pub struct Collider {
+    pub shapes            : Vec<CollisionShape>, // In the node's own space
+    pub max_landing_speed : f32,   // Coming down faster than this is a crash
+    pub max_slope         : f32,   // In radians. Setting down on anything steeper, or tilted further, is a crash.
+    pub max_resting_speed : f32,   // Moving along the ground faster than this is skidding
+    pub contact           : Option<ContactKind>, // What we're currently doing on the ground, None if in the air
+}
+
+// Where a shape has sunk into the terrain, in the terrain's space
+struct Penetration {
+    part   : Part,
+    point  : glm::Vec3,
+    normal : glm::Vec3,
+    depth  : f32,
+}
+
+impl Collider {
+    pub fn new(shapes: Vec<CollisionShape>) -> Self {
+        Collider {
+            shapes,
+            max_landing_speed : 4.0,
+            max_slope         : 0.5,
+            max_resting_speed : 1.0,
+            contact           : None,
+        }
+    }
+
+    // A body with two skids underneath it, fitted to the bounds of the helicopter's body mesh,
+    // with the skids along its length at the very bottom
+    pub fn helicopter(body_bounds: &Aabb) -> Self {
+        let size = body_bounds.max - body_bounds.min;
+        let center = body_bounds.center();
+        let skid_radius = 0.05 * size.y;
+        let skid_y = body_bounds.min.y + skid_radius;
+        let skid = |x: f32| CollisionShape {
+            shape: Shape::Capsule {
+                a      : glm::vec3(x, skid_y, center.z - 0.25 * size.z),
+                b      : glm::vec3(x, skid_y, center.z + 0.1 * size.z),
+                radius : skid_radius,
+            },
+            part: Part::Skid,
+        };
+
+        // The cabin is a sphere above the skids, and the tail boom a thin capsule behind it
+        let cabin_center = glm::vec3(center.x, body_bounds.min.y + 0.6 * size.y, center.z - 0.1 * size.z);
+        let cabin = CollisionShape {
+            shape: Shape::Sphere { center: cabin_center, radius: 0.4 * size.y },
+            part: Part::Body,
+        };
+        let tail = CollisionShape {
+            shape: Shape::Capsule {
+                a      : cabin_center,
+                b      : glm::vec3(center.x, cabin_center.y, body_bounds.max.z),
+                radius : 0.1 * size.y,
+            },
+            part: Part::Body,
+        };
+
+        Collider::new(vec![skid(center.x - 0.35 * size.x), skid(center.x + 0.35 * size.x), cabin, tail])
+    }
+
+    // Push a node with world matrix `world`, moving at `velocity` in world units per second,
+    // out of the terrain. `terrain_world` is the world matrix of the node `terrain` belongs to.
+    pub fn resolve(&mut self, world: &glm::Mat4, velocity: &glm::Vec3, terrain: &Bvh, terrain_world: &glm::Mat4) -> Resolution {
+        let to_terrain = glm::inverse(terrain_world) * world;
+        let shapes: Vec<(Shape, Part)> = self.shapes.iter()
+            .map(|shape| (shape.shape.transformed(&to_terrain), shape.part))
+            .collect();
+        let local_velocity = (glm::inverse(terrain_world) * glm::vec4(velocity.x, velocity.y, velocity.z, 0.0)).xyz();
+
+        let mut correction = glm::vec3(0.0, 0.0, 0.0);
+        let mut deepest: Option<Penetration> = None;
+        let mut touching = vec![];
+        let mut push = |step: Penetration, correction: &mut glm::Vec3| {
+            *correction += step.normal * step.depth;
+            if !touching.contains(&step.part) {
+                touching.push(step.part);
+            }
+            if deepest.as_ref().is_none_or(|deepest| step.depth > deepest.depth) {
+                deepest = Some(step);
+            }
+        };
+
+        // Anything that ended up completely under the surface, e.g. after a long frame, is lifted
+        // straight up first, as there'd be no way to tell which side of the triangles it belongs on
+        for (shape, part) in &shapes {
+            let (lowest, highest) = shape.span_y();
+            let probe = shape.center();
+            if let Some(height) = terrain.height_at(probe.x, probe.z) {
+                if highest + correction.y < height {
+                    let depth = height - lowest - correction.y;
+                    let point = glm::vec3(probe.x, height, probe.z);
+                    push(Penetration { part: *part, point, normal: glm::vec3(0.0, 1.0, 0.0), depth }, &mut correction);
+                }
+            }
+        }
+
+        for _ in 0..MAX_ITERATIONS {
+            let step = shapes.iter()
+                .filter_map(|(shape, part)| shape.translated(&correction).penetration(terrain, *part))
+                .max_by(|a, b| a.depth.total_cmp(&b.depth));
+            match step {
+                Some(step) => push(step, &mut correction),
+                None => break,
+            }
+        }
+
+        let to_world = |v: glm::Vec3, w: f32| (terrain_world * glm::vec4(v.x, v.y, v.z, w)).xyz();
+        let mut events = vec![];
+        let contact = deepest.as_ref().map(|deepest| {
+            let speed = -glm::dot(&local_velocity, &deepest.normal);
+            let along_ground = glm::length(&(local_velocity + deepest.normal * speed));
+            // Both the ground and the vehicle itself have to be close enough to level
+            let up = glm::normalize(&(to_terrain * glm::vec4(0.0, 1.0, 0.0, 0.0)).xyz());
+            let slope = deepest.normal.y.clamp(-1.0, 1.0).acos();
+            let tilt = glm::dot(&up, &deepest.normal).clamp(-1.0, 1.0).acos();
+            let too_steep = slope > self.max_slope || tilt > self.max_slope;
+            let kind = if touching.contains(&Part::Body) || speed > self.max_landing_speed || too_steep {
+                ContactKind::Crashed
+            } else if along_ground > self.max_resting_speed {
+                ContactKind::Skid
+            } else {
+                ContactKind::Landed
+            };
+            (kind, speed)
+        });
+
+        match (self.contact, contact) {
+            // A crash lasts until we're back in the air
+            (Some(ContactKind::Crashed), Some(_)) => {}
+            (previous, Some((kind, speed))) if previous != Some(kind) => {
+                let deepest = deepest.as_ref().unwrap();
+                events.push(ContactEvent {
+                    kind,
+                    point  : to_world(deepest.point, 1.0),
+                    normal : glm::normalize(&to_world(deepest.normal, 0.0)),
+                    speed,
+                });
+                self.contact = Some(kind);
+            }
+            (_, Some(_)) => {}
+            (_, None) => self.contact = None,
+        }
+
+        Resolution {
+            correction    : to_world(correction, 0.0),
+            ground_normal : deepest.map(|deepest| glm::normalize(&to_world(deepest.normal, 0.0))),
+            events,
+        }
+    }
+}
+
+impl Shape {
+    fn transformed(&self, matrix: &glm::Mat4) -> Shape {
+        let point = |p: &glm::Vec3| (matrix * glm::vec4(p.x, p.y, p.z, 1.0)).xyz();
+        match self {
+            Shape::Sphere { center, radius } => Shape::Sphere { center: point(center), radius: *radius },
+            Shape::Capsule { a, b, radius } => Shape::Capsule { a: point(a), b: point(b), radius: *radius },
+        }
+    }
+
+    fn translated(&self, offset: &glm::Vec3) -> Shape {
+        match self {
+            Shape::Sphere { center, radius } => Shape::Sphere { center: center + offset, radius: *radius },
+            Shape::Capsule { a, b, radius } => Shape::Capsule { a: a + offset, b: b + offset, radius: *radius },
+        }
+    }
+
+    fn center(&self) -> glm::Vec3 {
+        match self {
+            Shape::Sphere { center, .. } => *center,
+            Shape::Capsule { a, b, .. } => (a + b) * 0.5,
+        }
+    }
+
+    // The lowest and highest point of the shape
+    fn span_y(&self) -> (f32, f32) {
+        match self {
+            Shape::Sphere { center, radius } => (center.y - radius, center.y + radius),
+            Shape::Capsule { a, b, radius } => (a.y.min(b.y) - radius, a.y.max(b.y) + radius),
+        }
+    }
+
+    // How far the shape has sunk into the terrain, at the triangle where it's the deepest
+    fn penetration(&self, terrain: &Bvh, part: Part) -> Option<Penetration> {
+        let (candidates, radius) = match self {
+            Shape::Sphere { center, radius } => {
+                (terrain.triangles_in_sphere(&Sphere { center: *center, radius: *radius }), *radius)
+            }
+            Shape::Capsule { a, b, radius } => {
+                let mut bounds = Aabb::empty();
+                bounds.include_point(a);
+                bounds.include_point(b);
+                let padding = glm::vec3(*radius, *radius, *radius);
+                (terrain.triangles_in_aabb(&Aabb { min: bounds.min - padding, max: bounds.max + padding }), *radius)
+            }
+        };
+
+        candidates.into_iter().filter_map(|triangle| {
+            let corners = terrain.triangle(triangle);
+            let (on_shape, on_terrain) = match self {
+                Shape::Sphere { center, .. } => (*center, closest_point_on_triangle(center, corners)),
+                Shape::Capsule { a, b, .. } => closest_points_segment_triangle(a, b, corners),
+            };
+            let offset = on_shape - on_terrain;
+            let distance = glm::length(&offset);
+
+            // Terrain faces up, whichever way its triangles are wound
+            let mut face_normal = glm::normalize(&glm::cross(&(corners[1] - corners[0]), &(corners[2] - corners[0])));
+            if face_normal.y < 0.0 {
+                face_normal = -face_normal;
+            }
+            let above = glm::dot(&offset, &face_normal) >= 0.0;
+            if above && distance >= radius {
+                return None;
+            }
+            if above && distance > 1e-5 {
+                return Some(Penetration { part, point: on_terrain, normal: offset / distance, depth: radius - distance });
+            }
+
+            // On the surface, under it, or a capsule through it. Either way it has to go up past its lowest end.
+            let depth = |p: &glm::Vec3| -glm::dot(&face_normal, &(p - corners[0]));
+            let below = match self {
+                Shape::Sphere { center, .. } => depth(center).max(0.0),
+                Shape::Capsule { a, b, .. } => depth(a).max(depth(b)).max(0.0),
+            };
+            Some(Penetration { part, point: on_terrain, normal: face_normal, depth: radius + below })
+        }).max_by(|a, b| a.depth.total_cmp(&b.depth))
+    }
+}
+
+// The closest pair of points on the segment from `a` to `b` and on the triangle. If the segment
+// goes through the triangle, that's where, twice.
+fn closest_points_segment_triangle(a: &glm::Vec3, b: &glm::Vec3, corners: &[glm::Vec3; 3]) -> (glm::Vec3, glm::Vec3) {
+    let ray = Ray::new(*a, b - a);
+    if let Some((t, _)) = ray.intersect_triangle(&corners[0], &corners[1], &corners[2]) {
+        if t <= 1.0 {
+            let p = ray.at(t);
+            return (p, p);
+        }
+    }
+
+    // Otherwise it's between one of the segment's ends and the face, or the segment and an edge
+    let mut candidates = vec![
+        (*a, closest_point_on_triangle(a, corners)),
+        (*b, closest_point_on_triangle(b, corners)),
+    ];
+    for i in 0..3 {
+        candidates.push(closest_points_segments(a, b, &corners[i], &corners[(i + 1) % 3]));
+    }
+    candidates.into_iter()
+        .min_by(|x, y| glm::distance2(&x.0, &x.1).total_cmp(&glm::distance2(&y.0, &y.1)))
+        .unwrap()
+}
+
+// The closest pair of points on the segments p1-q1 and p2-q2, from Ericson's Real-Time Collision Detection
+fn closest_points_segments(p1: &glm::Vec3, q1: &glm::Vec3, p2: &glm::Vec3, q2: &glm::Vec3) -> (glm::Vec3, glm::Vec3) {
+    let d1 = q1 - p1;
+    let d2 = q2 - p2;
+    let r = p1 - p2;
+    let a = glm::dot(&d1, &d1);
+    let e = glm::dot(&d2, &d2);
+    let f = glm::dot(&d2, &r);
+
+    let (s, t) = if a <= f32::EPSILON && e <= f32::EPSILON {
+        (0.0, 0.0)
+    } else if a <= f32::EPSILON {
+        (0.0, (f / e).clamp(0.0, 1.0))
+    } else {
+        let c = glm::dot(&d1, &r);
+        if e <= f32::EPSILON {
+            ((-c / a).clamp(0.0, 1.0), 0.0)
+        } else {
+            let b = glm::dot(&d1, &d2);
+            let denominator = a * e - b * b;
+            let mut s = if denominator > f32::EPSILON { ((b * f - c * e) / denominator).clamp(0.0, 1.0) } else { 0.0 };
+            let mut t = (b * s + f) / e;
+            if t < 0.0 {
+                t = 0.0;
+                s = (-c / a).clamp(0.0, 1.0);
+            } else if t > 1.0 {
+                t = 1.0;
+                s = ((b - c) / a).clamp(0.0, 1.0);
+            }
+            (s, t)
+        }
+    };
+    (p1 + d1 * s, p2 + d2 * t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::fixtures;
+
+    // A 20 by 20 grid around the origin, tilted up towards +X by `slope` units per unit
+    fn terrain(slope: f32) -> Bvh {
+        let mut mesh = fixtures::grid(20);
+        for vertex in mesh.vertices.chunks_mut(3) {
+            vertex[0] -= 10.0;
+            vertex[2] -= 10.0;
+            vertex[1] = slope * vertex[0];
+        }
+        Bvh::build(&mesh)
+    }
+
+    // A helicopter 2 wide, 1.5 tall and 4 long, with the bottom of its skids `height` above the origin
+    fn helicopter_at(height: f32) -> (Collider, glm::Mat4) {
+        let bounds = Aabb { min: glm::vec3(-1.0, 0.0, -2.0), max: glm::vec3(1.0, 1.5, 2.0) };
+        (Collider::helicopter(&bounds), glm::translation(&glm::vec3(0.0, height, 0.0)))
+    }
+
+    fn kinds(resolution: &Resolution) -> Vec<ContactKind> {
+        resolution.events.iter().map(|event| event.kind).collect()
+    }
+
+    #[test]
+    fn slow_landing_on_the_skids_is_landed() {
+        let terrain = terrain(0.0);
+        let (mut collider, world) = helicopter_at(-0.02);
+        let resolution = collider.resolve(&world, &glm::vec3(0.0, -1.0, 0.0), &terrain, &glm::identity());
+
+        assert_eq!(kinds(&resolution), vec![ContactKind::Landed]);
+        assert_eq!(collider.contact, Some(ContactKind::Landed));
+        assert!((resolution.correction.y - 0.02).abs() < 1e-4, "{:?}", resolution.correction);
+        assert!(resolution.correction.xz().norm() < 1e-4);
+        assert!((resolution.ground_normal.unwrap() - glm::vec3(0.0, 1.0, 0.0)).norm() < 1e-4);
+        assert!((resolution.events[0].speed - 1.0).abs() < 1e-4);
+
+        // Staying down doesn't report it again
+        let resolution = collider.resolve(&world, &glm::vec3(0.0, 0.0, 0.0), &terrain, &glm::identity());
+        assert!(resolution.events.is_empty());
+    }
+
+    #[test]
+    fn skids_sunk_past_their_middle_are_pushed_up() {
+        let terrain = terrain(0.0);
+        let (mut collider, world) = helicopter_at(-0.1);
+        let resolution = collider.resolve(&world, &glm::vec3(0.0, -1.0, 0.0), &terrain, &glm::identity());
+        assert_eq!(kinds(&resolution), vec![ContactKind::Landed]);
+        assert!((resolution.correction - glm::vec3(0.0, 0.1, 0.0)).norm() < 1e-4, "{:?}", resolution.correction);
+    }
+
+    #[test]
+    fn fast_landing_is_a_crash() {
+        let terrain = terrain(0.0);
+        let (mut collider, world) = helicopter_at(-0.02);
+        let resolution = collider.resolve(&world, &glm::vec3(0.0, -6.0, 0.0), &terrain, &glm::identity());
+        assert_eq!(kinds(&resolution), vec![ContactKind::Crashed]);
+    }
+
+    #[test]
+    fn touching_down_with_the_body_is_a_crash() {
+        let terrain = terrain(0.0);
+        let body = CollisionShape { shape: Shape::Sphere { center: glm::vec3(0.0, 0.5, 0.0), radius: 0.5 }, part: Part::Body };
+        let mut collider = Collider::new(vec![body]);
+        let world = glm::translation(&glm::vec3(0.0, -0.1, 0.0));
+        let resolution = collider.resolve(&world, &glm::vec3(0.0, -0.5, 0.0), &terrain, &glm::identity());
+        assert_eq!(kinds(&resolution), vec![ContactKind::Crashed]);
+        assert!((resolution.correction.y - 0.1).abs() < 1e-4, "{:?}", resolution.correction);
+    }
+
+    #[test]
+    fn setting_down_on_a_steep_slope_is_a_crash() {
+        // With the uphill skid just touching, and the other one well clear of the ground
+        let terrain = terrain(0.8);
+        let (mut collider, world) = helicopter_at(0.56);
+        let resolution = collider.resolve(&world, &glm::vec3(0.0, -0.5, 0.0), &terrain, &glm::identity());
+        assert_eq!(kinds(&resolution), vec![ContactKind::Crashed]);
+    }
+
+    #[test]
+    fn moving_along_the_ground_is_a_skid() {
+        let terrain = terrain(0.0);
+        let (mut collider, world) = helicopter_at(-0.02);
+        let resolution = collider.resolve(&world, &glm::vec3(3.0, -0.5, 0.0), &terrain, &glm::identity());
+        assert_eq!(kinds(&resolution), vec![ContactKind::Skid]);
+
+        // Slowing down to a stop settles it
+        let resolution = collider.resolve(&world, &glm::vec3(0.5, 0.0, 0.0), &terrain, &glm::identity());
+        assert_eq!(kinds(&resolution), vec![ContactKind::Landed]);
+    }
+
+    #[test]
+    fn a_crash_lasts_until_back_in_the_air() {
+        let terrain = terrain(0.0);
+        let (mut collider, world) = helicopter_at(-0.02);
+        let resolution = collider.resolve(&world, &glm::vec3(0.0, -6.0, 0.0), &terrain, &glm::identity());
+        assert_eq!(kinds(&resolution), vec![ContactKind::Crashed]);
+
+        // Sitting still or sliding along afterwards doesn't turn it into a landing or a skid
+        for velocity in [glm::vec3(0.0, 0.0, 0.0), glm::vec3(3.0, 0.0, 0.0)] {
+            let resolution = collider.resolve(&world, &velocity, &terrain, &glm::identity());
+            assert!(resolution.events.is_empty());
+            assert_eq!(collider.contact, Some(ContactKind::Crashed));
+        }
+
+        let (_, above) = helicopter_at(1.0);
+        let resolution = collider.resolve(&above, &glm::vec3(0.0, 1.0, 0.0), &terrain, &glm::identity());
+        assert!(resolution.events.is_empty());
+        assert!(resolution.ground_normal.is_none());
+        assert_eq!(collider.contact, None);
+
+        let resolution = collider.resolve(&world, &glm::vec3(0.0, -1.0, 0.0), &terrain, &glm::identity());
+        assert_eq!(kinds(&resolution), vec![ContactKind::Landed]);
+    }
+
+    #[test]
+    fn terrain_transform_is_taken_into_account() {
+        let terrain = terrain(0.0);
+        let terrain_world = glm::translation(&glm::vec3(5.0, 2.0, -3.0));
+        let (mut collider, _) = helicopter_at(0.0);
+        let world = glm::translation(&glm::vec3(5.0, 1.9, -3.0));
+        let resolution = collider.resolve(&world, &glm::vec3(0.0, -1.0, 0.0), &terrain, &terrain_world);
+        assert_eq!(kinds(&resolution), vec![ContactKind::Landed]);
+        assert!((resolution.correction.y - 0.1).abs() < 1e-4, "{:?}", resolution.correction);
+        assert!((resolution.events[0].point.y - 2.0).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,562 @@
+use std::collections::HashMap;
+
+use glutin::event::VirtualKeyCode;
+
+use crate::collision::Resolution;
+use crate::flight::{self, Flight, FlightParameters, FlightState};
+use crate::scene_graph::SceneNode;
+
+// Flying the helicopter from the keyboard. Which keys do what is up to a `KeyBindings`, which can
+// be read from a file. While the player has the controls, the camera follows the helicopter, and
+// the helicopter is either flown through the flight model, with collective, cyclic and pedals, or
+// steered arcade style, where it simply goes where it's pointed.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Action {
+    CollectiveUp,   // Climb, when steering arcade style
+    CollectiveDown,
+    PitchForward,
+    PitchBack,
+    RollLeft,       // Strafe, when steering arcade style
+    RollRight,
+    YawLeft,
+    YawRight,
+    ToggleDoor,
+    ToggleSteering,
+    TogglePlayer,   // Take the helicopter over from the scripted path, or hand it back
+}
+
+// What each action is called in a bindings file
+const ACTION_NAMES: [(Action, &str); 11] = [
+    (Action::CollectiveUp,   "collective_up"),
+    (Action::CollectiveDown, "collective_down"),
+    (Action::PitchForward,   "pitch_forward"),
+    (Action::PitchBack,      "pitch_back"),
+    (Action::RollLeft,       "roll_left"),
+    (Action::RollRight,      "roll_right"),
+    (Action::YawLeft,        "yaw_left"),
+    (Action::YawRight,       "yaw_right"),
+    (Action::ToggleDoor,     "toggle_door"),
+    (Action::ToggleSteering, "toggle_steering"),
+    (Action::TogglePlayer,   "toggle_player"),
+];
+
+// The keys that can be bound, by the same names as in `VirtualKeyCode`. Q and Escape aren't among
+// them, as they quit.
+const BINDABLE_KEYS: [VirtualKeyCode; 63] = {
+    use VirtualKeyCode::*;
+    [
+        A, B, C, D, E, F, G, H, I, J, K, L, M, N, O, P, R, S, T, U, V, W, X, Y, Z,
+        Key0, Key1, Key2, Key3, Key4, Key5, Key6, Key7, Key8, Key9,
+        Numpad0, Numpad1, Numpad2, Numpad3, Numpad4, Numpad5, Numpad6, Numpad7, Numpad8, Numpad9,
+        Up, Down, Left, Right, Space, Tab, Return, Back,
+        LShift, RShift, LControl, RControl, LAlt, RAlt,
+        PageUp, PageDown, Home, End,
+    ]
+};
+
+#[derive(Clone, Debug)]
+pub struct KeyBindings {
+    keys : HashMap<Action, Vec<VirtualKeyCode>>,
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        use VirtualKeyCode::*;
+        let keys = vec![
+            (Action::CollectiveUp,   vec![R]),
+            (Action::CollectiveDown, vec![F]),
+            (Action::PitchForward,   vec![I]),
+            (Action::PitchBack,      vec![K]),
+            (Action::RollLeft,       vec![J]),
+            (Action::RollRight,      vec![L]),
+            (Action::YawLeft,        vec![U]),
+            (Action::YawRight,       vec![O]),
+            (Action::ToggleDoor,     vec![E]),
+            (Action::ToggleSteering, vec![M]),
+            (Action::TogglePlayer,   vec![P]),
+        ];
+        KeyBindings { keys: keys.into_iter().collect() }
+    }
+}
+
+impl KeyBindings {
+    // One action per line, as `action = Key, OtherKey`, e.g. `pitch_forward = Up, I`. Actions
+    // that aren't mentioned keep their default keys. Blank lines and lines starting with # are skipped.
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut bindings = KeyBindings::default();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, keys) = line.split_once('=')
+                .ok_or_else(|| format!("line {}: expected `action = keys`", number + 1))?;
+            let action = ACTION_NAMES.iter()
+                .find(|(_, action_name)| *action_name == name.trim())
+                .map(|(action, _)| *action)
+                .ok_or_else(|| format!("line {}: unknown action `{}`", number + 1, name.trim()))?;
+            let keys = keys.split(',')
+                .map(|key| key_named(key.trim()).ok_or_else(|| format!("line {}: unknown key `{}`", number + 1, key.trim())))
+                .collect::<Result<Vec<_>, String>>()?;
+            bindings.keys.insert(action, keys);
+        }
+        Ok(bindings)
+    }
+
+    // None if there's no such file
+    pub fn load(path: &str) -> Result<Option<Self>, String> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("{}: {}", path, e)),
+        };
+        KeyBindings::parse(&text).map(Some).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn keys(&self, action: Action) -> &[VirtualKeyCode] {
+        self.keys.get(&action).map_or(&[], |keys| keys.as_slice())
+    }
+
+    pub fn bind(&mut self, action: Action, keys: Vec<VirtualKeyCode>) {
+        self.keys.insert(action, keys);
+    }
+
+    pub fn is_held(&self, action: Action, pressed: &[VirtualKeyCode]) -> bool {
+        self.keys(action).iter().any(|key| pressed.contains(key))
+    }
+
+    // -1 while only `negative` is held, 1 while only `positive` is, 0 otherwise
+    pub fn axis(&self, negative: Action, positive: Action, pressed: &[VirtualKeyCode]) -> f32 {
+        let held = |action| if self.is_held(action, pressed) { 1.0 } else { 0.0 };
+        held(positive) - held(negative)
+    }
+}
+
+fn key_named(name: &str) -> Option<VirtualKeyCode> {
+    BINDABLE_KEYS.iter().copied().find(|key| format!("{:?}", key) == name)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Steering {
+    Flight, // Through the flight model
+    Arcade, // Straight to where the keys point
+}
+
+// Arcade style steering: the helicopter speeds up towards where the keys say, turns on the spot,
+// and leans into its movement just for show
+pub struct Arcade {
+    pub position    : glm::Vec3,
+    pub yaw         : f32,       // About the world's up axis, 0 with the nose along -Z
+    pub velocity    : glm::Vec3,
+    pub max_speed   : f32,
+    pub climb_speed : f32,
+    pub turn_speed  : f32,       // In radians per second
+    pub response    : f32,       // How quickly the velocity follows the keys, per second
+    pub lean        : f32,       // In radians, at full speed
+    rotor_angle     : f32,
+}
+
+// How fast the rotors seem to spin when steering arcade style, in radians per second
+const ARCADE_ROTOR_SPEED: f32 = 30.0;
+
+impl Arcade {
+    pub fn new(position: glm::Vec3, yaw: f32) -> Self {
+        Arcade {
+            position,
+            yaw,
+            velocity    : glm::vec3(0.0, 0.0, 0.0),
+            max_speed   : 10.0,
+            climb_speed : 4.0,
+            turn_speed  : 1.5,
+            response    : 2.0,
+            lean        : 0.3,
+            rotor_angle : 0.0,
+        }
+    }
+
+    // `forward`, `side`, `climb` and `turn` are from -1 to 1. Positive is forwards, right, up and turning right.
+    pub fn step(&mut self, forward: f32, side: f32, climb: f32, turn: f32, delta_time: f32) {
+        self.yaw -= turn * self.turn_speed * delta_time;
+        let heading = glm::quat_angle_axis(self.yaw, &glm::vec3(0.0, 1.0, 0.0));
+        let wanted = glm::quat_rotate_vec3(&heading, &glm::vec3(side * self.max_speed, climb * self.climb_speed, -forward * self.max_speed));
+        self.velocity += (wanted - self.velocity) * (1.0 - (-self.response * delta_time).exp());
+        self.position += self.velocity * delta_time;
+        self.rotor_angle = (self.rotor_angle + ARCADE_ROTOR_SPEED * delta_time) % std::f32::consts::TAU;
+    }
+
+    pub fn orientation(&self) -> glm::Quat {
+        // Lean the nose down when going forwards, and the right side down when going right
+        let heading = glm::quat_angle_axis(self.yaw, &glm::vec3(0.0, 1.0, 0.0));
+        let local = glm::quat_rotate_vec3(&glm::quat_inverse(&heading), &self.velocity) / self.max_speed;
+        let pitch = glm::quat_angle_axis(local.z * self.lean, &glm::vec3(1.0, 0.0, 0.0));
+        let roll = glm::quat_angle_axis(-local.x * self.lean, &glm::vec3(0.0, 0.0, 1.0));
+        heading * pitch * roll
+    }
+
+    pub fn collide(&mut self, resolution: &Resolution) {
+        self.position += resolution.correction;
+        if let Some(normal) = resolution.ground_normal {
+            let into_ground = glm::dot(&self.velocity, &normal);
+            if into_ground < 0.0 {
+                self.velocity -= normal * into_ground;
+            }
+        }
+    }
+
+    pub fn apply(&self, helicopter: &mut SceneNode, main_rotor: &mut SceneNode, tail_rotor: &mut SceneNode) {
+        helicopter.position = self.position;
+        helicopter.rotation = self.orientation();
+        main_rotor.rotation = glm::quat_angle_axis(self.rotor_angle, &glm::vec3(0.0, 1.0, 0.0));
+        tail_rotor.rotation = glm::quat_angle_axis(self.rotor_angle * 4.0, &glm::vec3(1.0, 0.0, 0.0));
+    }
+}
+
+// A sliding door, which eases in and out of moving rather than jumping between open and shut
+pub struct Door {
+    pub open_offset : glm::Vec3, // Where the door is moved to when open, from where it is when shut
+    pub duration    : f32,       // In seconds, to go all the way
+    pub opening     : bool,
+    progress        : f32,       // 0 when shut, 1 when open
+}
+
+impl Door {
+    pub fn new(open_offset: glm::Vec3, duration: f32) -> Self {
+        Door { open_offset, duration, opening: false, progress: 0.0 }
+    }
+
+    pub fn toggle(&mut self) {
+        self.opening = !self.opening;
+    }
+
+    pub fn update(&mut self, delta_time: f32) {
+        let step = delta_time / self.duration.max(f32::EPSILON);
+        self.progress = if self.opening { self.progress + step } else { self.progress - step }.clamp(0.0, 1.0);
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.progress >= 1.0
+    }
+
+    pub fn offset(&self) -> glm::Vec3 {
+        let t = self.progress;
+        self.open_offset * (t * t * (3.0 - 2.0 * t))
+    }
+
+    pub fn apply(&self, door: &mut SceneNode) {
+        door.position = self.offset();
+    }
+}
+
+// A camera trailing behind whatever it's attached to, catching up smoothly instead of being
+// bolted on, and staying level however the thing it follows is tilted
+pub struct ChaseCamera {
+    pub offset    : glm::Vec3, // Where the camera wants to be, in the followed node's space
+    pub target    : glm::Vec3, // What it looks at, in the same space
+    pub stiffness : f32,       // How quickly it catches up, per second
+    eye           : Option<glm::Vec3>,
+}
+
+impl ChaseCamera {
+    pub fn new(offset: glm::Vec3, target: glm::Vec3) -> Self {
+        ChaseCamera { offset, target, stiffness: 4.0, eye: None }
+    }
+
+    // Jump straight to where the camera wants to be on the next `view_matrix`
+    pub fn reset(&mut self) {
+        self.eye = None;
+    }
+
+    pub fn view_matrix(&mut self, followed_world: &glm::Mat4, delta_time: f32) -> glm::Mat4 {
+        let point = |p: &glm::Vec3| (followed_world * glm::vec4(p.x, p.y, p.z, 1.0)).xyz();
+        let wanted = point(&self.offset);
+        let eye = match self.eye {
+            Some(eye) => eye + (wanted - eye) * (1.0 - (-self.stiffness * delta_time).exp()),
+            None => wanted,
+        };
+        self.eye = Some(eye);
+        glm::look_at(&eye, &point(&self.target), &glm::vec3(0.0, 1.0, 0.0))
+    }
+}
+
+// Everything about the player flying the helicopter
+pub struct Player {
+    pub bindings   : KeyBindings,
+    pub steering   : Steering,
+    pub active     : bool,      // Whether the player has the helicopter, or it's on its scripted path
+    pub collective : f32,       // Held where it was left, unlike the cyclic and pedals
+    pub flight     : Option<Flight>,
+    pub arcade     : Option<Arcade>,
+    pub door       : Door,
+    pub camera     : ChaseCamera,
+    held           : Vec<VirtualKeyCode>, // As of the last `update`, to act on toggles once per press
+}
+
+impl Player {
+    pub fn new(bindings: KeyBindings) -> Self {
+        Player {
+            bindings,
+            steering   : Steering::Flight,
+            active     : false,
+            collective : 0.4,
+            flight     : None,
+            arcade     : None,
+            door       : Door::new(glm::vec3(0.0, 0.0, 2.0), 1.0),
+            camera     : ChaseCamera::new(glm::vec3(0.0, 4.0, 14.0), glm::vec3(0.0, 1.0, -4.0)),
+            held       : vec![],
+        }
+    }
+
+    fn just_pressed(&self, action: Action, pressed: &[VirtualKeyCode]) -> bool {
+        self.bindings.keys(action).iter().any(|key| pressed.contains(key) && !self.held.contains(key))
+    }
+
+    // Act on the keys for this frame. The helicopter is moved if the player has it, otherwise it's
+    // only looked at, in case the player takes it over from where it is.
+    pub fn update(&mut self, pressed: &[VirtualKeyCode], delta_time: f32, helicopter: &SceneNode) {
+        if self.just_pressed(Action::TogglePlayer, pressed) {
+            self.active = !self.active;
+            if self.active {
+                self.take_over(helicopter);
+            }
+        }
+        if self.active && self.just_pressed(Action::ToggleSteering, pressed) {
+            self.steering = match self.steering {
+                Steering::Flight => Steering::Arcade,
+                Steering::Arcade => Steering::Flight,
+            };
+            self.take_over(helicopter);
+        }
+        if self.active && self.just_pressed(Action::ToggleDoor, pressed) {
+            self.door.toggle();
+        }
+        self.held = pressed.to_vec();
+        self.door.update(delta_time);
+
+        if !self.active {
+            return;
+        }
+        let b = &self.bindings;
+        let forward = b.axis(Action::PitchBack, Action::PitchForward, pressed);
+        let side = b.axis(Action::RollLeft, Action::RollRight, pressed);
+        let climb = b.axis(Action::CollectiveDown, Action::CollectiveUp, pressed);
+        let turn = b.axis(Action::YawLeft, Action::YawRight, pressed);
+
+        if let Some(flight) = self.flight.as_mut() {
+            self.collective = (self.collective + climb * 0.5 * delta_time).clamp(0.0, 1.0);
+            let controls = flight::Controls { collective: self.collective, pitch: forward, roll: side, yaw: turn, throttle: 1.0 };
+            flight.advance(delta_time, &controls);
+        }
+        if let Some(arcade) = self.arcade.as_mut() {
+            arcade.step(forward, side, climb, turn, delta_time);
+        }
+    }
+
+    // Start steering from wherever the helicopter is now, with the rotors already spinning
+    fn take_over(&mut self, helicopter: &SceneNode) {
+        self.flight = None;
+        self.arcade = None;
+        match self.steering {
+            Steering::Flight => {
+                let state = FlightState::at(helicopter.position, helicopter.rotation);
+                let mut flight = Flight::new(FlightParameters::default(), state);
+                flight.state.rotor_speed = flight.parameters.max_rotor_speed;
+                self.flight = Some(flight);
+            }
+            Steering::Arcade => {
+                // The yaw of where the nose points, ignoring any pitch and roll
+                let nose = glm::quat_rotate_vec3(&helicopter.rotation, &glm::vec3(0.0, 0.0, -1.0));
+                self.arcade = Some(Arcade::new(helicopter.position, (-nose.x).atan2(-nose.z)));
+            }
+        }
+        self.camera.reset();
+    }
+
+    // How fast the player's helicopter is going, in world units per second
+    pub fn velocity(&self) -> glm::Vec3 {
+        match (&self.flight, &self.arcade) {
+            (Some(flight), _) => flight.state.velocity,
+            (_, Some(arcade)) => arcade.velocity,
+            _ => glm::vec3(0.0, 0.0, 0.0),
+        }
+    }
+
+    pub fn collide(&mut self, resolution: &Resolution) {
+        if let Some(flight) = self.flight.as_mut() {
+            flight.collide(resolution);
+        }
+        if let Some(arcade) = self.arcade.as_mut() {
+            arcade.collide(resolution);
+        }
+    }
+
+    // Move the helicopter's nodes to where the player has flown it. The door is moved even when
+    // the helicopter is on its scripted path, so it can finish closing after the player lets go.
+    pub fn apply(&self, helicopter: &mut SceneNode, main_rotor: &mut SceneNode, tail_rotor: &mut SceneNode, door: &mut SceneNode) {
+        self.door.apply(door);
+        if !self.active {
+            return;
+        }
+        if let Some(flight) = &self.flight {
+            flight.apply(helicopter, main_rotor, tail_rotor);
+        }
+        if let Some(arcade) = &self.arcade {
+            arcade.apply(helicopter, main_rotor, tail_rotor);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use VirtualKeyCode::*;
+
+    #[test]
+    fn several_keys_on_one_line() {
+        let bindings = KeyBindings::parse("# Arrows as well\npitch_forward = Up, I\n\n  yaw_left=Z  ").unwrap();
+        assert_eq!(bindings.keys(Action::PitchForward), &[Up, I]);
+        assert_eq!(bindings.keys(Action::YawLeft), &[Z]);
+        assert!(bindings.is_held(Action::PitchForward, &[Up]));
+        assert!(bindings.is_held(Action::PitchForward, &[I]));
+    }
+
+    #[test]
+    fn actions_not_mentioned_keep_their_defaults() {
+        let bindings = KeyBindings::parse("roll_left = A").unwrap();
+        let defaults = KeyBindings::default();
+        for (action, _) in ACTION_NAMES.iter().filter(|(action, _)| *action != Action::RollLeft) {
+            assert_eq!(bindings.keys(*action), defaults.keys(*action), "{:?}", action);
+        }
+        assert_eq!(bindings.keys(Action::RollLeft), &[A]);
+    }
+
+    #[test]
+    fn unknown_actions_and_keys_are_errors() {
+        let error = KeyBindings::parse("roll_left = A\nbarrel_roll = B").unwrap_err();
+        assert!(error.contains("line 2") && error.contains("barrel_roll"), "{}", error);
+
+        let error = KeyBindings::parse("roll_left = A, Banana").unwrap_err();
+        assert!(error.contains("line 1") && error.contains("Banana"), "{}", error);
+
+        assert!(KeyBindings::parse("roll_left A").is_err());
+
+        // Those would quit
+        for key in ["Q", "Escape"] {
+            let error = KeyBindings::parse(&format!("roll_left = {}", key)).unwrap_err();
+            assert!(error.contains(key), "{}", error);
+        }
+    }
+
+    #[test]
+    fn a_missing_file_is_not_an_error() {
+        assert!(KeyBindings::load("./resources/no_such_controls.txt").unwrap().is_none());
+    }
+
+    fn nose(arcade: &Arcade) -> glm::Vec3 {
+        glm::quat_rotate_vec3(&arcade.orientation(), &glm::vec3(0.0, 0.0, -1.0))
+    }
+
+    #[test]
+    fn arcade_speeds_up_towards_the_keys() {
+        let mut arcade = Arcade::new(glm::vec3(0.0, 10.0, 0.0), 0.0);
+        let mut last_speed = 0.0;
+        for _ in 0..60 {
+            arcade.step(1.0, 0.0, 0.0, 0.0, 1.0 / 60.0);
+            let speed = glm::length(&arcade.velocity);
+            assert!(speed > last_speed);
+            last_speed = speed;
+        }
+        // Nose first along -Z, and not yet at full speed after a second
+        assert!(arcade.velocity.z < 0.0 && arcade.velocity.x.abs() < 1e-5, "{}", arcade.velocity);
+        assert!(last_speed < arcade.max_speed);
+        assert!(arcade.position.z < 0.0);
+
+        for _ in 0..600 {
+            arcade.step(1.0, 0.0, 0.0, 0.0, 1.0 / 60.0);
+        }
+        assert!((arcade.velocity.z + arcade.max_speed).abs() < 1e-2, "{}", arcade.velocity);
+
+        let mut sideways = Arcade::new(glm::vec3(0.0, 10.0, 0.0), 0.0);
+        for _ in 0..60 {
+            sideways.step(0.0, 1.0, 1.0, 0.0, 1.0 / 60.0);
+        }
+        assert!(sideways.velocity.x > 0.0 && sideways.velocity.y > 0.0, "{}", sideways.velocity);
+        assert!(sideways.velocity.y < sideways.climb_speed);
+    }
+
+    #[test]
+    fn arcade_turns_the_right_way() {
+        let mut arcade = Arcade::new(glm::vec3(0.0, 10.0, 0.0), 0.0);
+        // A quarter turn to the right takes the nose from -Z to +X
+        let seconds = std::f32::consts::FRAC_PI_2 / arcade.turn_speed;
+        let frames = (seconds * 60.0).round() as usize;
+        for _ in 0..frames {
+            arcade.step(0.0, 0.0, 0.0, 1.0, seconds / frames as f32);
+        }
+        assert!(glm::distance(&nose(&arcade), &glm::vec3(1.0, 0.0, 0.0)) < 1e-3, "{}", nose(&arcade));
+
+        // And forwards is now along +X
+        for _ in 0..60 {
+            arcade.step(1.0, 0.0, 0.0, 0.0, 1.0 / 60.0);
+        }
+        assert!(arcade.velocity.x > 0.0 && arcade.velocity.z.abs() < 1e-3, "{}", arcade.velocity);
+    }
+
+    #[test]
+    fn door_eases_open_and_shut() {
+        let mut door = Door::new(glm::vec3(0.0, 0.0, 2.0), 1.0);
+        door.update(0.5);
+        assert_eq!(door.offset(), glm::vec3(0.0, 0.0, 0.0));
+
+        door.toggle();
+        door.update(0.25);
+        // Slow to start, and halfway at half the time
+        assert!((door.offset().z - 2.0 * 0.15625).abs() < 1e-5, "{}", door.offset());
+        door.update(0.25);
+        assert!((door.offset().z - 1.0).abs() < 1e-5, "{}", door.offset());
+        assert!(!door.is_open());
+
+        // No further than all the way
+        door.update(5.0);
+        assert!(door.is_open());
+        assert_eq!(door.offset(), glm::vec3(0.0, 0.0, 2.0));
+
+        door.toggle();
+        door.update(0.5);
+        assert!((door.offset().z - 1.0).abs() < 1e-5, "{}", door.offset());
+        door.update(5.0);
+        assert_eq!(door.offset(), glm::vec3(0.0, 0.0, 0.0));
+    }
+
+    #[test]
+    fn toggles_act_once_per_press() {
+        let helicopter = SceneNode::named("helicopter");
+        let mut player = Player::new(KeyBindings::default());
+
+        // Held down over several frames, it's still only one press
+        for _ in 0..3 {
+            player.update(&[P], 0.1, &helicopter);
+            assert!(player.active);
+        }
+        player.update(&[], 0.1, &helicopter);
+        assert!(player.active);
+        player.update(&[P], 0.1, &helicopter);
+        assert!(!player.active);
+        player.update(&[], 0.1, &helicopter);
+
+        // The door only works while flying
+        player.update(&[E], 0.1, &helicopter);
+        assert!(!player.door.opening);
+        player.update(&[P], 0.1, &helicopter);
+        player.update(&[P, E], 0.1, &helicopter);
+        player.update(&[P, E], 0.1, &helicopter);
+        assert!(player.active && player.door.opening);
+
+        // Switching steering starts over from where the helicopter is
+        assert!(player.flight.is_some() && player.arcade.is_none());
+        player.update(&[M], 0.1, &helicopter);
+        player.update(&[M], 0.1, &helicopter);
+        assert_eq!(player.steering, Steering::Arcade);
+        assert!(player.flight.is_none() && player.arcade.is_some());
+    }
+}
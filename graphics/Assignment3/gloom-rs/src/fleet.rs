@@ -0,0 +1,209 @@
+use std::rc::Rc;
+
+use crate::animation::{AnimationClip, Animator, PlaybackMode};
+use crate::bvh::Bvh;
+use crate::gl_objects::Vao;
+use crate::mesh::{Helicopter, Mesh};
+use crate::scene_graph::{Node, SceneNode};
+use crate::toolbox::Heading;
+use crate::vertex_layout::VertexArrayBuilder;
+
+// Lots of helicopters out of one. A `Model` uploads the meshes of a loaded model once, and every
+// copy made with `Model::instantiate` draws those same buffers from a subtree of its own, so it
+// can be moved about on its own. A `Fleet` keeps such copies flying along paths like
+// `toolbox::simple_heading_animation`, each with its own path and how far ahead of or behind the
+// others it is, and keeps them clear of the terrain where the path runs into it.
+
+// Where the tail rotor turns about, in the model's space
+const TAIL_ROTOR_PIVOT: [f32; 3] = [0.35, 2.3, 10.4];
+
+pub struct ModelPart {
+    pub name            : String,    // Of the node that draws it, below the model's own node
+    pub vao             : Rc<Vao>,
+    pub mesh            : Rc<Mesh>,
+    pub reference_point : glm::Vec3, // For the node that draws it
+}
+
+pub struct Model {
+    pub parts : Vec<ModelPart>,
+}
+
+// Where something flying a path is at a given time, in seconds, and how high up
+pub type HeadingPath = Rc<dyn Fn(f32) -> (Heading, f32)>;
+
+pub struct FleetMember {
+    pub node        : Node,        // Hangs off the parent it was spawned under, with the parts below it
+    pub path        : HeadingPath,
+    pub time_offset : f32,         // How many seconds ahead of the fleet's clock it is along its path
+    pub clearance   : f32,         // The least height it keeps above the terrain
+    rotors          : Animator,
+}
+
+pub struct Fleet {
+    pub name       : String,             // The members are called this, followed by their number
+    pub rotor_clip : Rc<AnimationClip>,  // Looped on every member, as far ahead as it is along its path
+    pub clearance  : f32,                // For members spawned from now on
+    pub members    : Vec<FleetMember>,
+    pub time       : f32,                // Seconds since the fleet set off
+}
+
+impl Model {
+    // Upload every part, named after the node that should draw it. Needs a current GL context.
+    pub unsafe fn new(parts: Vec<(&str, Mesh, glm::Vec3)>) -> Self {
+        let parts = parts.into_iter()
+            .map(|(name, mesh, reference_point)| ModelPart {
+                name : name.to_string(),
+                vao  : Rc::new(VertexArrayBuilder::new(&mesh).build()),
+                mesh : Rc::new(mesh),
+                reference_point,
+            })
+            .collect();
+        Model { parts }
+    }
+
+    // The body, rotors and door, for nodes named "body", "main_rotor", "tail_rotor" and "door"
+    pub unsafe fn helicopter(helicopter: Helicopter) -> Self {
+        let origin = glm::vec3(0.0, 0.0, 0.0);
+        Model::new(vec![
+            ("body", helicopter.body, origin),
+            ("main_rotor", helicopter.main_rotor, origin),
+            ("tail_rotor", helicopter.tail_rotor, glm::Vec3::from(TAIL_ROTOR_PIVOT)),
+            ("door", helicopter.door, origin),
+        ])
+    }
+
+    // Make the nodes below `root` draw the parts they're named after. Parts without a node are left out.
+    pub fn dress(&self, root: &mut SceneNode) {
+        for part in &self.parts {
+            if let Some(node) = root.find(&part.name) {
+                node.set_vao(part.vao.clone());
+                node.set_mesh(part.mesh.clone());
+                node.reference_point = part.reference_point;
+            }
+        }
+    }
+
+    // A new copy to place in the scene: a node called `name` with a child drawing each part
+    pub fn instantiate(&self, name: &str) -> Node {
+        let mut root = SceneNode::named(name);
+        for part in &self.parts {
+            let child = SceneNode::named(&part.name);
+            root.add_child(&child);
+        }
+        self.dress(&mut root);
+        root
+    }
+}
+
+impl Fleet {
+    pub fn new(name: &str, rotor_clip: Rc<AnimationClip>, clearance: f32) -> Self {
+        Fleet { name: name.to_string(), rotor_clip, clearance, members: vec![], time: 0.0 }
+    }
+
+    // Add a copy of `model` under `parent`, flying `path` `time_offset` seconds ahead of the rest of the fleet
+    pub fn spawn(&mut self, model: &Model, parent: &mut SceneNode, path: HeadingPath, time_offset: f32) -> &mut FleetMember {
+        let mut node = model.instantiate(&format!("{}_{}", self.name, self.members.len()));
+        parent.add_child(&node);
+        let mut rotors = Animator::new(self.rotor_clip.clone(), &mut node, PlaybackMode::Loop);
+        rotors.time = time_offset;
+
+        let mut member = FleetMember { node, path, time_offset, clearance: self.clearance, rotors };
+        member.update(self.time, None);
+        self.members.push(member);
+        self.members.last_mut().unwrap()
+    }
+
+    // Add `count` copies, asking `placement` for the path and time offset of each by its number
+    pub fn spawn_many(&mut self, model: &Model, parent: &mut SceneNode, count: usize, mut placement: impl FnMut(usize) -> (HeadingPath, f32)) {
+        for i in 0..count {
+            let (path, time_offset) = placement(i);
+            self.spawn(model, parent, path, time_offset);
+        }
+    }
+
+    // Move the fleet on by `delta_time` seconds. `terrain_world` is the matrix of the node `terrain`
+    // belongs to, in the space of the members' parent.
+    pub fn update(&mut self, delta_time: f32, terrain: Option<(&Bvh, &glm::Mat4)>) {
+        self.time += delta_time;
+        for member in &mut self.members {
+            member.rotors.advance(delta_time);
+            member.update(self.time, terrain);
+        }
+    }
+}
+
+impl FleetMember {
+    // Put the helicopter where its path has it at the fleet's `time`, lifted over the terrain if need be
+    fn update(&mut self, time: f32, terrain: Option<(&Bvh, &glm::Mat4)>) {
+        let (heading, height) = (self.path)(time + self.time_offset);
+        self.node.position = glm::vec3(heading.x, height, heading.z);
+        self.node.rotation = heading.rotation();
+        if let Some((terrain, terrain_world)) = terrain {
+            self.keep_clear(terrain, terrain_world);
+        }
+        self.rotors.apply();
+    }
+
+    fn keep_clear(&mut self, terrain: &Bvh, terrain_world: &glm::Mat4) {
+        let p = self.node.position;
+        let mut local = glm::inverse(terrain_world) * glm::vec4(p.x, p.y, p.z, 1.0);
+        if let Some(ground) = terrain.height_at(local.x, local.z) {
+            if local.y < ground + self.clearance {
+                local.y = ground + self.clearance;
+                self.node.position = (terrain_world * local).xyz();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::{Keyframes, Track};
+    use crate::mesh::fixtures;
+
+    // Flying along +X, climbing a unit a second from 1 up
+    fn climb() -> HeadingPath {
+        Rc::new(|time| (Heading { x: time, z: 0.0, roll: 0.0, pitch: 0.0, yaw: 0.0 }, 1.0 + time))
+    }
+
+    fn escorts(root: &mut SceneNode, offsets: &[f32]) -> Fleet {
+        let rotor_clip = Rc::new(AnimationClip::new("rotors", vec![
+            Track::new("main_rotor", Keyframes::spin(&glm::vec3(0.0, 1.0, 0.0), 1.0, 1.0)),
+        ]));
+        // No parts, so nothing has to be uploaded
+        let model = Model { parts: vec![] };
+        let mut fleet = Fleet::new("escort", rotor_clip, 2.0);
+        fleet.spawn_many(&model, root, offsets.len(), |i| (climb(), offsets[i]));
+        fleet
+    }
+
+    #[test]
+    fn members_follow_the_height_of_their_path() {
+        let mut root = SceneNode::named("root");
+        let mut fleet = escorts(&mut root, &[0.0, -1.0]);
+        fleet.update(3.0, None);
+        assert_eq!(fleet.members[0].node.position, glm::vec3(3.0, 4.0, 0.0));
+        assert_eq!(fleet.members[1].node.position, glm::vec3(2.0, 3.0, 0.0));
+        assert!(root.find("escort_1").is_some());
+    }
+
+    #[test]
+    fn members_keep_clear_of_the_terrain() {
+        let mut root = SceneNode::named("root");
+        let mut fleet = escorts(&mut root, &[0.0, -2.0]);
+        // A flat plain 3 units up, from the origin to 20 along X and Z
+        let terrain = Bvh::build(&fixtures::grid(20));
+        let terrain_world = glm::translation(&glm::vec3(0.0, 3.0, 0.0));
+
+        fleet.update(4.0, Some((&terrain, &terrain_world)));
+        // Where the path is high enough it's left alone, and lower down it's lifted to 2 above the ground
+        assert_eq!(fleet.members[0].node.position, glm::vec3(4.0, 5.0, 0.0));
+        assert!(glm::distance(&fleet.members[1].node.position, &glm::vec3(2.0, 5.0, 0.0)) < 1e-4, "{}", fleet.members[1].node.position);
+
+        // Off the edge of the terrain, there's nothing to keep clear of
+        let mut fleet = escorts(&mut root, &[-30.0]);
+        fleet.update(0.0, Some((&terrain, &terrain_world)));
+        assert_eq!(fleet.members[0].node.position, glm::vec3(-30.0, -29.0, 0.0));
+    }
+}
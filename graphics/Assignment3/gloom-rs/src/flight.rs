@@ -0,0 +1,281 @@
+use crate::collision::Resolution;
+use crate::scene_graph::SceneNode;
+
+// A small rigid body model of a helicopter, to fly it around instead of following a scripted path.
+// The main rotor pushes along the helicopter's up axis as hard as the collective asks for, cyclic
+// tilts it forwards, backwards and sideways, and the tail rotor turns it about its up axis. Gravity
+// and a bit of drag do the rest.
+//
+// It's stepped at a fixed rate no matter the frame rate, so the same inputs always give the same
+// flight, down to the last bit, and it can be tested without a window.
+//
+// The helicopter's nose points along -Z and its up is +Y, as in the model.
+
+// What the pilot is asking for
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Controls {
+    pub collective : f32, // 0 to 1, how hard the main rotor pushes
+    pub pitch      : f32, // -1 to 1, cyclic. Positive tips the nose down, to go forwards.
+    pub roll       : f32, // -1 to 1, cyclic. Positive tips the right side down.
+    pub yaw        : f32, // -1 to 1, tail rotor pedals. Positive turns right.
+    pub throttle   : f32, // 0 to 1, how fast the rotors should spin
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlightParameters {
+    pub mass             : f32,
+    pub max_thrust       : f32,       // At full collective and full rotor speed
+    pub gravity          : f32,       // Downwards, in units per second squared
+    pub linear_drag      : f32,       // Per second, of the velocity. The moon has next to no air to slow us down.
+    pub angular_drag     : f32,       // Per second, of the angular velocity
+    pub inertia          : glm::Vec3, // About the helicopter's own X, Y and Z axes
+    pub cyclic_torque    : f32,       // At full cyclic and full rotor speed
+    pub tail_torque      : f32,       // At full pedal and full rotor speed
+    pub max_rotor_speed  : f32,       // Main rotor, in radians per second. The tail rotor spins faster.
+    pub rotor_spool_rate : f32,       // How quickly the rotor speed follows the throttle, per second
+    pub ground_friction  : f32,       // Per second, of the velocity and spin while touching the ground
+    pub timestep         : f32,       // In seconds
+}
+
+impl Default for FlightParameters {
+    fn default() -> Self {
+        FlightParameters {
+            mass             : 1.0,
+            max_thrust       : 4.0,
+            gravity          : 1.62,
+            linear_drag      : 0.05,
+            angular_drag     : 2.0,
+            inertia          : glm::vec3(1.0, 1.5, 1.0),
+            cyclic_torque    : 2.0,
+            tail_torque      : 2.0,
+            max_rotor_speed  : 30.0,
+            rotor_spool_rate : 0.5,
+            ground_friction  : 3.0,
+            timestep         : 1.0 / 120.0,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlightState {
+    pub position         : glm::Vec3,
+    pub velocity         : glm::Vec3,
+    pub orientation      : glm::Quat,
+    pub angular_velocity : glm::Vec3, // In the helicopter's own space, in radians per second
+    pub rotor_speed      : f32,       // Main rotor, in radians per second
+    pub main_rotor_angle : f32,
+    pub tail_rotor_angle : f32,
+}
+
+impl FlightState {
+    pub fn at(position: glm::Vec3, orientation: glm::Quat) -> Self {
+        FlightState {
+            position,
+            velocity         : glm::vec3(0.0, 0.0, 0.0),
+            orientation,
+            angular_velocity : glm::vec3(0.0, 0.0, 0.0),
+            rotor_speed      : 0.0,
+            main_rotor_angle : 0.0,
+            tail_rotor_angle : 0.0,
+        }
+    }
+}
+
+pub struct Flight {
+    pub parameters : FlightParameters,
+    pub state      : FlightState,
+    pub on_ground  : bool,
+    accumulator    : f32, // Time not yet stepped through
+}
+
+// How many times faster the tail rotor spins than the main rotor
+const TAIL_ROTOR_RATIO: f32 = 4.0;
+
+// The most time `Flight::advance` catches up on in one call, in seconds. After a long hitch, like
+// dragging the window about, the rest is dropped rather than stalling the next frame on thousands of steps.
+const MAX_CATCH_UP: f32 = 0.25;
+
+impl Flight {
+    pub fn new(parameters: FlightParameters, state: FlightState) -> Self {
+        Flight { parameters, state, on_ground: false, accumulator: 0.0 }
+    }
+
+    // Run the simulation for `delta_time` seconds, in as many whole steps as fit. What's left
+    // over is carried on to the next call. No more than `MAX_CATCH_UP` seconds are run at once.
+    // Returns the number of steps taken.
+    pub fn advance(&mut self, delta_time: f32, controls: &Controls) -> usize {
+        self.accumulator = (self.accumulator + delta_time).min(MAX_CATCH_UP);
+        let mut steps = 0;
+        while self.accumulator >= self.parameters.timestep {
+            self.step(controls);
+            self.accumulator -= self.parameters.timestep;
+            steps += 1;
+        }
+        steps
+    }
+
+    // One step of `parameters.timestep` seconds, with semi-implicit Euler
+    pub fn step(&mut self, controls: &Controls) {
+        let p = &self.parameters;
+        let s = &mut self.state;
+        let dt = p.timestep;
+        let clamp = |x: f32, low: f32| x.clamp(low, 1.0);
+
+        // The rotors take a while to get up to speed, and the forces they make grow with it
+        let target_speed = clamp(controls.throttle, 0.0) * p.max_rotor_speed;
+        s.rotor_speed += (target_speed - s.rotor_speed) * (p.rotor_spool_rate * dt).min(1.0);
+        let spin = s.rotor_speed / p.max_rotor_speed;
+        let lift = spin * spin;
+
+        let up = glm::quat_rotate_vec3(&s.orientation, &glm::vec3(0.0, 1.0, 0.0));
+        let thrust = up * (clamp(controls.collective, 0.0) * p.max_thrust * lift);
+        let gravity = glm::vec3(0.0, -p.gravity * p.mass, 0.0);
+        let drag = -s.velocity * (p.linear_drag * p.mass);
+        let acceleration = (thrust + gravity + drag) / p.mass;
+        s.velocity += acceleration * dt;
+        let friction = if self.on_ground { (1.0 - p.ground_friction * dt).max(0.0) } else { 1.0 };
+        s.velocity *= friction;
+        s.position += s.velocity * dt;
+
+        // Torques in the helicopter's own space. See `Controls` for which way is which.
+        let torque = glm::vec3(
+            -clamp(controls.pitch, -1.0) * p.cyclic_torque * lift,
+            -clamp(controls.yaw, -1.0) * p.tail_torque * lift,
+            -clamp(controls.roll, -1.0) * p.cyclic_torque * lift,
+        ) - s.angular_velocity * p.angular_drag;
+        s.angular_velocity += torque.component_div(&p.inertia) * dt;
+        s.angular_velocity *= friction;
+
+        let angle = glm::length(&s.angular_velocity) * dt;
+        if angle > 0.0 {
+            let turn = glm::quat_angle_axis(angle, &glm::normalize(&s.angular_velocity));
+            s.orientation = glm::quat_normalize(&(s.orientation * turn));
+        }
+
+        let tau = std::f32::consts::TAU;
+        s.main_rotor_angle = (s.main_rotor_angle + s.rotor_speed * dt) % tau;
+        s.tail_rotor_angle = (s.tail_rotor_angle + s.rotor_speed * TAIL_ROTOR_RATIO * dt) % tau;
+    }
+
+    // Take a collision with the ground into account: move out of it, and stop moving into it
+    pub fn collide(&mut self, resolution: &Resolution) {
+        self.state.position += resolution.correction;
+        self.on_ground = resolution.ground_normal.is_some();
+        if let Some(normal) = resolution.ground_normal {
+            let into_ground = glm::dot(&self.state.velocity, &normal);
+            if into_ground < 0.0 {
+                self.state.velocity -= normal * into_ground;
+            }
+        }
+    }
+
+    // Move the helicopter's nodes to where the simulation has it. The helicopter node is taken
+    // to hang right off the root, so its position is in world space.
+    pub fn apply(&self, helicopter: &mut SceneNode, main_rotor: &mut SceneNode, tail_rotor: &mut SceneNode) {
+        helicopter.position = self.state.position;
+        helicopter.rotation = self.state.orientation;
+        main_rotor.rotation = glm::quat_angle_axis(self.state.main_rotor_angle, &glm::vec3(0.0, 1.0, 0.0));
+        tail_rotor.rotation = glm::quat_angle_axis(self.state.tail_rotor_angle, &glm::vec3(1.0, 0.0, 0.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn level_at(position: glm::Vec3) -> Flight {
+        Flight::new(FlightParameters::default(), FlightState::at(position, glm::quat_identity()))
+    }
+
+    // Rotors up to full speed, and collective just right for the thrust to cancel out gravity
+    fn hover() -> Controls {
+        let p = FlightParameters::default();
+        Controls { collective: p.gravity * p.mass / p.max_thrust, throttle: 1.0, ..Controls::default() }
+    }
+
+    // Fly for `seconds` at 60 frames a second
+    fn fly(flight: &mut Flight, seconds: f32, controls: &Controls) {
+        for _ in 0..(seconds * 60.0).round() as usize {
+            flight.advance(1.0 / 60.0, controls);
+        }
+    }
+
+    #[test]
+    fn same_inputs_give_the_same_flight_whatever_the_frame_rate() {
+        let controls = Controls { collective: 0.6, pitch: 0.3, roll: -0.2, yaw: 0.5, throttle: 1.0 };
+        let mut stepped = level_at(glm::vec3(0.0, 10.0, 0.0));
+        let mut choppy = level_at(glm::vec3(0.0, 10.0, 0.0));
+
+        let mut steps = 0;
+        for frame in 0..200 {
+            steps += choppy.advance(if frame % 3 == 0 { 0.005 } else { 0.0125 }, &controls);
+        }
+        for _ in 0..steps {
+            stepped.step(&controls);
+        }
+        assert!(steps > 200);
+        assert_eq!(stepped.state, choppy.state);
+    }
+
+    #[test]
+    fn long_frames_only_catch_up_so_far() {
+        let mut flight = level_at(glm::vec3(0.0, 10.0, 0.0));
+        let steps = flight.advance(10.0, &hover());
+        // Give or take the one that rounding loses
+        let most = (MAX_CATCH_UP / flight.parameters.timestep).round() as usize;
+        assert!(steps <= most && steps + 1 >= most, "{}", steps);
+        // Nothing is left over from the hitch for the frames after it
+        assert!(flight.advance(0.5 * flight.parameters.timestep, &hover()) <= 1);
+    }
+
+    #[test]
+    fn falls_like_a_stone_with_the_engine_off() {
+        let mut flight = level_at(glm::vec3(0.0, 100.0, 0.0));
+        flight.parameters.linear_drag = 0.0;
+        fly(&mut flight, 2.0, &Controls::default());
+        // Semi-implicit Euler lands a little below the exact 100 - g t² / 2
+        let expected = 100.0 - 0.5 * flight.parameters.gravity * 4.0;
+        assert!((flight.state.position.y - expected).abs() < 0.05, "{}", flight.state.position.y);
+        assert_eq!(flight.state.rotor_speed, 0.0);
+    }
+
+    #[test]
+    fn hovers_once_the_rotor_is_up_to_speed() {
+        let mut flight = level_at(glm::vec3(0.0, 10.0, 0.0));
+        flight.state.rotor_speed = flight.parameters.max_rotor_speed;
+        fly(&mut flight, 5.0, &hover());
+        assert!(glm::length(&(flight.state.position - glm::vec3(0.0, 10.0, 0.0))) < 1e-3, "{}", flight.state.position);
+    }
+
+    #[test]
+    fn rotor_speed_follows_the_throttle() {
+        let mut flight = level_at(glm::vec3(0.0, 10.0, 0.0));
+        let controls = Controls { throttle: 0.5, ..Controls::default() };
+        fly(&mut flight, 1.0, &controls);
+        let spooling = flight.state.rotor_speed;
+        fly(&mut flight, 20.0, &controls);
+        assert!(spooling > 0.0 && spooling < flight.state.rotor_speed);
+        assert!((flight.state.rotor_speed - 0.5 * flight.parameters.max_rotor_speed).abs() < 0.1);
+    }
+
+    #[test]
+    fn cyclic_and_pedals_fly_the_way_they_should() {
+        let forward = |controls: Controls| {
+            let mut flight = level_at(glm::vec3(0.0, 10.0, 0.0));
+            flight.state.rotor_speed = flight.parameters.max_rotor_speed;
+            fly(&mut flight, 1.0, &controls);
+            flight
+        };
+
+        let nose_down = forward(Controls { pitch: 0.5, ..hover() });
+        assert!(nose_down.state.position.z < -0.01, "{}", nose_down.state.position);
+
+        let right = forward(Controls { roll: 0.5, ..hover() });
+        assert!(right.state.position.x > 0.01, "{}", right.state.position);
+
+        // Turning right takes the nose from -Z towards +X
+        let turned = forward(Controls { yaw: 0.5, ..hover() });
+        let nose = glm::quat_rotate_vec3(&turned.state.orientation, &glm::vec3(0.0, 0.0, -1.0));
+        assert!(nose.x > 0.1, "{}", nose);
+    }
+}
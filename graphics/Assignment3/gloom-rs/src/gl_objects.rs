@@ -0,0 +1,394 @@
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::{mem, os::raw::c_void, ptr};
+
+use crate::mesh::Mesh;
+use crate::vertex_layout::VertexLayout;
+
+// Owned handles to OpenGL objects, which delete the object they wrap when dropped.
+//
+// An OpenGL object only means something in the context that created it, and our context lives
+// on the render thread for its entire life. The raw pointer in `ContextBound` makes all of these
+// types !Send and !Sync, so the compiler refuses to let one wander off to another thread (and by
+// extension, to another context) where its id would silently refer to something else.
+type ContextBound = PhantomData<*const ()>;
+
+pub struct Buffer {
+    id      : u32,
+    target  : gl::types::GLenum,   // e.g. ARRAY_BUFFER or ELEMENT_ARRAY_BUFFER
+    size    : Cell<usize>,         // In bytes. Changes when the storage is re-specified.
+    usage   : gl::types::GLenum,   // STATIC_DRAW, or DYNAMIC_DRAW/STREAM_DRAW for data that changes
+    _context: ContextBound,
+}
+
+impl Buffer {
+    // Create a buffer, bind it to `target` and fill it with `data`
+    pub unsafe fn new<T>(target: gl::types::GLenum, data: &[T], usage: gl::types::GLenum) -> Self {
+        let mut id = 0;
+        gl::GenBuffers(1, &mut id);
+        gl::BindBuffer(target, id);
+        gl::BufferData(
+            target,
+            mem::size_of_val(data) as isize,
+            if data.is_empty() { ptr::null() } else { data.as_ptr() as *const c_void },
+            usage,
+        );
+        Buffer {
+            id,
+            target,
+            size: Cell::new(mem::size_of_val(data)),
+            usage,
+            _context: PhantomData,
+        }
+    }
+
+    // Create a buffer of `size` bytes with undefined contents, to be filled in with `update_range`
+    pub unsafe fn with_capacity(target: gl::types::GLenum, size: usize, usage: gl::types::GLenum) -> Self {
+        let mut id = 0;
+        gl::GenBuffers(1, &mut id);
+        gl::BindBuffer(target, id);
+        gl::BufferData(target, size as isize, ptr::null(), usage);
+        Buffer {
+            id,
+            target,
+            size: Cell::new(size),
+            usage,
+            _context: PhantomData,
+        }
+    }
+
+    // Overwrite part of the buffer, starting `offset` bytes in. The size of the buffer is unchanged.
+    pub unsafe fn update_range<T>(&self, offset: usize, data: &[T]) {
+        let length = mem::size_of_val(data);
+        if offset + length > self.size() {
+            panic!("Tried to write bytes {}..{} of a buffer of {} bytes", offset, offset + length, self.size());
+        }
+        if length == 0 {
+            return;
+        }
+        self.bind_for_writing();
+        gl::BufferSubData(gl::COPY_WRITE_BUFFER, offset as isize, length as isize, data.as_ptr() as *const c_void);
+    }
+
+    // Detach the current storage and get a fresh block of the same size. Any draw still reading
+    // the old contents keeps doing so, instead of forcing `update_range` to wait for it to finish.
+    // The contents are undefined afterwards, so rewrite all of it.
+    pub unsafe fn orphan(&self) {
+        self.bind_for_writing();
+        gl::BufferData(gl::COPY_WRITE_BUFFER, self.size() as isize, ptr::null(), self.usage);
+    }
+
+    // Orphan the buffer and fill it with `data`, which may be a different size than before
+    pub unsafe fn replace<T>(&self, data: &[T]) {
+        self.bind_for_writing();
+        gl::BufferData(
+            gl::COPY_WRITE_BUFFER,
+            mem::size_of_val(data) as isize,
+            if data.is_empty() { ptr::null() } else { data.as_ptr() as *const c_void },
+            self.usage,
+        );
+        self.size.set(mem::size_of_val(data));
+    }
+
+    pub unsafe fn bind(&self) {
+        gl::BindBuffer(self.target, self.id);
+    }
+
+    // Binding an index buffer to ELEMENT_ARRAY_BUFFER would attach it to whatever VAO is bound,
+    // so writes go through COPY_WRITE_BUFFER, which isn't part of any VAO's state
+    unsafe fn bind_for_writing(&self) {
+        gl::BindBuffer(gl::COPY_WRITE_BUFFER, self.id);
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn target(&self) -> gl::types::GLenum {
+        self.target
+    }
+
+    pub fn size(&self) -> usize {
+        self.size.get()
+    }
+
+    pub fn usage(&self) -> gl::types::GLenum {
+        self.usage
+    }
+}
+
+impl Drop for Buffer {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteBuffers(1, &self.id) }
+    }
+}
+
+// A VAO along with the vertex and index buffers it reads from
+pub struct Vao {
+    id            : u32,
+    vertex_buffer : Buffer,
+    index_buffer  : Buffer,
+    vertex_count  : usize,
+    index_count   : i32,
+    layout        : VertexLayout,
+    _context      : ContextBound,
+}
+
+impl Vao {
+    // Takes ownership of an already set up VAO and its buffers
+    pub unsafe fn from_parts(id: u32, vertex_buffer: Buffer, index_buffer: Buffer, vertex_count: usize, index_count: i32, layout: VertexLayout) -> Self {
+        Vao {
+            id,
+            vertex_buffer,
+            index_buffer,
+            vertex_count,
+            index_count,
+            layout,
+            _context: PhantomData,
+        }
+    }
+
+    pub unsafe fn bind(&self) {
+        gl::BindVertexArray(self.id);
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn vertex_buffer(&self) -> &Buffer {
+        &self.vertex_buffer
+    }
+
+    pub fn index_buffer(&self) -> &Buffer {
+        &self.index_buffer
+    }
+
+    pub fn vertex_count(&self) -> usize {
+        self.vertex_count
+    }
+
+    pub fn index_count(&self) -> i32 {
+        self.index_count
+    }
+
+    pub fn layout(&self) -> &VertexLayout {
+        &self.layout
+    }
+
+    // Re-upload `count` vertices of `mesh`, starting at `first`, e.g. after deforming or recoloring
+    // them on the CPU. The mesh must have the same vertices and attributes it was built from.
+    // Use DYNAMIC_DRAW or STREAM_DRAW when building VAOs that are updated like this.
+    pub unsafe fn update_vertices(&self, mesh: &Mesh, first: usize, count: usize) {
+        let data = self.layout.pack_range(mesh, first, count);
+        self.vertex_buffer.update_range(first * self.layout.stride(), &data);
+    }
+
+    // Re-upload every vertex of `mesh`, orphaning the old storage first
+    pub unsafe fn replace_vertices(&self, mesh: &Mesh) {
+        if mesh.vertices.len() / 3 != self.vertex_count {
+            panic!("Mesh has {} vertices, but the VAO was built for {}", mesh.vertices.len() / 3, self.vertex_count);
+        }
+        let data = self.layout.pack(mesh);
+        self.vertex_buffer.orphan();
+        self.vertex_buffer.update_range(0, &data);
+    }
+}
+
+impl Drop for Vao {
+    fn drop(&mut self) {
+        // The buffers are deleted right after, when the fields are dropped
+        unsafe { gl::DeleteVertexArrays(1, &self.id) }
+    }
+}
+
+pub struct Program {
+    id       : u32,
+    _context : ContextBound,
+}
+
+impl Program {
+    pub unsafe fn new() -> Self {
+        Program {
+            id: gl::CreateProgram(),
+            _context: PhantomData,
+        }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+impl Drop for Program {
+    fn drop(&mut self) {
+        unsafe { gl::DeleteProgram(self.id) }
+    }
+}
+
+// How many seconds `RingBuffer::begin_section` waits for a section before giving up on its fence
+const RING_BUFFER_WAIT_TRIES: usize = 5;
+
+// A persistently mapped buffer split into `sections` equally sized parts, for streaming data
+// that changes every frame. While the GPU reads one section, the CPU writes the next, and a fence
+// per section makes sure we never overwrite a part the GPU hasn't finished with yet.
+// Three sections is usually enough to never wait at all.
+//
+// Draw from the current section by offsetting into the buffer with `section_offset`, e.g. by
+// passing `section_offset() / stride` as the base vertex to DrawElementsBaseVertex.
+pub struct RingBuffer {
+    id           : u32,
+    target       : gl::types::GLenum,
+    section_size : usize,
+    mapped       : *mut u8,
+    fences       : Vec<gl::types::GLsync>,
+    current      : usize,
+    _context     : ContextBound,
+}
+
+impl RingBuffer {
+    // Returns None if the driver doesn't support persistent mapping (OpenGL 4.4 or ARB_buffer_storage)
+    pub unsafe fn new(target: gl::types::GLenum, section_size: usize, sections: usize) -> Option<Self> {
+        if !gl::BufferStorage::is_loaded() || sections == 0 {
+            return None;
+        }
+        let flags = gl::MAP_WRITE_BIT | gl::MAP_PERSISTENT_BIT | gl::MAP_COHERENT_BIT;
+        let size = section_size * sections;
+
+        let mut id = 0;
+        gl::GenBuffers(1, &mut id);
+        gl::BindBuffer(gl::COPY_WRITE_BUFFER, id);
+        gl::BufferStorage(gl::COPY_WRITE_BUFFER, size as isize, ptr::null(), flags);
+        let mapped = gl::MapBufferRange(gl::COPY_WRITE_BUFFER, 0, size as isize, flags) as *mut u8;
+        if mapped.is_null() {
+            gl::DeleteBuffers(1, &id);
+            return None;
+        }
+
+        Some(RingBuffer {
+            id,
+            target,
+            section_size,
+            mapped,
+            fences: vec![ptr::null(); sections],
+            current: 0,
+            _context: PhantomData,
+        })
+    }
+
+    // Move on to the next section, wait until the GPU is done reading it, and hand it out for writing
+    pub unsafe fn begin_section(&mut self) -> &mut [u8] {
+        self.current = (self.current + 1) % self.fences.len();
+        let fence = self.fences[self.current];
+        if !fence.is_null() {
+            let one_second = 1_000_000_000;
+            let mut result = gl::TIMEOUT_EXPIRED;
+            for _ in 0..RING_BUFFER_WAIT_TRIES {
+                result = gl::ClientWaitSync(fence, gl::SYNC_FLUSH_COMMANDS_BIT, one_second);
+                if result != gl::TIMEOUT_EXPIRED {
+                    break;
+                }
+            }
+            if result != gl::ALREADY_SIGNALED && result != gl::CONDITION_SATISFIED {
+                // Something is badly stuck, or the fence couldn't be waited on at all. Rather than
+                // hang forever or write over data still in use, wait for everything the GPU has been
+                // asked to do, which includes reading this section.
+                if result == gl::WAIT_FAILED {
+                    eprintln!("Waiting for a ring buffer section failed, waiting for the GPU to finish");
+                } else {
+                    eprintln!("Ring buffer section still in use after {} seconds, waiting for the GPU to finish", RING_BUFFER_WAIT_TRIES);
+                }
+                gl::Finish();
+            }
+            gl::DeleteSync(fence);
+            self.fences[self.current] = ptr::null();
+        }
+        std::slice::from_raw_parts_mut(self.mapped.add(self.section_offset()), self.section_size)
+    }
+
+    // Call right after issuing the last draw that reads from the current section
+    pub unsafe fn end_section(&mut self) {
+        self.fences[self.current] = gl::FenceSync(gl::SYNC_GPU_COMMANDS_COMPLETE, 0);
+    }
+
+    pub unsafe fn bind(&self) {
+        gl::BindBuffer(self.target, self.id);
+    }
+
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    // Bytes from the start of the buffer to the current section
+    pub fn section_offset(&self) -> usize {
+        self.current * self.section_size
+    }
+
+    pub fn section_size(&self) -> usize {
+        self.section_size
+    }
+}
+
+impl Drop for RingBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            for &fence in &self.fences {
+                if !fence.is_null() {
+                    gl::DeleteSync(fence);
+                }
+            }
+            gl::BindBuffer(gl::COPY_WRITE_BUFFER, self.id);
+            gl::UnmapBuffer(gl::COPY_WRITE_BUFFER);
+            gl::DeleteBuffers(1, &self.id);
+        }
+    }
+}
+
+// Stands in for an uploaded mesh in tests, which run without a GL context. None of its ids refer
+// to anything, and it's never freed, as that would call into GL.
+#[cfg(test)]
+pub(crate) fn placeholder_vao(index_count: i32) -> std::rc::Rc<Vao> {
+    let buffer = |target| Buffer { id: 0, target, size: Cell::new(0), usage: gl::STATIC_DRAW, _context: PhantomData };
+    let vao = std::rc::Rc::new(Vao {
+        id            : 0,
+        vertex_buffer : buffer(gl::ARRAY_BUFFER),
+        index_buffer  : buffer(gl::ELEMENT_ARRAY_BUFFER),
+        vertex_count  : 0,
+        index_count,
+        layout        : VertexLayout::new(),
+        _context      : PhantomData,
+    });
+    mem::forget(vao.clone());
+    vao
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `check_send` only compiles for types that aren't Send, and `check_sync` for those that aren't
+    // Sync. Otherwise both impls apply, and the compiler can't tell which one is meant.
+    trait AmbiguousIfSend<A> {
+        fn check_send() {}
+    }
+    impl<T: ?Sized> AmbiguousIfSend<()> for T {}
+    impl<T: ?Sized + Send> AmbiguousIfSend<u8> for T {}
+
+    trait AmbiguousIfSync<A> {
+        fn check_sync() {}
+    }
+    impl<T: ?Sized> AmbiguousIfSync<()> for T {}
+    impl<T: ?Sized + Sync> AmbiguousIfSync<u8> for T {}
+
+    #[test]
+    fn gl_objects_stay_on_the_context_thread() {
+        <Buffer as AmbiguousIfSend<_>>::check_send();
+        <Vao as AmbiguousIfSend<_>>::check_send();
+        <Program as AmbiguousIfSend<_>>::check_send();
+        <RingBuffer as AmbiguousIfSend<_>>::check_send();
+        <Buffer as AmbiguousIfSync<_>>::check_sync();
+        <Vao as AmbiguousIfSync<_>>::check_sync();
+        <Program as AmbiguousIfSync<_>>::check_sync();
+        <RingBuffer as AmbiguousIfSync<_>>::check_sync();
+    }
+}
@@ -0,0 +1,432 @@
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::gl_objects::Vao;
+use crate::mesh::Mesh;
+use crate::animation::{Interpolation, Keyframes, Property};
+use crate::scene_graph::{Node, SceneNode};
+use crate::vertex_layout::VertexArrayBuilder;
+
+// Loads glTF 2.0 files, both .gltf with separate buffers and binary .glb, keeping what OBJ can't
+// describe: the node hierarchy, each node's transform and the animations moving them.
+//
+// This happens in two steps, like every other model:
+//  * `load` reads and parses the file into a `GltfScene`. It doesn't touch OpenGL, so it can run
+//    on an asset worker.
+//  * `GltfScene::instantiate` uploads the meshes and builds the `SceneNode` tree on the render thread.
+
+pub struct GltfScene {
+    pub meshes     : Vec<Mesh>,          // One per primitive, with the material's base color baked into the vertex colors
+    pub nodes      : Vec<GltfNode>,      // In the same order as in the file
+    pub roots      : Vec<usize>,         // The top level nodes of the default scene
+    pub animations : Vec<GltfAnimation>,
+}
+
+pub struct GltfNode {
+    pub name        : Option<String>,
+    pub translation : glm::Vec3,
+    pub rotation    : glm::Quat,
+    pub scale       : glm::Vec3,
+    pub meshes      : Vec<usize>,   // Into `GltfScene::meshes`
+    pub children    : Vec<usize>,   // Into `GltfScene::nodes`
+}
+
+// Keyframes for one property of one node
+pub struct Channel {
+    pub node      : usize,
+    pub keyframes : Keyframes,
+}
+
+pub struct GltfAnimation {
+    pub name     : Option<String>,
+    pub channels : Vec<Channel>,
+    pub duration : f32,
+}
+
+// The scene graph built by `GltfScene::instantiate`
+pub struct ImportedScene {
+    pub root       : Node,                  // Parent of all of the scene's top level nodes
+    pub nodes      : Vec<Node>,             // In the same order as `GltfScene::nodes`
+    pub primitives : Vec<Node>,             // Extra children for nodes with more than one primitive
+}
+
+pub fn load(path: &str) -> Result<GltfScene, String> {
+    let gltf::Gltf { document, blob } = gltf::Gltf::open(path)
+        .map_err(|e| format!("{}: {}", path, e))?;
+    // Textures aren't used yet, so only the buffers are read, and the images are never decoded
+    let buffers = gltf::import_buffers(&document, Path::new(path).parent(), blob)
+        .map_err(|e| format!("{}: {}", path, e))?;
+
+    let mut meshes = vec![];
+    let mut mesh_ranges = vec![];
+    for mesh in document.meshes() {
+        let mut range = vec![];
+        for primitive in mesh.primitives() {
+            match load_primitive(&primitive, &buffers) {
+                Ok(loaded) => {
+                    range.push(meshes.len());
+                    meshes.push(loaded);
+                }
+                Err(message) => eprintln!("{}: skipping a primitive of mesh {}, {}", path, mesh.index(), message),
+            }
+        }
+        mesh_ranges.push(range);
+    }
+
+    let nodes = document.nodes().map(|node| {
+        let (translation, rotation, scale) = node.transform().decomposed();
+        GltfNode {
+            name        : node.name().map(|name| name.to_string()),
+            translation : glm::make_vec3(&translation),
+            rotation    : glm::quat(rotation[0], rotation[1], rotation[2], rotation[3]),
+            scale       : glm::make_vec3(&scale),
+            meshes      : node.mesh().map_or(vec![], |mesh| mesh_ranges[mesh.index()].clone()),
+            children    : node.children().map(|child| child.index()).collect(),
+        }
+    }).collect();
+
+    let roots = document.default_scene()
+        .or_else(|| document.scenes().next())
+        .map_or(vec![], |scene| scene.nodes().map(|node| node.index()).collect());
+
+    let animations = document.animations()
+        .map(|animation| load_animation(&animation, &buffers))
+        .collect::<Result<_, _>>()
+        .map_err(|message| format!("{}: {}", path, message))?;
+
+    Ok(GltfScene { meshes, nodes, roots, animations })
+}
+
+fn load_primitive(primitive: &gltf::Primitive, buffers: &[gltf::buffer::Data]) -> Result<Mesh, String> {
+    if primitive.mode() != gltf::mesh::Mode::Triangles {
+        return Err(format!("{:?} are not supported, only triangles", primitive.mode()));
+    }
+    let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+
+    let vertices: Vec<f32> = reader.read_positions()
+        .ok_or("it has no positions")?
+        .flatten()
+        .collect();
+    let vertex_count = vertices.len() / 3;
+    let indices: Vec<u32> = match reader.read_indices() {
+        Some(indices) => indices.into_u32().collect(),
+        None => (0..vertex_count as u32).collect(),
+    };
+    let normals = match reader.read_normals() {
+        Some(normals) => normals.flatten().collect(),
+        None => smooth_normals(&vertices, &indices),
+    };
+    let uvs = reader.read_tex_coords(0)
+        .map_or(vec![], |uvs| uvs.into_f32().flatten().collect());
+
+    let base_color = primitive.material().pbr_metallic_roughness().base_color_factor();
+    let colors = match reader.read_colors(0) {
+        Some(colors) => colors.into_rgba_f32()
+            .flat_map(|color| (0..4).map(move |i| color[i] * base_color[i]))
+            .collect(),
+        None => base_color.iter().cloned().cycle().take(vertex_count * 4).collect(),
+    };
+
+    let index_count = indices.len() as i32;
+    Ok(Mesh {
+        vertices,
+        normals,
+        colors,
+        uvs,
+        tangents: vec![],
+        indices,
+        index_count,
+    })
+}
+
+// Area weighted vertex normals, for primitives exported without any
+fn smooth_normals(vertices: &[f32], indices: &[u32]) -> Vec<f32> {
+    let position = |i: u32| glm::make_vec3(&vertices[i as usize * 3..i as usize * 3 + 3]);
+    let mut normals = vec![glm::Vec3::zeros(); vertices.len() / 3];
+    for corners in indices.chunks_exact(3) {
+        let a = position(corners[0]);
+        let face = glm::cross(&(position(corners[1]) - a), &(position(corners[2]) - a));
+        for &corner in corners {
+            normals[corner as usize] += face;
+        }
+    }
+    normals.iter()
+        .flat_map(|n| {
+            let n = if glm::length(n) > 0.0 { glm::normalize(n) } else { glm::vec3(0.0, 1.0, 0.0) };
+            [n.x, n.y, n.z]
+        })
+        .collect()
+}
+
+fn load_animation(animation: &gltf::Animation, buffers: &[gltf::buffer::Data]) -> Result<GltfAnimation, String> {
+    use gltf::animation::util::ReadOutputs;
+
+    let mut channels = vec![];
+    for channel in animation.channels() {
+        let reader = channel.reader(|buffer| Some(&buffers[buffer.index()]));
+        let times: Vec<f32> = reader.read_inputs()
+            .ok_or("an animation channel has no keyframe times")?
+            .collect();
+        let (property, values): (Property, Vec<glm::Vec4>) = match reader.read_outputs() {
+            Some(ReadOutputs::Translations(values)) => (Property::Translation, values.map(|v| glm::vec4(v[0], v[1], v[2], 0.0)).collect()),
+            Some(ReadOutputs::Rotations(values))    => (Property::Rotation, values.into_f32().map(|v| glm::make_vec4(&v)).collect()),
+            Some(ReadOutputs::Scales(values))       => (Property::Scale, values.map(|v| glm::vec4(v[0], v[1], v[2], 0.0)).collect()),
+            Some(ReadOutputs::MorphTargetWeights(_)) => continue, // Morph targets aren't supported
+            None => return Err("an animation channel has no keyframe values".to_string()),
+        };
+        // glTF wants linear rotations spherical, and its cubic spline tangents are per second like ours
+        let interpolation = match channel.sampler().interpolation() {
+            gltf::animation::Interpolation::Step => Interpolation::Step,
+            gltf::animation::Interpolation::Linear if property == Property::Rotation => Interpolation::Slerp,
+            gltf::animation::Interpolation::Linear => Interpolation::Linear,
+            gltf::animation::Interpolation::CubicSpline => Interpolation::CubicHermite,
+        };
+
+        let values_per_key = if interpolation == Interpolation::CubicHermite { 3 } else { 1 };
+        if times.is_empty() || values.len() != times.len() * values_per_key {
+            return Err(format!("an animation channel has {} keyframe times, but {} values", times.len(), values.len()));
+        }
+        channels.push(Channel {
+            node      : channel.target().node().index(),
+            keyframes : Keyframes { property, interpolation, times, values },
+        });
+    }
+
+    let duration = channels.iter()
+        .map(|channel| channel.keyframes.duration())
+        .fold(0.0, f32::max);
+    Ok(GltfAnimation {
+        name: animation.name().map(|name| name.to_string()),
+        channels,
+        duration,
+    })
+}
+
+impl GltfScene {
+    // Upload every mesh, and build the node hierarchy with the transforms from the file
+    pub unsafe fn instantiate(&self) -> ImportedScene {
+        let vaos: Vec<Rc<Vao>> = self.meshes.iter()
+            .map(|mesh| Rc::new(VertexArrayBuilder::new(mesh).build()))
+            .collect();
+        self.assemble(&vaos)
+    }
+
+    // Build the node hierarchy around already uploaded meshes, one VAO per entry of `meshes`
+    unsafe fn assemble(&self, vaos: &[Rc<Vao>]) -> ImportedScene {
+        let meshes: Vec<Rc<Mesh>> = self.meshes.iter().cloned().map(Rc::new).collect();
+
+        let mut nodes = Vec::with_capacity(self.nodes.len());
+        let mut primitives = vec![];
+        for gltf_node in &self.nodes {
+            let mut node = SceneNode::new();
+            node.name = gltf_node.name.clone();
+            node.position = gltf_node.translation;
+            node.rotation = gltf_node.rotation;
+            node.scale = gltf_node.scale;
+
+            // A node only draws one VAO, so any further primitives hang off it as children
+            for (i, &mesh) in gltf_node.meshes.iter().enumerate() {
+                if i == 0 {
+                    node.set_vao(Rc::clone(&vaos[mesh]));
+                    node.set_mesh(Rc::clone(&meshes[mesh]));
+                } else {
+                    let mut primitive = SceneNode::from_vao(Rc::clone(&vaos[mesh]));
+                    primitive.set_mesh(Rc::clone(&meshes[mesh]));
+                    node.add_child(&primitive);
+                    primitives.push(primitive);
+                }
+            }
+            nodes.push(node);
+        }
+
+        for (parent, gltf_node) in self.nodes.iter().enumerate() {
+            for &child in &gltf_node.children {
+                let child: *const SceneNode = &**nodes[child];
+                nodes[parent].add_child(&*child);
+            }
+        }
+
+        let mut root = SceneNode::new();
+        for &node in &self.roots {
+            root.add_child(&nodes[node]);
+        }
+
+        ImportedScene {
+            root,
+            nodes,
+            primitives,
+        }
+    }
+}
+
+impl ImportedScene {
+    // The first node with the given name in the file
+    pub fn node_named(&mut self, name: &str) -> Option<&mut SceneNode> {
+        self.nodes.iter_mut()
+            .find(|node| node.name.as_deref() == Some(name))
+            .map(|node| &mut ***node)
+    }
+}
+
+impl GltfAnimation {
+    // Pose the nodes of `scene` as they are `time` seconds into the animation, looping it
+    pub fn apply(&self, time: f32, scene: &mut ImportedScene) {
+        let time = if self.duration > 0.0 { time.rem_euclid(self.duration) } else { 0.0 };
+        for channel in &self.channels {
+            channel.keyframes.apply(time, &mut scene.nodes[channel.node]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gl_objects::placeholder_vao;
+
+    // A triangle without normals, spinning and scaled on a "body" node with an "arm" child that
+    // slides up over a second. The mesh has a point primitive too, which should be skipped.
+    const SCENE: &str = r#"{
+        "asset"       : { "version": "2.0" },
+        "scene"       : 0,
+        "scenes"      : [{ "nodes": [0] }],
+        "nodes"       : [
+            { "name": "body", "mesh": 0, "children": [1], "translation": [1, 0, 0],
+              "rotation": [0, 0.70710677, 0, 0.70710677], "scale": [2, 2, 2] },
+            { "name": "arm", "translation": [0, 1, 0] }
+        ],
+        "meshes"      : [{ "primitives": [
+            { "attributes": { "POSITION": 0 }, "material": 0 },
+            { "attributes": { "POSITION": 0 }, "mode": 0 }
+        ] }],
+        "materials"   : [{ "pbrMetallicRoughness": { "baseColorFactor": [1, 0.5, 0.25, 1] } }],
+        "animations"  : [{ "name": "raise",
+            "samplers": [{ "input": 1, "output": 2 }],
+            "channels": [{ "sampler": 0, "target": { "node": 1, "path": "translation" } }] }],
+        "buffers"     : [{ "uri": "BUFFER", "byteLength": 68 }],
+        "bufferViews" : [
+            { "buffer": 0, "byteOffset": 0,  "byteLength": 36 },
+            { "buffer": 0, "byteOffset": 36, "byteLength": 8 },
+            { "buffer": 0, "byteOffset": 44, "byteLength": 24 }
+        ],
+        "accessors"   : [
+            { "bufferView": 0, "componentType": 5126, "count": 3, "type": "VEC3", "min": [0, 0, 0], "max": [1, 1, 0] },
+            { "bufferView": 1, "componentType": 5126, "count": 2, "type": "SCALAR", "min": [0], "max": [1] },
+            { "bufferView": 2, "componentType": 5126, "count": 2, "type": "VEC3" }
+        ]
+    }"#;
+
+    // Write the scene and its buffer to a directory of their own, and load it back
+    fn load_scene(name: &str) -> GltfScene {
+        let floats: [f32; 17] = [
+            0.0, 0.0, 0.0,  1.0, 0.0, 0.0,  0.0, 1.0, 0.0,  // Positions
+            0.0, 1.0,                                       // Keyframe times
+            0.0, 1.0, 0.0,  0.0, 3.0, 0.0,                  // Translations
+        ];
+        let bytes: Vec<u8> = floats.iter().flat_map(|f| f.to_le_bytes()).collect();
+
+        let directory = std::env::temp_dir().join(format!("gloom-rs-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join(format!("{}.bin", name)), bytes).unwrap();
+        let path = directory.join(format!("{}.gltf", name));
+        std::fs::write(&path, SCENE.replace("BUFFER", &format!("{}.bin", name))).unwrap();
+
+        let scene = load(path.to_str().unwrap());
+        std::fs::remove_dir_all(&directory).unwrap();
+        scene.unwrap()
+    }
+
+    fn assemble(scene: &GltfScene) -> ImportedScene {
+        let vaos: Vec<Rc<Vao>> = scene.meshes.iter()
+            .map(|mesh| placeholder_vao(mesh.index_count))
+            .collect();
+        unsafe { scene.assemble(&vaos) }
+    }
+
+    fn close(a: &[f32], b: &[f32]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-5)
+    }
+
+    #[test]
+    fn loads_the_hierarchy_and_transforms() {
+        let scene = load_scene("hierarchy");
+        assert_eq!(scene.roots, vec![0]);
+        assert_eq!(scene.nodes.len(), 2);
+
+        let body = &scene.nodes[0];
+        assert_eq!(body.name.as_deref(), Some("body"));
+        assert_eq!(body.children, vec![1]);
+        assert_eq!(body.meshes, vec![0]);
+        assert!(close(body.translation.as_slice(), &[1.0, 0.0, 0.0]));
+        assert!(close(body.rotation.coords.as_slice(), &[0.0, 0.70710677, 0.0, 0.70710677]));
+        assert!(close(body.scale.as_slice(), &[2.0, 2.0, 2.0]));
+
+        let arm = &scene.nodes[1];
+        assert_eq!(arm.name.as_deref(), Some("arm"));
+        assert!(arm.meshes.is_empty() && arm.children.is_empty());
+        assert!(close(arm.translation.as_slice(), &[0.0, 1.0, 0.0]));
+    }
+
+    #[test]
+    fn loads_triangles_with_the_base_color_and_skips_points() {
+        let scene = load_scene("primitives");
+        assert_eq!(scene.meshes.len(), 1);
+
+        let mesh = &scene.meshes[0];
+        assert_eq!(mesh.indices, vec![0, 1, 2]);
+        assert_eq!(mesh.index_count, 3);
+        assert!(close(&mesh.vertices, &[0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0, 0.0]));
+        assert!(close(&mesh.colors, &[1.0, 0.5, 0.25, 1.0].repeat(3)));
+        // There were no normals in the file, so they face the way the triangle winds
+        assert!(close(&mesh.normals, &[0.0, 0.0, 1.0].repeat(3)));
+    }
+
+    #[test]
+    fn smooth_normals_average_the_faces_around_a_vertex() {
+        // Two triangles folded along the X axis, one facing +Y and the other +Z
+        let vertices = [
+            0.0, 0.0, 0.0,  1.0, 0.0, 0.0,  0.0, 0.0, -1.0,  0.0, 1.0, 0.0,
+            5.0, 5.0, 5.0,  // Not in any triangle
+        ];
+        let normals = smooth_normals(&vertices, &[0, 1, 2, 0, 1, 3]);
+        let diagonal = std::f32::consts::FRAC_1_SQRT_2;
+        assert!(close(&normals[0..3], &[0.0, diagonal, diagonal]));
+        assert!(close(&normals[3..6], &[0.0, diagonal, diagonal]));
+        assert!(close(&normals[6..9], &[0.0, 1.0, 0.0]));
+        assert!(close(&normals[9..12], &[0.0, 0.0, 1.0]));
+        assert!(close(&normals[12..15], &[0.0, 1.0, 0.0]));
+    }
+
+    #[test]
+    fn instantiates_the_node_tree() {
+        let scene = load_scene("instantiate");
+        let mut imported = assemble(&scene);
+        assert!(imported.primitives.is_empty());
+
+        imported.root.update_world_matrices(&glm::identity(), false);
+        let arm = imported.root.find("body/arm").expect("the arm should hang off the body");
+        // Up one, scaled by two, and the quarter turn about Y leaves it pointing up
+        assert!(close(arm.world_position().as_slice(), &[1.0, 2.0, 0.0]));
+
+        let body = imported.node_named("body").unwrap();
+        assert!(body.is_drawable());
+        assert!(close(body.scale.as_slice(), &[2.0, 2.0, 2.0]));
+        assert!(close(body.mesh.as_ref().unwrap().colors.as_slice(), &[1.0, 0.5, 0.25, 1.0].repeat(3)));
+    }
+
+    #[test]
+    fn animation_poses_the_nodes_and_loops() {
+        let scene = load_scene("animation");
+        let mut imported = assemble(&scene);
+        let animation = &scene.animations[0];
+        assert_eq!(animation.name.as_deref(), Some("raise"));
+        assert_eq!(animation.channels.len(), 1);
+        assert_eq!(animation.duration, 1.0);
+
+        animation.apply(0.5, &mut imported);
+        assert!(close(imported.nodes[1].position.as_slice(), &[0.0, 2.0, 0.0]));
+        animation.apply(1.25, &mut imported);
+        assert!(close(imported.nodes[1].position.as_slice(), &[0.0, 1.5, 0.0]));
+        // The body isn't animated, so it keeps its transform from the file
+        assert!(close(imported.nodes[0].position.as_slice(), &[1.0, 0.0, 0.0]));
+    }
+}
@@ -8,6 +8,7 @@
 #![allow(unused_assignments)]
 
 extern crate nalgebra_glm as glm;
+use std::rc::Rc;
 use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
 use std::{mem, os::raw::c_void, ptr};
@@ -17,12 +18,26 @@ mod util;
 mod mesh;
 mod scene_graph;
 mod toolbox;
+mod asset_loader;
+mod vertex_layout;
+mod gl_objects;
+mod bounds;
+mod picking;
+mod bvh;
+mod collision;
+mod controls;
+mod flight;
+mod gltf_import;
+mod rotation;
+mod transform;
+mod animation;
+mod fleet;
 
 
 use glutin::event::{
     DeviceEvent,
     ElementState::{Pressed, Released},
-    Event, KeyboardInput,
+    Event, KeyboardInput, MouseButton,
     VirtualKeyCode::{self, *},
     WindowEvent,
 };
@@ -32,13 +47,29 @@ use glutin::event_loop::ControlFlow;
 const INITIAL_SCREEN_W: u32 = 800;
 const INITIAL_SCREEN_H: u32 = 600;
 
+// How high the helicopter flies, before being pushed up over any hills in the way
+const HELICOPTER_ALTITUDE: f32 = 5.0;
+
+// How fast the helicopter flies along a route loaded from a file, in units per second
+const ROUTE_SPEED: f32 = 10.0;
+
+// How long the helicopter takes to ease back onto its scripted path when the player lets go, in seconds
+const HANDOVER_TIME: f32 = 2.0;
+
+// How many more helicopters follow the first one along its path, and how many seconds apart
+const FLEET_SIZE: usize = 12;
+const FLEET_SPACING: f32 = 1.5;
+
+// The least height the rest of the fleet keeps above the terrain, where their path would take them lower
+const FLEET_CLEARANCE: f32 = 2.0;
+
 
 // == // Helper functions to make interacting with OpenGL a little bit prettier. You *WILL* need these! // == //
 
 // Get the size of an arbitrary array of numbers measured in bytes
 // Example usage:  pointer_to_array(my_array)
 fn byte_size_of_array<T>(val: &[T]) -> isize {
-    std::mem::size_of_val(&val[..]) as isize
+    std::mem::size_of_val(val) as isize
 }
 
 // Get the OpenGL-compatible pointer to an arbitrary array of numbers
@@ -63,163 +94,65 @@ fn offset<T>(n: u32) -> *const c_void {
 // ptr::null()
 
 // == // Generate your VAO here
-unsafe fn create_vao(vertices: &Vec<f32>, indices: &Vec<u32>, colors: &Vec<f32>, normals: &Vec<f32> ) -> u32 {
-
-    let mut vao_id = 0;
-    gl::GenVertexArrays(1, &mut vao_id);
-    gl::BindVertexArray(vao_id);
-
-    let mut buffer_id = 0;
-    gl::GenBuffers(1, &mut buffer_id);
-    gl::BindBuffer(gl::ARRAY_BUFFER, buffer_id);
-
-    gl::BufferData(
-        gl::ARRAY_BUFFER,
-        byte_size_of_array(vertices),
-        pointer_to_array(vertices),
-        gl::STATIC_DRAW,
-    );
-
-    let mut index_buffer_id = 0;
-    gl::GenBuffers(1, &mut index_buffer_id);
-    gl::BindBuffer(gl::ELEMENT_ARRAY_BUFFER, index_buffer_id);
-
-    gl::BufferData(
-        gl::ELEMENT_ARRAY_BUFFER,
-        byte_size_of_array(indices),
-        pointer_to_array(indices),
-        gl::STATIC_DRAW,
-    );
-
-    let vertices_index = 0;
-    gl::EnableVertexAttribArray(vertices_index);
-    gl::VertexAttribPointer(
-        vertices_index,
-        3, //[x,y,z],
-        gl::FLOAT,
-        gl::FALSE,
-        0, // same type for all values
-        ptr::null()
-    );
-
-    let mut buffer_color_id = 0;
-    gl::GenBuffers(1, &mut buffer_color_id);
-    gl::BindBuffer(gl::ARRAY_BUFFER, buffer_color_id);
-    gl::BufferData(
-        gl::ARRAY_BUFFER,
-        byte_size_of_array(colors),
-        pointer_to_array(colors),
-        gl::STATIC_DRAW
-    );
-
-    let color_index = 1;
-    gl::EnableVertexAttribArray(color_index);
-    gl::VertexAttribPointer(
-        color_index,
-        4,
-        gl::FLOAT, 
-        gl::FALSE, 
-        0, 
-        ptr::null()
-    );
-
-    /* Vertex Buffer Object for normals */
-    let mut buffer_normal_id = 0;
-    gl::GenBuffers(1, &mut buffer_normal_id);
-    gl::BindBuffer(gl::ARRAY_BUFFER, buffer_normal_id);
-    gl::BufferData(
-        gl::ARRAY_BUFFER,
-        byte_size_of_array(normals),
-        pointer_to_array(normals),
-        gl::STATIC_DRAW,
-    );
-    let normals_index = 2;
-    gl::EnableVertexAttribArray(normals_index);
-    // The VertexAttribPointer give the Vertex shader info about the data
-    gl::VertexAttribPointer(
-        normals_index,
-        3, // 3 floats -> XYZ
-        gl::FLOAT,
-        gl::FALSE,   // Whether OpenGL should normalize the values in the buffer
-        0, // All floats, so OpenGL fixes this. Specify a value != 0 if there are multiple types (e.g. float, integers) in one entry
-        ptr::null(), // Array buffer offset
-    );
-
-
-    return vao_id;
+unsafe fn create_vao_from_mesh(mesh: &mesh::Mesh) -> gl_objects::Vao {
+    vertex_layout::VertexArrayBuilder::new(mesh).build()
 }
 
-unsafe fn create_vao_from_mesh(mesh: &mesh::Mesh) -> u32 {
-    return create_vao(&mesh.vertices, &mesh.indices, &mesh.colors, &mesh.normals);
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct DrawStats {
+    drawn  : usize,
+    culled : usize,
 }
 
-unsafe fn draw_scene(root: &scene_graph::SceneNode, view_projection_matrix: &glm::Mat4) {
-    // Check if node is drawable, set uniforms, draw
-    if root.index_count > 0 {
-        gl::UniformMatrix4fv(5, 1, 0, (view_projection_matrix * root.current_transformation_matrix).as_ptr());
-        gl::UniformMatrix4fv(6, 1, 0, (root.current_transformation_matrix).as_ptr());
-        gl::BindVertexArray(root.vao_id);
-        gl::DrawElements(gl::TRIANGLES, root.index_count, gl::UNSIGNED_INT, ptr::null());
-    }
+// Draws every node that may be in view, skipping whole subtrees whose bounds are outside it
+struct DrawPass<'a> {
+    view_projection_matrix : &'a glm::Mat4,
+    frustum                : bounds::Frustum,
+    stats                  : DrawStats,
+}
+
+impl scene_graph::traversal::Visitor for DrawPass<'_> {
+    fn enter(&mut self, node: &scene_graph::SceneNode, _depth: usize) -> bool {
+        if let Some(bounds) = node.world_bounds() {
+            if !self.frustum.intersects_aabb(&bounds) {
+                self.stats.culled += node.depth_first().filter(|visit| visit.node.is_drawable()).count();
+                return false;
+            }
+        }
 
-    // Recurse
-    for &child in &root.children {
-        draw_scene(&*child, view_projection_matrix);
+        // Check if node is drawable, set uniforms, draw
+        if let Some(vao) = &node.vao {
+            if node.index_count > 0 {
+                unsafe {
+                    gl::UniformMatrix4fv(5, 1, 0, (self.view_projection_matrix * node.current_transformation_matrix).as_ptr());
+                    // Normals need the inverse transpose, or non-uniform scaling would skew the lighting
+                    let normal_matrix = glm::transpose(&glm::inverse(&glm::mat4_to_mat3(&node.current_transformation_matrix)));
+                    gl::UniformMatrix3fv(7, 1, 0, normal_matrix.as_ptr());
+                    vao.bind();
+                    gl::DrawElements(gl::TRIANGLES, node.index_count, gl::UNSIGNED_INT, ptr::null());
+                }
+                self.stats.drawn += 1;
+            }
+        }
+        true
     }
 }
 
+unsafe fn draw_scene(root: &scene_graph::SceneNode, view_projection_matrix: &glm::Mat4) -> DrawStats {
+    let mut pass = DrawPass {
+        view_projection_matrix,
+        frustum: bounds::Frustum::from_matrix(view_projection_matrix),
+        stats: DrawStats::default(),
+    };
+    root.accept(&mut pass);
+    pass.stats
+}
+
 
 unsafe fn update_node_transformations(root: &mut scene_graph::SceneNode, transformation_so_far: &glm::Mat4) {
-    // Construct the correct transformation matrix
-    let origin = glm::mat4(
-        1.0, 0.0, 0.0, root.reference_point[0],
-        0.0, 1.0, 0.0, root.reference_point[1],
-        0.0, 0.0, 1.0, root.reference_point[2],
-        0.0, 0.0, 0.0, 1.0,
-    );
-
-    let rotate_x = glm::mat4(
-        1.0, 0.0, 0.0, 0.0,
-        0.0, root.rotation[0].cos(), -root.rotation[0].sin(), 0.0,
-        0.0, root.rotation[0].sin(), root.rotation[0].cos(), 0.0,
-        0.0, 0.0, 0.0, 1.0,
-    );
-
-    let rotate_y = glm::mat4(
-        root.rotation[1].cos(), 0.0, root.rotation[1].sin(), 0.0,
-        0.0, 1.0, 0.0, 0.0,
-        -root.rotation[1].sin(), 0.0, root.rotation[1].cos(), 0.0,
-        0.0, 0.0, 0.0, 1.0,
-    );
-
-    let rotate_z = glm::mat4(
-        root.rotation[2].cos(), -root.rotation[2].sin(), 0.0, 0.0,
-        root.rotation[2].sin(), root.rotation[2].cos(), 0.0, 0.0,
-        0.0, 0.0, 1.0, 0.0,
-        0.0, 0.0, 0.0, 1.0,
-    );
-
-    let inverse_origin = glm::mat4(
-        1.0, 0.0, 0.0, -root.reference_point[0],
-        0.0, 1.0, 0.0, -root.reference_point[1],
-        0.0, 0.0, 1.0, -root.reference_point[2],
-        0.0, 0.0, 0.0, 1.0,
-    );
-
-    let translation = glm::mat4(
-        1.0, 0.0, 0.0, root.position[0],
-        0.0, 1.0, 0.0, root.position[1],
-        0.0, 0.0, 1.0, root.position[2],
-        0.0, 0.0, 0.0, 1.0,
-    );
-    // Update the node's transformation matrix
-    root.current_transformation_matrix = transformation_so_far * translation * origin * rotate_x * rotate_y * rotate_z * inverse_origin;
-
-    // Recurse
-    for &child in &root.children {
-        update_node_transformations(&mut *child,
-        &root.current_transformation_matrix);
-    }
+    // Only the subtrees that moved since last frame are recomputed, or everything if
+    // `transformation_so_far` isn't what it was last frame
+    root.update_world_matrices(transformation_so_far, false);
 }
 
 fn main() {
@@ -248,11 +181,23 @@ fn main() {
     // Make a reference of this tuple to send to the render thread
     let mouse_delta = Arc::clone(&arc_mouse_delta);
 
-    // Set up shared tuple for tracking changes to the window size
-    let arc_window_size = Arc::new(Mutex::new((INITIAL_SCREEN_W, INITIAL_SCREEN_H, false)));
+    // Set up shared tuple for tracking the cursor position, and whether it was clicked since last frame
+    let arc_cursor = Arc::new(Mutex::new((0f32, 0f32, false)));
+    // Make a reference of this tuple to send to the render thread
+    let cursor = Arc::clone(&arc_cursor);
+
+    // Set up shared tuple for tracking changes to the window size, in physical pixels like the cursor
+    // position, which on a HiDPI display is larger than the logical size asked for above
+    let initial_size = windowed_context.window().inner_size();
+    let arc_window_size = Arc::new(Mutex::new((initial_size.width, initial_size.height, false)));
     // Make a reference of this tuple to send to the render thread
     let window_size = Arc::clone(&arc_window_size);
 
+    // Set up a shared flag for whether the player is flying the helicopter, so Q doesn't quit mid-flight
+    let arc_player_active = Arc::new(Mutex::new(false));
+    // Make a reference of this flag to send to the render thread
+    let player_active = Arc::clone(&arc_player_active);
+
     // Spawn a separate thread for rendering, so event handling doesn't block rendering
     let render_thread = thread::spawn(move || {
         // Acquire the OpenGL Context and load the function pointers.
@@ -459,14 +404,78 @@ fn main() {
         ];
          */
 
-        let terrain = mesh::Terrain::load("./resources/lunarsurface.obj");
-        let terrain_vao = unsafe { create_vao_from_mesh(&terrain) };
+        // Models are parsed in the background. Their nodes draw nothing until the mesh is ready
+        // and has been uploaded further down, in the render loop.
+        let mut asset_loader = asset_loader::AssetLoader::new(2);
+        let terrain_asset = asset_loader.request(
+            asset_loader::AssetRequest::Terrain("./resources/lunarsurface.obj".to_string())
+        );
+
+        let helicopter_asset = asset_loader.request(
+            asset_loader::AssetRequest::Helicopter("./resources/helicopter.obj".to_string())
+        );
 
-        let mut root_node = scene_graph::SceneNode::new();
-        let mut terrain_node = scene_graph::SceneNode::from_vao(terrain_vao, terrain.index_count);
+        let mut root_node = scene_graph::SceneNode::named("root");
+        let mut terrain_node = scene_graph::SceneNode::named("terrain");
 
         root_node.add_child(&terrain_node);
 
+        // The helicopter's parts hang off a node of their own, which is the one that's moved around
+        let mut helicopter_node = scene_graph::SceneNode::named("helicopter");
+        let mut body_node = scene_graph::SceneNode::named("body");
+        let mut main_rotor_node = scene_graph::SceneNode::named("main_rotor");
+        let mut tail_rotor_node = scene_graph::SceneNode::named("tail_rotor");
+        let mut door_node = scene_graph::SceneNode::named("door");
+
+        root_node.add_child(&helicopter_node);
+        helicopter_node.add_child(&body_node);
+        helicopter_node.add_child(&main_rotor_node);
+        helicopter_node.add_child(&tail_rotor_node);
+        helicopter_node.add_child(&door_node);
+
+        // The scripted path is the route in resources/route.txt if there is one, see `toolbox::FlightPath::parse`
+        let route = match toolbox::FlightPath::load("./resources/route.txt") {
+            Ok(route) => route.map(Rc::new),
+            Err(message) => {
+                eprintln!("Flying the built-in path, as {}", message);
+                None
+            }
+        };
+
+        // The rotors spin on their own while the helicopter is on its scripted path, with the main
+        // rotor wobbling a little on top
+        let rotor_clip = Rc::new(animation::AnimationClip::new("rotors", vec![
+            animation::Track::new("main_rotor", animation::Keyframes::spin(&glm::vec3(0.0, 1.0, 0.0), 5.0, 1.0)),
+            animation::Track::new("tail_rotor", animation::Keyframes::spin(&glm::vec3(1.0, 0.0, 0.0), 20.0, 1.0)),
+        ]));
+        let wobble = |angle: f32, axis: glm::Vec3| glm::quat_angle_axis(angle, &axis);
+        let wobble_clip = Rc::new(animation::AnimationClip::new("rotor_wobble", vec![
+            animation::Track::new("main_rotor", animation::Keyframes::rotation(animation::Interpolation::CubicHermite, &[
+                (0.0, glm::quat_identity()),
+                (0.3, wobble(0.02, glm::vec3(1.0, 0.0, 0.0))),
+                (0.7, wobble(0.015, glm::vec3(0.0, 0.0, 1.0))),
+                (1.0, glm::quat_identity()),
+            ]).unwrap()),
+        ]));
+        let mut rotor_mixer = animation::blend::AnimationMixer::new();
+        let spinning = rotor_mixer.add_layer(animation::blend::LayerMode::Override, 1.0);
+        let wobbling = rotor_mixer.add_layer(animation::blend::LayerMode::Additive, 1.0);
+        rotor_mixer.layers[spinning].play(animation::Animator::new(rotor_clip.clone(), &mut helicopter_node, animation::PlaybackMode::Loop), 1.0);
+        rotor_mixer.layers[wobbling].play(animation::Animator::new(wobble_clip, &mut helicopter_node, animation::PlaybackMode::Loop), 1.0);
+
+        // The rest of the fleet, spawned once the helicopter has loaded, trailing the first one along its path
+        let mut fleet = fleet::Fleet::new("escort", rotor_clip, FLEET_CLEARANCE);
+
+        // When the player lets go, the helicopter eases back onto its scripted path from wherever they left it
+        let mut player_was_active = false;
+        // What the last frame drew and culled, shown in the window title
+        let mut last_draw_stats = DrawStats::default();
+        let mut handing_back: Option<(transform::Transform, f32)> = None;
+
+        // Built once the body has loaded, as it's fitted to its bounds
+        let mut helicopter_collider: Option<collision::Collider> = None;
+        let mut helicopter_last_position: Option<glm::Vec3> = None;
+
         let translate_z_index: glm::Mat4 = glm::mat4(
             1.0, 0.0, 0.0, 0.0, //
             0.0, 1.0, 0.0, 0.0, //
@@ -477,7 +486,7 @@ fn main() {
 
         let perspective: glm::Mat4 = glm::perspective(
             (INITIAL_SCREEN_W as f32) / (INITIAL_SCREEN_H as f32), // Aspect ratio = width/height
-            (60.0 * 3.14) / 180.0,                 // 60 degress FOV, but the function uses radians
+            (60.0 * std::f32::consts::PI) / 180.0, // 60 degress FOV, but the function uses radians
             1.0,                                   //
             1000.0,                                 //
         );
@@ -493,8 +502,8 @@ fn main() {
                 .attach_file("./shaders/simple.vert")
                 .attach_file("./shaders/simple.frag")
                 .link()
-                .activate();
         };
+        unsafe { simple_shader.activate() };
 
         // Used to demonstrate keyboard handling for exercise 2.
         //let mut _arbitrary_number = 0.0; // feel free to remove
@@ -509,6 +518,17 @@ fn main() {
             0.0, 0.0, -2.0, 0.0, 0.0
         ];
 
+        // Press P to fly the helicopter yourself, and again to hand it back to the scripted path.
+        // The keys can be changed in resources/controls.txt, see `controls::KeyBindings::parse`.
+        let bindings = match controls::KeyBindings::load("./resources/controls.txt") {
+            Ok(bindings) => bindings.unwrap_or_default(),
+            Err(message) => {
+                eprintln!("Using the default controls, as {}", message);
+                controls::KeyBindings::default()
+            }
+        };
+        let mut player = controls::Player::new(bindings);
+
         // The main rendering loop
         let first_frame_time = std::time::Instant::now();
         let mut prevous_frame_time = first_frame_time;
@@ -523,7 +543,14 @@ fn main() {
             let x_speed = 2.0;
             let y_speed = 2.0;
 
-            if let Ok(keys) = pressed_keys.lock() {
+            let keys: Vec<VirtualKeyCode> = pressed_keys.lock().map_or(vec![], |keys| keys.clone());
+            player.update(&keys, delta_time, &helicopter_node);
+            if let Ok(mut active) = player_active.lock() {
+                *active = player.active;
+            }
+
+            // The camera keys only move the camera while it isn't following the helicopter
+            if !player.active {
                 for key in keys.iter() {
                     match key {
                         VirtualKeyCode::A => {
@@ -580,6 +607,34 @@ fn main() {
                 *delta = (0.0, 0.0); // reset when done
             }
 
+            // Upload at most one finished model per frame, so that several assets finishing at
+            // once don't all stall the same frame
+            for loaded in asset_loader.poll(1) {
+                match loaded.result {
+                    Ok(asset_loader::Asset::Terrain(terrain)) if loaded.id == terrain_asset => {
+                        let terrain_vao = unsafe { create_vao_from_mesh(&terrain.mesh) };
+                        terrain_node.set_vao(Rc::new(terrain_vao));
+                        terrain_node.set_mesh(Rc::new(terrain.mesh));
+                        terrain_node.set_bvh(Rc::new(terrain.bvh));
+                    }
+                    Ok(asset_loader::Asset::Helicopter(helicopter)) if loaded.id == helicopter_asset => {
+                        helicopter_collider = Some(collision::Collider::helicopter(&helicopter.body.bounding_box()));
+                        let model = unsafe { fleet::Model::helicopter(*helicopter) };
+                        model.dress(&mut helicopter_node);
+                        let path: fleet::HeadingPath = match &route {
+                            Some(route) => {
+                                let route = route.clone();
+                                Rc::new(move |time| (route.heading_at_time(time, ROUTE_SPEED), route.position_at(time * ROUTE_SPEED).y))
+                            }
+                            None => Rc::new(|time| (toolbox::simple_heading_animation(time), HELICOPTER_ALTITUDE)),
+                        };
+                        fleet.spawn_many(&model, &mut root_node, FLEET_SIZE, |i| (path.clone(), -FLEET_SPACING * (i + 1) as f32));
+                    }
+                    Ok(_) => {}
+                    Err(message) => eprintln!("Failed to load asset {}: {}", loaded.id, message),
+                }
+            }
+
             /* 
             let mut shader_matrix: glm::Mat4 = perspective * translate_z_index; // First we apply the perspective on the z-index translation matrix
 
@@ -603,6 +658,66 @@ fn main() {
 
 
 
+            let last_helicopter_transform = helicopter_node.transform();
+            player.apply(&mut helicopter_node, &mut main_rotor_node, &mut tail_rotor_node, &mut door_node);
+            // Kept running while the player flies, so the rotors carry on from where they'd be by
+            // now when the scripted path takes over again, instead of from where they were left
+            rotor_mixer.advance(delta_time);
+            if !player.active {
+                let (heading, height) = match &route {
+                    Some(route) => (route.heading_at_time(elapsed, ROUTE_SPEED), route.position_at(elapsed * ROUTE_SPEED).y),
+                    None => (toolbox::simple_heading_animation(elapsed), HELICOPTER_ALTITUDE),
+                };
+                helicopter_node.position = glm::vec3(heading.x, height, heading.z);
+                helicopter_node.rotation = heading.rotation();
+                if player_was_active {
+                    handing_back = Some((last_helicopter_transform, 0.0));
+                }
+                if let Some((from, elapsed)) = handing_back.as_mut() {
+                    *elapsed += delta_time;
+                    let t = (*elapsed / HANDOVER_TIME).min(1.0);
+                    let eased = from.interpolate(&helicopter_node.transform(), t * t * (3.0 - 2.0 * t));
+                    helicopter_node.set_transform(&eased);
+                    if t >= 1.0 {
+                        handing_back = None;
+                    }
+                }
+                rotor_mixer.apply();
+            }
+            player_was_active = player.active;
+            // The fleet hangs right off the root, like the terrain, so the terrain's world matrix will do
+            let terrain_world = terrain_node.world_matrix();
+            fleet.update(delta_time, terrain_node.bvh.as_deref().map(|terrain| (terrain, &terrain_world)));
+            unsafe { update_node_transformations(&mut root_node, &glm::identity()) };
+
+            // Keep the helicopter out of the hills, and tell what happened when it touched them
+            if let (Some(collider), Some(terrain)) = (helicopter_collider.as_mut(), terrain_node.bvh.as_ref()) {
+                // On the scripted path, how fast the animation moves it, as the corrections only
+                // ever push it up out of the ground
+                let position = helicopter_node.world_position();
+                let velocity = match helicopter_last_position {
+                    _ if player.active => player.velocity(),
+                    Some(last) if delta_time > 0.0 => (position - last) / delta_time,
+                    _ => glm::vec3(0.0, 0.0, 0.0),
+                };
+                let resolution = collider.resolve(&helicopter_node.world_matrix(), &velocity, terrain, &terrain_node.world_matrix());
+                for event in &resolution.events {
+                    println!(
+                        "Helicopter {:?} at [{:.2}, {:.2}, {:.2}], {:.2} units/s into the ground",
+                        event.kind, event.point.x, event.point.y, event.point.z, event.speed,
+                    );
+                }
+                if player.active {
+                    player.collide(&resolution);
+                    player.apply(&mut helicopter_node, &mut main_rotor_node, &mut tail_rotor_node, &mut door_node);
+                } else {
+                    // The helicopter hangs right off the root, so its position is in world space
+                    helicopter_node.position += resolution.correction;
+                }
+                helicopter_last_position = Some(position);
+                unsafe { update_node_transformations(&mut root_node, &glm::identity()) };
+            }
+
             // == // Please compute camera transforms here (exercise 2 & 3)
 
             let matrix = glm::mat4(
@@ -636,6 +751,30 @@ fn main() {
             //view_projection_matrix = glm::rotate_z(&view_projection_matrix, roll);
             view_projection_matrix = glm::translate(&view_projection_matrix, &glm::vec3(x, y, z));
 
+            // While the player flies, the camera rides along behind the helicopter instead
+            if player.active {
+                view_projection_matrix = perspective * player.camera.view_matrix(&helicopter_node.world_matrix(), delta_time);
+            }
+
+            // Report what was clicked on, if anything
+            if let Ok(mut cursor) = cursor.lock() {
+                if cursor.2 {
+                    cursor.2 = false;
+                    let (width, height) = window_size.lock().map_or((INITIAL_SCREEN_W, INITIAL_SCREEN_H), |size| (size.0, size.1));
+                    let ray = picking::Ray::from_cursor(cursor.0, cursor.1, width as f32, height as f32, &view_projection_matrix);
+                    match picking::pick(&root_node, &ray) {
+                        Some(hit) => println!(
+                            "Clicked {} at [{:.2}, {:.2}, {:.2}], triangle {}, barycentric [{:.2}, {:.2}, {:.2}]",
+                            hit.node.name.as_deref().unwrap_or("an unnamed node"),
+                            hit.point.x, hit.point.y, hit.point.z,
+                            hit.triangle,
+                            hit.barycentric.x, hit.barycentric.y, hit.barycentric.z,
+                        ),
+                        None => println!("Clicked on nothing"),
+                    }
+                }
+            }
+
             unsafe {
                 // Clear the color and depth buffers
                 gl::ClearColor(0.035, 0.046, 0.078, 1.0); // night sky, full opacity
@@ -643,7 +782,11 @@ fn main() {
 
                 // == // Issue the necessary gl:: commands to draw your scene here
 
-                draw_scene(&root_node, &view_projection_matrix)
+                let draw_stats = draw_scene(&root_node, &view_projection_matrix);
+                if draw_stats != last_draw_stats {
+                    context.window().set_title(&format!("Gloom-rs - {} drawn, {} culled", draw_stats.drawn, draw_stats.culled));
+                    last_draw_stats = draw_stats;
+                }
 
                 //gl::BindVertexArray(vao_id);
 
@@ -670,7 +813,7 @@ fn main() {
     let render_thread_healthy = Arc::new(RwLock::new(true));
     let render_thread_watchdog = Arc::clone(&render_thread_healthy);
     thread::spawn(move || {
-        if !render_thread.join().is_ok() {
+        if render_thread.join().is_err() {
             if let Ok(mut health) = render_thread_watchdog.write() {
                 println!("Render thread panicked!");
                 *health = false;
@@ -684,7 +827,7 @@ fn main() {
 
         // Terminate program if render thread panics
         if let Ok(health) = render_thread_healthy.read() {
-            if *health == false {
+            if !*health {
                 *control_flow = ControlFlow::Exit;
             }
         }
@@ -738,17 +881,34 @@ fn main() {
                     }
                 }
 
-                // Handle Escape and Q keys separately
+                // Handle Escape and Q keys separately. Q is left alone while the player is flying.
                 match keycode {
                     Escape => {
                         *control_flow = ControlFlow::Exit;
                     }
-                    Q => {
+                    Q if !arc_player_active.lock().is_ok_and(|active| *active) => {
                         *control_flow = ControlFlow::Exit;
                     }
                     _ => {}
                 }
             }
+            // Keep track of the cursor, for picking
+            Event::WindowEvent {
+                event: WindowEvent::CursorMoved { position, .. },
+                ..
+            } => {
+                if let Ok(mut cursor) = arc_cursor.lock() {
+                    *cursor = (position.x as f32, position.y as f32, cursor.2);
+                }
+            }
+            Event::WindowEvent {
+                event: WindowEvent::MouseInput { state: Pressed, button: MouseButton::Left, .. },
+                ..
+            } => {
+                if let Ok(mut cursor) = arc_cursor.lock() {
+                    cursor.2 = true;
+                }
+            }
             Event::DeviceEvent {
                 event: DeviceEvent::MouseMotion { delta },
                 ..
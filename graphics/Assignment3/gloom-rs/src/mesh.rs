@@ -1,4 +1,11 @@
-use tobj;
+pub mod validate;
+pub mod simplify;
+pub mod export;
+mod tangents;
+
+pub use simplify::simplify;
+
+use crate::bounds::{Aabb, Sphere};
 
 // internal helper
 fn generate_color_vec(color: [f32; 4], num: usize) -> Vec<f32> {
@@ -7,10 +14,13 @@ fn generate_color_vec(color: [f32; 4], num: usize) -> Vec<f32> {
 
 // Mesh
 
+#[derive(Clone)]
 pub struct Mesh {
     pub vertices    : Vec<f32>,
     pub normals     : Vec<f32>,
     pub colors      : Vec<f32>,
+    pub uvs         : Vec<f32>, // Empty if the model has no texture coordinates
+    pub tangents    : Vec<f32>, // Empty unless generated
     pub indices     : Vec<u32>,
     pub index_count : i32,
 }
@@ -22,11 +32,89 @@ impl Mesh {
         Mesh {
             vertices: mesh.positions,
             normals: mesh.normals,
+            uvs: mesh.texcoords,
+            tangents: vec![],
             indices: mesh.indices,
             colors: generate_color_vec(color, num_verts),
             index_count,
         }
     }
+
+    pub fn bounding_box(&self) -> Aabb {
+        Aabb::from_positions(&self.vertices)
+    }
+
+    pub fn bounding_sphere(&self) -> Sphere {
+        Sphere::from_positions(&self.vertices)
+    }
+
+    // A copy with positions, normals and tangents moved by `matrix`, e.g. a node's current
+    // transformation, so it can be written out the way it is posed in the scene
+    pub fn transformed(&self, matrix: &glm::Mat4) -> Self {
+        let normal_matrix = glm::transpose(&glm::inverse(&glm::mat4_to_mat3(matrix)));
+        let linear = glm::mat4_to_mat3(matrix);
+        let mut mesh = self.clone();
+
+        for p in mesh.vertices.chunks_exact_mut(3) {
+            let moved = matrix * glm::vec4(p[0], p[1], p[2], 1.0);
+            p.copy_from_slice(&[moved.x / moved.w, moved.y / moved.w, moved.z / moved.w]);
+        }
+        for n in mesh.normals.chunks_exact_mut(3) {
+            let moved = normal_matrix * glm::vec3(n[0], n[1], n[2]);
+            let moved = if glm::length(&moved) > 0.0 { glm::normalize(&moved) } else { moved };
+            n.copy_from_slice(&[moved.x, moved.y, moved.z]);
+        }
+        for t in mesh.tangents.chunks_exact_mut(4) {
+            let moved = linear * glm::vec3(t[0], t[1], t[2]);
+            let moved = if glm::length(&moved) > 0.0 { glm::normalize(&moved) } else { moved };
+            t[..3].copy_from_slice(&[moved.x, moved.y, moved.z]);
+        }
+
+        // A mirroring transformation turns the triangles inside out, so flip them back. It also
+        // mirrors the bitangent, which the tangent's sign has to follow.
+        if glm::determinant(&linear) < 0.0 {
+            for corners in mesh.indices.chunks_exact_mut(3) {
+                corners.swap(1, 2);
+            }
+            for t in mesh.tangents.chunks_exact_mut(4) {
+                t[3] = -t[3];
+            }
+        }
+        mesh
+    }
+
+    // All of `meshes` in one. Normals, UVs and tangents are only kept if every mesh has them, so
+    // that they always line up with the vertices. Colors are required, so missing ones are white.
+    pub fn merge(meshes: &[Mesh]) -> Self {
+        let has_normals = meshes.iter().all(|m| m.normals.len() == m.vertices.len());
+        let has_uvs = meshes.iter().all(|m| m.uvs.len() / 2 == m.vertices.len() / 3);
+        let has_tangents = meshes.iter().all(|m| m.tangents.len() / 4 == m.vertices.len() / 3);
+
+        let mut merged = Mesh {
+            vertices: vec![],
+            normals: vec![],
+            colors: vec![],
+            uvs: vec![],
+            tangents: vec![],
+            indices: vec![],
+            index_count: 0,
+        };
+        for mesh in meshes {
+            let base = (merged.vertices.len() / 3) as u32;
+            merged.vertices.extend_from_slice(&mesh.vertices);
+            if has_normals { merged.normals.extend_from_slice(&mesh.normals); }
+            if mesh.colors.len() / 4 == mesh.vertices.len() / 3 {
+                merged.colors.extend_from_slice(&mesh.colors);
+            } else {
+                merged.colors.extend(generate_color_vec([1.0, 1.0, 1.0, 1.0], mesh.vertices.len() / 3));
+            }
+            if has_uvs { merged.uvs.extend_from_slice(&mesh.uvs); }
+            if has_tangents { merged.tangents.extend_from_slice(&mesh.tangents); }
+            merged.indices.extend(mesh.indices.iter().map(|i| i + base));
+        }
+        merged.index_count = merged.indices.len() as i32;
+        merged
+    }
 }
 
 // Lunar terrain
@@ -47,7 +135,7 @@ impl Terrain {
         let after = std::time::Instant::now();
         println!("Done in {:.3}ms.", after.duration_since(before).as_micros() as f32 / 1e3);
 
-        if models.len() > 1 || models.len() == 0 {
+        if models.len() != 1 {
             panic!("Please use a model with a single mesh!")
             // You could try merging the vertices and indices
             // of the separate meshes into a single mesh.
@@ -79,7 +167,7 @@ pub struct Helicopter {
 use std::ops::Index;
 impl Index<usize> for Helicopter {
     type Output = Mesh;
-    fn index<'a>(&'a self, i: usize) -> &'a Mesh {
+    fn index(&self, i: usize) -> &Mesh {
         match i {
             0 => &self.body,
             1 => &self.main_rotor,
@@ -122,3 +210,116 @@ impl Helicopter {
         }
     }
 }
+
+// Small meshes for the tests of this module and the ones below it
+#[cfg(test)]
+pub(crate) mod fixtures {
+    use super::{generate_color_vec, Mesh};
+
+    // A unit cube with four vertices per face, so that each face has flat normals and its own UVs
+    pub fn cube() -> Mesh {
+        let faces: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+            // (normal, one tangent direction, the other), counter-clockwise seen from outside
+            ([ 1.0,  0.0,  0.0], [ 0.0,  0.0, -1.0], [0.0, 1.0,  0.0]),
+            ([-1.0,  0.0,  0.0], [ 0.0,  0.0,  1.0], [0.0, 1.0,  0.0]),
+            ([ 0.0,  1.0,  0.0], [ 1.0,  0.0,  0.0], [0.0, 0.0, -1.0]),
+            ([ 0.0, -1.0,  0.0], [ 1.0,  0.0,  0.0], [0.0, 0.0,  1.0]),
+            ([ 0.0,  0.0,  1.0], [ 1.0,  0.0,  0.0], [0.0, 1.0,  0.0]),
+            ([ 0.0,  0.0, -1.0], [-1.0,  0.0,  0.0], [0.0, 1.0,  0.0]),
+        ];
+        let mut mesh = empty();
+        for (face, (n, u, v)) in faces.iter().enumerate() {
+            for (s, t) in [(0.0, 0.0), (1.0, 0.0), (1.0, 1.0), (0.0, 1.0)] {
+                for k in 0..3 {
+                    mesh.vertices.push(0.5 * n[k] + (s - 0.5) * u[k] + (t - 0.5) * v[k]);
+                }
+                mesh.normals.extend_from_slice(n);
+                mesh.uvs.extend_from_slice(&[s, t]);
+            }
+            let base = face as u32 * 4;
+            mesh.indices.extend_from_slice(&[base, base + 1, base + 2, base, base + 2, base + 3]);
+        }
+        finish(mesh)
+    }
+
+    // A flat, square `size` by `size` grid of quads in the XZ plane, facing up
+    pub fn grid(size: usize) -> Mesh {
+        let mut mesh = empty();
+        for z in 0..=size {
+            for x in 0..=size {
+                mesh.vertices.extend_from_slice(&[x as f32, 0.0, z as f32]);
+                mesh.normals.extend_from_slice(&[0.0, 1.0, 0.0]);
+                mesh.uvs.extend_from_slice(&[x as f32 / size as f32, z as f32 / size as f32]);
+            }
+        }
+        let at = |x: usize, z: usize| (z * (size + 1) + x) as u32;
+        for z in 0..size {
+            for x in 0..size {
+                mesh.indices.extend_from_slice(&[at(x, z), at(x, z + 1), at(x + 1, z + 1)]);
+                mesh.indices.extend_from_slice(&[at(x, z), at(x + 1, z + 1), at(x + 1, z)]);
+            }
+        }
+        finish(mesh)
+    }
+
+    fn empty() -> Mesh {
+        Mesh { vertices: vec![], normals: vec![], colors: vec![], uvs: vec![], tangents: vec![], indices: vec![], index_count: 0 }
+    }
+
+    fn finish(mut mesh: Mesh) -> Mesh {
+        mesh.colors = generate_color_vec([0.5, 0.6, 0.7, 1.0], mesh.vertices.len() / 3);
+        mesh.index_count = mesh.indices.len() as i32;
+        mesh
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vec3_at(values: &[f32], i: usize) -> glm::Vec3 {
+        glm::make_vec3(&values[i * 3..i * 3 + 3])
+    }
+
+    // The bitangent the shaders rebuild from the normal and the tangent with its sign
+    fn bitangent(mesh: &Mesh, i: usize) -> glm::Vec3 {
+        let t = &mesh.tangents[i * 4..i * 4 + 4];
+        glm::cross(&vec3_at(&mesh.normals, i), &glm::vec3(t[0], t[1], t[2])) * t[3]
+    }
+
+    #[test]
+    fn mirroring_keeps_triangles_facing_out_and_tangent_frames_intact() {
+        let mut cube = fixtures::cube();
+        cube.generate_tangents().unwrap();
+        let mirror = glm::scaling(&glm::vec3(-1.0, 1.0, 1.0));
+        let mirrored = cube.transformed(&mirror);
+
+        for corners in mirrored.indices.chunks_exact(3) {
+            let [a, b, c] = [0, 1, 2].map(|k| vec3_at(&mirrored.vertices, corners[k] as usize));
+            let face = glm::cross(&(b - a), &(c - a));
+            assert!(glm::dot(&face, &vec3_at(&mirrored.normals, corners[0] as usize)) > 0.0);
+        }
+        for i in 0..mirrored.vertices.len() / 3 {
+            let expected = glm::mat4_to_mat3(&mirror) * bitangent(&cube, i);
+            assert!(glm::distance(&bitangent(&mirrored, i), &expected) < 1e-5, "vertex {}", i);
+        }
+
+        // The same tangents as generating them for the mirrored cube from scratch
+        let mut regenerated = mirrored.clone();
+        regenerated.generate_tangents().unwrap();
+        for (a, b) in mirrored.tangents.iter().zip(&regenerated.tangents) {
+            assert!((a - b).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn turning_leaves_the_tangent_sign_alone() {
+        let mut cube = fixtures::cube();
+        cube.generate_tangents().unwrap();
+        let turned = cube.transformed(&glm::rotation(1.0, &glm::vec3(0.0, 1.0, 0.0)));
+        assert_eq!(turned.indices, cube.indices);
+        for (a, b) in turned.tangents.chunks_exact(4).zip(cube.tangents.chunks_exact(4)) {
+            assert_eq!(a[3], b[3]);
+        }
+    }
+}
@@ -0,0 +1,335 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use super::Mesh;
+
+// Writers for handing meshes back to other tools: Wavefront OBJ (with an MTL file for the
+// colors), PLY and binary STL. They write to anything implementing `Write`, so a `Vec<u8>` works
+// just as well as a file when comparing geometry.
+//
+// To export a posed part of the scene graph, flatten it first with `SceneNode::flattened_mesh`,
+// or `SceneNode::world_meshes` to keep the parts as separate OBJ objects.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlyFormat {
+    Ascii,
+    BinaryLittleEndian,
+}
+
+// Write each of `meshes` as a separate OBJ object with the given name. If `mtl` is given as
+// (file name, writer), one material per object is written there, with its color as the diffuse
+// color. Vertex colors are also written inline after the positions, which Blender and MeshLab read.
+pub fn write_obj<W: Write>(meshes: &[(&str, &Mesh)], obj: &mut W, mut mtl: Option<(&str, &mut dyn Write)>) -> io::Result<()> {
+    writeln!(obj, "# Exported by gloom-rs")?;
+    if let Some((mtl_name, _)) = &mtl {
+        writeln!(obj, "mtllib {}", mtl_name)?;
+    }
+
+    // OBJ indices are global across objects, and start at 1
+    let (mut position_base, mut normal_base, mut uv_base) = (1, 1, 1);
+    for (name, mesh) in meshes {
+        let vertex_count = mesh.vertices.len() / 3;
+        let has_colors = mesh.colors.len() == vertex_count * 4;
+        let has_normals = mesh.normals.len() == vertex_count * 3;
+        let has_uvs = mesh.uvs.len() == vertex_count * 2;
+
+        writeln!(obj, "o {}", name)?;
+        for vertex in 0..vertex_count {
+            let p = &mesh.vertices[vertex * 3..vertex * 3 + 3];
+            if has_colors {
+                let c = &mesh.colors[vertex * 4..vertex * 4 + 3];
+                writeln!(obj, "v {} {} {} {} {} {}", p[0], p[1], p[2], c[0], c[1], c[2])?;
+            } else {
+                writeln!(obj, "v {} {} {}", p[0], p[1], p[2])?;
+            }
+        }
+        if has_normals {
+            for n in mesh.normals.chunks_exact(3) {
+                writeln!(obj, "vn {} {} {}", n[0], n[1], n[2])?;
+            }
+        }
+        if has_uvs {
+            for uv in mesh.uvs.chunks_exact(2) {
+                writeln!(obj, "vt {} {}", uv[0], uv[1])?;
+            }
+        }
+
+        if let Some((_, mtl_writer)) = &mut mtl {
+            let color = if has_colors && vertex_count > 0 { &mesh.colors[0..4] } else { &[1.0, 1.0, 1.0, 1.0][..] };
+            writeln!(mtl_writer, "newmtl {}_material", name)?;
+            writeln!(mtl_writer, "Kd {} {} {}", color[0], color[1], color[2])?;
+            writeln!(mtl_writer, "d {}", color[3])?;
+            writeln!(mtl_writer)?;
+            writeln!(obj, "usemtl {}_material", name)?;
+        }
+
+        for corners in mesh.indices.chunks_exact(3) {
+            write!(obj, "f")?;
+            for &index in corners {
+                let index = index as usize;
+                match (has_uvs, has_normals) {
+                    (true, true)   => write!(obj, " {}/{}/{}", position_base + index, uv_base + index, normal_base + index)?,
+                    (true, false)  => write!(obj, " {}/{}", position_base + index, uv_base + index)?,
+                    (false, true)  => write!(obj, " {}//{}", position_base + index, normal_base + index)?,
+                    (false, false) => write!(obj, " {}", position_base + index)?,
+                }
+            }
+            writeln!(obj)?;
+        }
+
+        position_base += vertex_count;
+        if has_normals { normal_base += vertex_count; }
+        if has_uvs { uv_base += vertex_count; }
+    }
+    Ok(())
+}
+
+pub fn write_ply<W: Write>(mesh: &Mesh, out: &mut W, format: PlyFormat) -> io::Result<()> {
+    let vertex_count = mesh.vertices.len() / 3;
+    let has_colors = mesh.colors.len() == vertex_count * 4;
+    let has_normals = mesh.normals.len() == vertex_count * 3;
+    let has_uvs = mesh.uvs.len() == vertex_count * 2;
+
+    writeln!(out, "ply")?;
+    match format {
+        PlyFormat::Ascii              => writeln!(out, "format ascii 1.0")?,
+        PlyFormat::BinaryLittleEndian => writeln!(out, "format binary_little_endian 1.0")?,
+    }
+    writeln!(out, "comment Exported by gloom-rs")?;
+    writeln!(out, "element vertex {}", vertex_count)?;
+    writeln!(out, "property float x\nproperty float y\nproperty float z")?;
+    if has_normals {
+        writeln!(out, "property float nx\nproperty float ny\nproperty float nz")?;
+    }
+    if has_colors {
+        writeln!(out, "property uchar red\nproperty uchar green\nproperty uchar blue\nproperty uchar alpha")?;
+    }
+    if has_uvs {
+        writeln!(out, "property float s\nproperty float t")?;
+    }
+    writeln!(out, "element face {}", mesh.indices.len() / 3)?;
+    writeln!(out, "property list uchar uint vertex_indices")?;
+    writeln!(out, "end_header")?;
+
+    let to_byte = |c: f32| (c.clamp(0.0, 1.0) * 255.0).round() as u8;
+    for vertex in 0..vertex_count {
+        let mut floats: Vec<f32> = mesh.vertices[vertex * 3..vertex * 3 + 3].to_vec();
+        if has_normals {
+            floats.extend_from_slice(&mesh.normals[vertex * 3..vertex * 3 + 3]);
+        }
+        let color: Vec<u8> = if has_colors {
+            mesh.colors[vertex * 4..vertex * 4 + 4].iter().map(|&c| to_byte(c)).collect()
+        } else {
+            vec![]
+        };
+        let uv: &[f32] = if has_uvs { &mesh.uvs[vertex * 2..vertex * 2 + 2] } else { &[] };
+
+        match format {
+            PlyFormat::Ascii => {
+                let fields: Vec<String> = floats.iter().map(|f| f.to_string())
+                    .chain(color.iter().map(|c| c.to_string()))
+                    .chain(uv.iter().map(|f| f.to_string()))
+                    .collect();
+                writeln!(out, "{}", fields.join(" "))?;
+            }
+            PlyFormat::BinaryLittleEndian => {
+                for f in &floats {
+                    out.write_all(&f.to_le_bytes())?;
+                }
+                out.write_all(&color)?;
+                for f in uv {
+                    out.write_all(&f.to_le_bytes())?;
+                }
+            }
+        }
+    }
+
+    for corners in mesh.indices.chunks_exact(3) {
+        match format {
+            PlyFormat::Ascii => writeln!(out, "3 {} {} {}", corners[0], corners[1], corners[2])?,
+            PlyFormat::BinaryLittleEndian => {
+                out.write_all(&[3u8])?;
+                for index in corners {
+                    out.write_all(&index.to_le_bytes())?;
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Binary STL only has triangles with a face normal each, so colors, UVs and vertex normals are lost
+pub fn write_stl<W: Write>(mesh: &Mesh, out: &mut W) -> io::Result<()> {
+    let mut header = [0u8; 80];
+    let title = b"Exported by gloom-rs";
+    header[..title.len()].copy_from_slice(title);
+    out.write_all(&header)?;
+    out.write_all(&((mesh.indices.len() / 3) as u32).to_le_bytes())?;
+
+    for corners in mesh.indices.chunks_exact(3) {
+        let a = mesh.position(corners[0] as usize);
+        let b = mesh.position(corners[1] as usize);
+        let c = mesh.position(corners[2] as usize);
+        let normal = glm::cross(&(b - a), &(c - a));
+        let normal = if glm::length(&normal) > 0.0 { glm::normalize(&normal) } else { normal };
+        for v in [normal, a, b, c] {
+            for component in v.iter() {
+                out.write_all(&component.to_le_bytes())?;
+            }
+        }
+        out.write_all(&0u16.to_le_bytes())?; // Attribute byte count, unused
+    }
+    Ok(())
+}
+
+// Write `mesh` to `path`, picking the format from the extension: .obj (with a .mtl next to it),
+// .ply (binary) or .stl
+pub fn save(mesh: &Mesh, path: &Path) -> io::Result<()> {
+    let extension = path.extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase());
+    let mut out = BufWriter::new(File::create(path)?);
+    match extension.as_deref() {
+        Some("obj") => {
+            let mtl_path = path.with_extension("mtl");
+            let mtl_name = mtl_path.file_name().and_then(|n| n.to_str()).unwrap_or("material.mtl").to_string();
+            let object_name = path.file_stem().and_then(|n| n.to_str()).unwrap_or("mesh").to_string();
+            let mut mtl = BufWriter::new(File::create(&mtl_path)?);
+            write_obj(&[(&object_name, mesh)], &mut out, Some((&mtl_name, &mut mtl)))?;
+            mtl.flush()?;
+        }
+        Some("ply") => write_ply(mesh, &mut out, PlyFormat::BinaryLittleEndian)?,
+        Some("stl") => write_stl(mesh, &mut out)?,
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Unknown mesh format: {}", path.display()))),
+    }
+    out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::fixtures;
+
+    // Each triangle's corners, in order
+    fn triangles(positions: &[f32], indices: &[u32]) -> Vec<[f32; 9]> {
+        indices.chunks_exact(3).map(|corners| {
+            let mut triangle = [0.0; 9];
+            for (k, &index) in corners.iter().enumerate() {
+                triangle[k * 3..k * 3 + 3].copy_from_slice(&positions[index as usize * 3..index as usize * 3 + 3]);
+            }
+            triangle
+        }).collect()
+    }
+
+    #[test]
+    fn obj_reads_back_the_same_triangles() {
+        let cube = fixtures::cube();
+        let mut obj = vec![];
+        let mut mtl = vec![];
+        write_obj(&[("cube", &cube)], &mut obj, Some(("cube.mtl", &mut mtl))).unwrap();
+
+        let options = tobj::LoadOptions { triangulate: true, single_index: true, ..Default::default() };
+        let (models, _) = tobj::load_obj_buf(&mut &obj[..], &options, |_| Ok((vec![], Default::default()))).unwrap();
+        assert_eq!(models.len(), 1);
+        assert_eq!(models[0].name, "cube");
+        let read = &models[0].mesh;
+        assert_eq!(triangles(&read.positions, &read.indices), triangles(&cube.vertices, &cube.indices));
+        assert_eq!(read.normals.len(), read.positions.len());
+        assert_eq!(read.texcoords.len() / 2, read.positions.len() / 3);
+        assert!(String::from_utf8(mtl).unwrap().contains("newmtl cube_material\nKd 0.5 0.6 0.7"));
+    }
+
+    #[test]
+    fn obj_leaves_out_what_the_mesh_does_not_have() {
+        let mut cube = fixtures::cube();
+        cube.normals.clear();
+        cube.uvs.clear();
+        let mut obj = vec![];
+        write_obj(&[("a", &cube), ("b", &cube)], &mut obj, None).unwrap();
+        let obj = String::from_utf8(obj).unwrap();
+        assert!(!obj.contains("vn ") && !obj.contains("vt ") && !obj.contains("mtllib"));
+        // The second object's faces carry on after the first one's 24 vertices
+        let last_face = obj.lines().rev().find(|line| line.starts_with("f ")).unwrap();
+        assert_eq!(last_face, "f 45 47 48");
+    }
+
+    #[test]
+    fn ascii_ply_reads_back_the_same_vertices_and_faces() {
+        let cube = fixtures::cube();
+        let mut ply = vec![];
+        write_ply(&cube, &mut ply, PlyFormat::Ascii).unwrap();
+        let ply = String::from_utf8(ply).unwrap();
+        let (header, body) = ply.split_once("end_header\n").unwrap();
+        assert!(header.contains("element vertex 24\n") && header.contains("element face 12\n"));
+
+        let lines: Vec<Vec<f32>> = body.lines().map(|line| line.split(' ').map(|v| v.parse().unwrap()).collect()).collect();
+        let (vertices, faces) = lines.split_at(24);
+        for (vertex, fields) in vertices.iter().enumerate() {
+            // x y z, nx ny nz, r g b a as bytes, s t
+            assert_eq!(fields.len(), 12);
+            assert_eq!(&fields[0..3], &cube.vertices[vertex * 3..vertex * 3 + 3]);
+            assert_eq!(&fields[3..6], &cube.normals[vertex * 3..vertex * 3 + 3]);
+            assert_eq!(&fields[6..10], &[128.0, 153.0, 179.0, 255.0]);
+            assert_eq!(&fields[10..12], &cube.uvs[vertex * 2..vertex * 2 + 2]);
+        }
+        let indices: Vec<u32> = faces.iter().flat_map(|face| {
+            assert_eq!(face[0], 3.0);
+            face[1..].iter().map(|&i| i as u32).collect::<Vec<_>>()
+        }).collect();
+        assert_eq!(indices, cube.indices);
+    }
+
+    #[test]
+    fn binary_ply_has_the_size_its_header_says() {
+        let cube = fixtures::cube();
+        let mut ply = vec![];
+        write_ply(&cube, &mut ply, PlyFormat::BinaryLittleEndian).unwrap();
+        let body = ply.len() - (ply.windows(11).position(|w| w == b"end_header\n").unwrap() + 11);
+        // Position, normal and UV floats and four color bytes per vertex, a count and three indices per face
+        assert_eq!(body, 24 * (8 * 4 + 4) + 12 * (1 + 3 * 4));
+    }
+
+    #[test]
+    fn stl_reads_back_the_same_triangles() {
+        let cube = fixtures::cube();
+        let mut stl = vec![];
+        write_stl(&cube, &mut stl).unwrap();
+        assert_eq!(stl.len(), 84 + 12 * 50);
+        assert_eq!(u32::from_le_bytes([stl[80], stl[81], stl[82], stl[83]]), 12);
+
+        let float = |at: usize| f32::from_le_bytes([stl[at], stl[at + 1], stl[at + 2], stl[at + 3]]);
+        let expected = triangles(&cube.vertices, &cube.indices);
+        for (triangle, expected) in expected.iter().enumerate() {
+            let record = 84 + triangle * 50;
+            let read: Vec<f32> = (0..9).map(|k| float(record + 12 + k * 4)).collect();
+            assert_eq!(&read[..], &expected[..]);
+            // The face normal is the one the cube's vertex normals have
+            let first = cube.indices[triangle * 3] as usize;
+            for (k, expected) in cube.normals[first * 3..first * 3 + 3].iter().enumerate() {
+                assert!((float(record + k * 4) - expected).abs() < 1e-6);
+            }
+        }
+    }
+
+    #[test]
+    fn merging_keeps_attributes_in_step_with_the_vertices() {
+        let cube = fixtures::cube();
+        let mut bare = fixtures::cube();
+        bare.normals.clear();
+        bare.colors.clear();
+
+        let merged = Mesh::merge(&[cube.clone(), bare]);
+        assert_eq!(merged.vertices.len(), 2 * cube.vertices.len());
+        assert!(merged.normals.is_empty());
+        assert_eq!(&merged.colors[..cube.colors.len()], &cube.colors[..]);
+        assert!(merged.colors[cube.colors.len()..].iter().all(|&c| c == 1.0));
+        assert_eq!(merged.uvs.len() / 2, merged.vertices.len() / 3);
+        let mut obj = vec![];
+        write_obj(&[("merged", &merged)], &mut obj, None).unwrap();
+        assert!(!String::from_utf8(obj).unwrap().contains("vn "));
+
+        let merged = Mesh::merge(&[cube.clone(), cube.clone()]);
+        assert_eq!(merged.normals.len(), merged.vertices.len());
+        assert_eq!(merged.colors.len() / 4, merged.vertices.len() / 3);
+        assert_eq!(merged.indices[36], 24);
+    }
+}
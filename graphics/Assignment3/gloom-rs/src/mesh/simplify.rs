@@ -0,0 +1,429 @@
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, HashSet};
+
+use super::Mesh;
+
+// Mesh simplification by edge collapse, ordered by the quadric error metric of Garland and Heckbert.
+//
+// Every collapse moves one vertex onto a neighbour and removes the triangles between them. Vertices
+// are only ever moved onto existing vertices, never to new positions, so colors, normals and UVs
+// carry over exactly without having to be interpolated. Vertices on the border of the mesh may only
+// slide along the border, and vertices on an attribute seam (where a position is split into several
+// vertices, e.g. because of a UV discontinuity) may only slide along the seam. That keeps holes
+// from growing and textures from tearing.
+
+pub struct Simplified {
+    pub mesh             : Mesh,
+    pub error            : f32,   // Roughly how far the surface has moved at most, in model units
+    pub triangles_before : usize,
+    pub triangles_after  : usize,
+}
+
+// How strongly the planes keeping borders and seams in place are weighted, compared to the surface
+const BOUNDARY_WEIGHT: f64 = 10.0;
+
+// Simplify `mesh` down to about `target_ratio` of its triangles, e.g. 0.25 for a quarter of them.
+// Stops early if no more edges can be collapsed without breaking borders, seams or the topology.
+pub fn simplify(mesh: &Mesh, target_ratio: f32) -> Simplified {
+    let mut simplifier = Simplifier::new(mesh);
+    let triangles_before = simplifier.alive_faces;
+    let target = (triangles_before as f32 * target_ratio.clamp(0.0, 1.0)).ceil() as usize;
+    let max_error = simplifier.run(target);
+
+    let mut simplified = mesh.clone();
+    simplified.indices = simplifier.faces.iter()
+        .zip(&simplifier.face_alive)
+        .filter(|(_, &alive)| alive)
+        .flat_map(|(face, _)| face.iter().cloned())
+        .collect();
+    simplified.remove_unused_vertices();
+    simplified.index_count = simplified.indices.len() as i32;
+
+    Simplified {
+        triangles_after: simplified.indices.len() / 3,
+        mesh: simplified,
+        error: max_error.max(0.0).sqrt() as f32,
+        triangles_before,
+    }
+}
+
+// A symmetric 4x4 matrix, stored as its upper triangle
+#[derive(Clone, Copy, Default)]
+struct Quadric([f64; 10]);
+
+impl Quadric {
+    // Squared distance to the plane through `point` with unit `normal`, times `weight`
+    fn from_plane(normal: &glm::DVec3, point: &glm::DVec3, weight: f64) -> Self {
+        let (a, b, c) = (normal.x, normal.y, normal.z);
+        let d = -glm::dot(normal, point);
+        Quadric([
+            a * a, a * b, a * c, a * d,
+                   b * b, b * c, b * d,
+                          c * c, c * d,
+                                 d * d,
+        ].map(|v| v * weight))
+    }
+
+    fn add(&mut self, other: &Quadric) {
+        for (a, b) in self.0.iter_mut().zip(other.0.iter()) {
+            *a += b;
+        }
+    }
+
+    fn error(&self, p: &glm::DVec3) -> f64 {
+        let q = &self.0;
+        let (x, y, z) = (p.x, p.y, p.z);
+        q[0] * x * x + 2.0 * q[1] * x * y + 2.0 * q[2] * x * z + 2.0 * q[3] * x
+                     +       q[4] * y * y + 2.0 * q[5] * y * z + 2.0 * q[6] * y
+                                          +       q[7] * z * z + 2.0 * q[8] * z
+                                                               +       q[9]
+    }
+}
+
+struct Simplifier {
+    positions   : Vec<glm::DVec3>, // Per vertex
+    welded      : Vec<u32>,        // Per vertex, the first vertex at the same position.
+                                   // The collapse works on these, called "points" below.
+    faces       : Vec<[u32; 3]>,   // Vertex indices
+    face_alive  : Vec<bool>,
+    alive_faces : usize,
+    point_faces : Vec<Vec<usize>>, // Per point, the faces touching it
+    quadrics    : Vec<Quadric>,    // Per point
+    on_border   : Vec<bool>,       // Per point
+    on_seam     : Vec<bool>,       // Per point
+    border_edges: HashSet<(u32, u32)>,
+    seam_edges  : HashSet<(u32, u32)>,
+    version     : Vec<u32>,        // Per point, bumped whenever it changes, to spot stale queue entries
+}
+
+// A face using an edge, and which of its vertices are at the lower and higher point of the edge
+type EdgeUse = (usize, u32, u32);
+
+fn edge_key(a: u32, b: u32) -> (u32, u32) {
+    (a.min(b), a.max(b))
+}
+
+impl Simplifier {
+    fn new(mesh: &Mesh) -> Self {
+        let vertex_count = mesh.vertices.len() / 3;
+        let positions: Vec<glm::DVec3> = (0..vertex_count)
+            .map(|v| glm::convert(mesh.position(v)))
+            .collect();
+        let welded = mesh.position_ids();
+
+        let faces: Vec<[u32; 3]> = mesh.indices.chunks_exact(3)
+            .map(|c| [c[0], c[1], c[2]])
+            .filter(|f| f.iter().all(|&i| (i as usize) < vertex_count))
+            .filter(|f| {
+                let (a, b, c) = (welded[f[0] as usize], welded[f[1] as usize], welded[f[2] as usize]);
+                a != b && b != c && a != c
+            })
+            .collect();
+
+        let mut point_faces = vec![vec![]; vertex_count];
+        let mut quadrics = vec![Quadric::default(); vertex_count];
+        // For each edge between points: the faces using it, and the vertices they use for its ends
+        let mut edges: HashMap<(u32, u32), Vec<EdgeUse>> = HashMap::new();
+
+        for (f, face) in faces.iter().enumerate() {
+            let normal = face_normal(&positions, face);
+            let plane = Quadric::from_plane(&normal, &positions[face[0] as usize], 1.0);
+            for k in 0..3 {
+                let (u, v) = (face[k], face[(k + 1) % 3]);
+                let (pu, pv) = (welded[u as usize], welded[v as usize]);
+                point_faces[pu as usize].push(f);
+                quadrics[pu as usize].add(&plane);
+                let (first, second) = if pu < pv { (u, v) } else { (v, u) };
+                edges.entry(edge_key(pu, pv)).or_default().push((f, first, second));
+            }
+        }
+
+        let mut on_border = vec![false; vertex_count];
+        let mut on_seam = vec![false; vertex_count];
+        let mut border_edges = HashSet::new();
+        let mut seam_edges = HashSet::new();
+        for (&(a, b), uses) in &edges {
+            // Edges shared by more than two faces are treated as borders, which keeps them in place
+            let is_border = uses.len() != 2;
+            let is_seam = uses.len() == 2 && (uses[0].1 != uses[1].1 || uses[0].2 != uses[1].2);
+            if !is_border && !is_seam {
+                continue;
+            }
+
+            // A plane through the edge, standing straight up from each face along it,
+            // pulls vertices moving away from the border or seam back towards it
+            let (pa, pb) = (positions[a as usize], positions[b as usize]);
+            let direction = pb - pa;
+            for &(f, _, _) in uses {
+                let normal = glm::cross(&direction, &face_normal(&positions, &faces[f]));
+                if glm::length(&normal) > 0.0 {
+                    let weight = BOUNDARY_WEIGHT * glm::length2(&direction);
+                    let plane = Quadric::from_plane(&glm::normalize(&normal), &pa, weight);
+                    quadrics[a as usize].add(&plane);
+                    quadrics[b as usize].add(&plane);
+                }
+            }
+
+            if is_border {
+                on_border[a as usize] = true;
+                on_border[b as usize] = true;
+                border_edges.insert((a, b));
+            } else {
+                on_seam[a as usize] = true;
+                on_seam[b as usize] = true;
+                seam_edges.insert((a, b));
+            }
+        }
+
+        Simplifier {
+            positions,
+            welded,
+            alive_faces: faces.len(),
+            face_alive: vec![true; faces.len()],
+            faces,
+            point_faces,
+            quadrics,
+            on_border,
+            on_seam,
+            border_edges,
+            seam_edges,
+            version: vec![0; vertex_count],
+        }
+    }
+
+    // Collapse edges, cheapest first, until at most `target` faces are left.
+    // Returns the largest quadric error of any collapse done.
+    fn run(&mut self, target: usize) -> f64 {
+        let mut queue = BinaryHeap::new();
+        for point in 0..self.point_faces.len() as u32 {
+            self.push_edges(point, &mut queue);
+        }
+
+        let mut max_error: f64 = 0.0;
+        while self.alive_faces > target {
+            let (Reverse(cost_bits), from, to, from_version, to_version) = match queue.pop() {
+                Some(entry) => entry,
+                None => break,
+            };
+            if self.version[from as usize] != from_version || self.version[to as usize] != to_version {
+                continue; // Something changed since this was queued. It has been queued again if still relevant.
+            }
+            if let Some(remap) = self.try_collapse(from, to) {
+                self.collapse(from, to, &remap);
+                max_error = max_error.max(f64::from_bits(cost_bits));
+                self.push_edges(to, &mut queue);
+            }
+        }
+        max_error
+    }
+
+    fn neighbours(&self, point: u32) -> Vec<u32> {
+        let mut neighbours: Vec<u32> = self.point_faces[point as usize].iter()
+            .flat_map(|&f| self.faces[f].iter().map(|&v| self.welded[v as usize]))
+            .filter(|&p| p != point)
+            .collect();
+        neighbours.sort_unstable();
+        neighbours.dedup();
+        neighbours
+    }
+
+    // Whether moving `from` onto `to` keeps borders and seams in place
+    fn may_move(&self, from: u32, to: u32) -> bool {
+        if self.on_border[from as usize] {
+            self.border_edges.contains(&edge_key(from, to))
+        } else if self.on_seam[from as usize] {
+            self.seam_edges.contains(&edge_key(from, to))
+        } else {
+            true
+        }
+    }
+
+    fn push_edges(&self, point: u32, queue: &mut BinaryHeap<(Reverse<u64>, u32, u32, u32, u32)>) {
+        for neighbour in self.neighbours(point) {
+            let mut quadric = self.quadrics[point as usize];
+            quadric.add(&self.quadrics[neighbour as usize]);
+
+            // Queue the cheaper of the two directions which is allowed at all
+            let candidates = [(point, neighbour), (neighbour, point)];
+            let best = candidates.iter()
+                .filter(|&&(from, to)| self.may_move(from, to))
+                .map(|&(from, to)| (quadric.error(&self.positions[to as usize]).max(0.0), from, to))
+                .min_by(|a, b| a.0.total_cmp(&b.0));
+            if let Some((cost, from, to)) = best {
+                // Non-negative floats sort the same as their bit patterns
+                queue.push((Reverse(cost.to_bits()), from, to, self.version[from as usize], self.version[to as usize]));
+            }
+        }
+    }
+
+    // Check that moving `from` onto `to` is safe, and if so return which vertex of `to`
+    // each vertex of `from` should be replaced with
+    fn try_collapse(&self, from: u32, to: u32) -> Option<HashMap<u32, u32>> {
+        let faces_from = &self.point_faces[from as usize];
+
+        // Each vertex at `from` must share a face with exactly one vertex at `to`, so that it's
+        // clear which side of a seam it belongs to
+        let mut remap: HashMap<u32, Option<u32>> = HashMap::new();
+        for &f in faces_from {
+            let face = self.faces[f];
+            let vertex_from = *face.iter().find(|&&v| self.welded[v as usize] == from)?;
+            remap.entry(vertex_from).or_insert(None);
+            if let Some(&vertex_to) = face.iter().find(|&&v| self.welded[v as usize] == to) {
+                match remap.get(&vertex_from) {
+                    Some(Some(existing)) if *existing != vertex_to => return None,
+                    _ => { remap.insert(vertex_from, Some(vertex_to)); }
+                }
+            }
+        }
+        let remap: HashMap<u32, u32> = remap.into_iter()
+            .map(|(v, target)| target.map(|t| (v, t)))
+            .collect::<Option<_>>()?;
+
+        // Link condition: the two points may only have the neighbours in common which are on
+        // the faces between them, or the collapse pinches the surface together
+        let shared_faces = faces_from.iter()
+            .filter(|&&f| self.faces[f].iter().any(|&v| self.welded[v as usize] == to))
+            .count();
+        let neighbours_to = self.neighbours(to);
+        let common = self.neighbours(from).iter().filter(|p| neighbours_to.binary_search(p).is_ok()).count();
+        if common != shared_faces {
+            return None;
+        }
+
+        // The faces that survive mustn't flip over or collapse to nothing
+        let target = self.positions[to as usize];
+        for &f in faces_from {
+            let face = self.faces[f];
+            if face.iter().any(|&v| self.welded[v as usize] == to) {
+                continue;
+            }
+            let before = face_normal(&self.positions, &face);
+            let corners: Vec<glm::DVec3> = face.iter()
+                .map(|&v| if self.welded[v as usize] == from { target } else { self.positions[v as usize] })
+                .collect();
+            let after = glm::cross(&(corners[1] - corners[0]), &(corners[2] - corners[0]));
+            if glm::length(&after) <= f64::EPSILON || glm::dot(&before, &glm::normalize(&after)) < 0.2 {
+                return None;
+            }
+        }
+
+        Some(remap)
+    }
+
+    fn collapse(&mut self, from: u32, to: u32, remap: &HashMap<u32, u32>) {
+        let faces_from = std::mem::take(&mut self.point_faces[from as usize]);
+        for f in faces_from {
+            if self.faces[f].iter().any(|&v| self.welded[v as usize] == to) {
+                // The faces between the two points disappear
+                self.face_alive[f] = false;
+                self.alive_faces -= 1;
+                for &v in &self.faces[f] {
+                    let point = self.welded[v as usize] as usize;
+                    if point != from as usize {
+                        self.point_faces[point].retain(|&other| other != f);
+                    }
+                }
+            } else {
+                for v in self.faces[f].iter_mut() {
+                    if let Some(&target) = remap.get(v) {
+                        *v = target;
+                    }
+                }
+                self.point_faces[to as usize].push(f);
+            }
+        }
+
+        // Borders and seams running through `from` now run through `to` instead
+        for edges in [&mut self.border_edges, &mut self.seam_edges] {
+            let moved: Vec<(u32, u32)> = edges.iter()
+                .filter(|&&(a, b)| a == from || b == from)
+                .cloned()
+                .collect();
+            for (a, b) in moved {
+                edges.remove(&(a, b));
+                let other = if a == from { b } else { a };
+                if other != to {
+                    edges.insert(edge_key(other, to));
+                }
+            }
+        }
+
+        let quadric = self.quadrics[from as usize];
+        self.quadrics[to as usize].add(&quadric);
+        self.version[from as usize] += 1;
+        self.version[to as usize] += 1;
+    }
+}
+
+fn face_normal(positions: &[glm::DVec3], face: &[u32; 3]) -> glm::DVec3 {
+    let a = positions[face[0] as usize];
+    let b = positions[face[1] as usize];
+    let c = positions[face[2] as usize];
+    let normal = glm::cross(&(b - a), &(c - a));
+    let length = glm::length(&normal);
+    if length > 0.0 { normal / length } else { normal }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::fixtures;
+
+    fn area(mesh: &Mesh) -> f32 {
+        mesh.indices.chunks_exact(3).map(|c| {
+            let (a, b, c) = (mesh.position(c[0] as usize), mesh.position(c[1] as usize), mesh.position(c[2] as usize));
+            glm::length(&glm::cross(&(b - a), &(c - a))) * 0.5
+        }).sum()
+    }
+
+    // The edges only one triangle uses, as pairs of positions
+    fn border(mesh: &Mesh) -> Vec<(glm::Vec3, glm::Vec3)> {
+        let mut uses: HashMap<(u32, u32), usize> = HashMap::new();
+        for c in mesh.indices.chunks_exact(3) {
+            for k in 0..3 {
+                *uses.entry(edge_key(c[k], c[(k + 1) % 3])).or_default() += 1;
+            }
+        }
+        uses.into_iter()
+            .filter(|&(_, count)| count == 1)
+            .map(|((a, b), _)| (mesh.position(a as usize), mesh.position(b as usize)))
+            .collect()
+    }
+
+    #[test]
+    fn reaches_the_target_on_a_flat_grid() {
+        let grid = fixtures::grid(16);
+        let simplified = simplify(&grid, 0.25);
+        assert_eq!(simplified.triangles_before, 512);
+        assert!(simplified.triangles_after <= 128, "{}", simplified.triangles_after);
+        assert!(simplified.triangles_after >= 2);
+        assert!(simplified.error < 1e-3, "{}", simplified.error);
+        assert!(simplified.mesh.validate().is_ok(), "{}", simplified.mesh.validate());
+    }
+
+    #[test]
+    fn keeps_the_border_where_it_was() {
+        let simplified = simplify(&fixtures::grid(16), 0.1).mesh;
+        // No holes, and nothing pulled in from the edges
+        assert!((area(&simplified) - 256.0).abs() < 1e-3, "{}", area(&simplified));
+        let border = border(&simplified);
+        let length: f32 = border.iter().map(|(a, b)| glm::distance(a, b)).sum();
+        assert!((length - 64.0).abs() < 1e-3, "{}", length);
+        for (a, b) in &border {
+            let same_side = (a.x == b.x && (a.x == 0.0 || a.x == 16.0)) || (a.z == b.z && (a.z == 0.0 || a.z == 16.0));
+            assert!(same_side, "{} {}", a, b);
+        }
+        for corner in [[0.0, 0.0], [16.0, 0.0], [0.0, 16.0], [16.0, 16.0]] {
+            assert!(simplified.vertices.chunks_exact(3).any(|p| p[0] == corner[0] && p[2] == corner[1]));
+        }
+    }
+
+    #[test]
+    fn a_ratio_of_one_changes_nothing() {
+        let cube = fixtures::cube();
+        let simplified = simplify(&cube, 1.0);
+        assert_eq!(simplified.triangles_after, 12);
+        assert_eq!(simplified.mesh.indices, cube.indices);
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::HashMap;
+
+use super::Mesh;
+
+// Per-vertex tangents for normal mapping, following the conventions of MikkTSpace, which is what
+// Blender and most bakers use, so normal maps baked there come out the same here:
+//  * Each triangle's tangent and bitangent directions come from how its UVs run across it.
+//  * At each corner they are projected onto the plane of the vertex normal and weighted by the
+//    angle of the corner before being summed up.
+//  * Vertices with the same position, normal and UV share one tangent, even if they are separate
+//    vertices in the mesh.
+//  * Where the UVs are mirrored, a vertex is split in two, so that both sides get their own tangent.
+//  * The tangent is stored as XYZ, and W holds the sign of the bitangent, so that the shader can
+//    rebuild it as `bitangent = sign * cross(normal, tangent)`.
+
+impl Mesh {
+    // Fill in `tangents` from the positions, normals and UVs. May add vertices where UVs are mirrored.
+    pub fn generate_tangents(&mut self) -> Result<(), String> {
+        let vertex_count = self.vertices.len() / 3;
+        if self.uvs.len() != vertex_count * 2 {
+            return Err("generating tangents needs a UV for every vertex".to_string());
+        }
+        if self.normals.len() != vertex_count * 3 {
+            return Err("generating tangents needs a normal for every vertex".to_string());
+        }
+
+        self.split_mirrored_vertices();
+        let vertex_count = self.vertices.len() / 3;
+
+        // After the split, a vertex is only used by mirrored or only by unmirrored triangles
+        let mut mirrored = vec![false; vertex_count];
+        for corners in self.indices.chunks_exact(3) {
+            if self.uv_area(corners) < -f32::EPSILON {
+                for &v in corners {
+                    mirrored[v as usize] = true;
+                }
+            }
+        }
+
+        // Vertices which only differ in color are treated as one
+        let mut group_of: HashMap<Vec<u32>, usize> = HashMap::new();
+        let groups: Vec<usize> = (0..vertex_count).map(|vertex| {
+            let key: Vec<u32> = self.vertices[vertex * 3..vertex * 3 + 3].iter()
+                .chain(&self.normals[vertex * 3..vertex * 3 + 3])
+                .chain(&self.uvs[vertex * 2..vertex * 2 + 2])
+                .map(|v| v.to_bits())
+                .chain(std::iter::once(mirrored[vertex] as u32))
+                .collect();
+            let next = group_of.len();
+            *group_of.entry(key).or_insert(next)
+        }).collect();
+
+        let mut tangents = vec![glm::Vec3::zeros(); group_of.len()];
+        let mut bitangents = vec![glm::Vec3::zeros(); group_of.len()];
+
+        for corners in self.indices.chunks_exact(3) {
+            let (direction_s, direction_t) = match self.uv_directions(corners) {
+                Some(directions) => directions,
+                None => continue, // The UVs have no area, so they don't say anything about direction
+            };
+
+            for k in 0..3 {
+                let vertex = corners[k] as usize;
+                let normal = self.normal(vertex);
+                let position = self.position(vertex);
+                let to_next = project(&(self.position(corners[(k + 1) % 3] as usize) - position), &normal);
+                let to_prev = project(&(self.position(corners[(k + 2) % 3] as usize) - position), &normal);
+                let angle = if glm::length(&to_next) > 0.0 && glm::length(&to_prev) > 0.0 {
+                    glm::dot(&glm::normalize(&to_next), &glm::normalize(&to_prev)).clamp(-1.0, 1.0).acos()
+                } else {
+                    0.0
+                };
+
+                tangents[groups[vertex]] += normalize_or_zero(&project(&direction_s, &normal)) * angle;
+                bitangents[groups[vertex]] += normalize_or_zero(&project(&direction_t, &normal)) * angle;
+            }
+        }
+
+        self.tangents = Vec::with_capacity(vertex_count * 4);
+        for (vertex, &group) in groups.iter().enumerate() {
+            let normal = self.normal(vertex);
+            let mut tangent = normalize_or_zero(&project(&tangents[group], &normal));
+            if tangent == glm::Vec3::zeros() {
+                // Nothing to go by, any direction along the surface will do
+                tangent = any_perpendicular(&normal);
+            }
+            let sign = if glm::dot(&glm::cross(&normal, &tangent), &bitangents[group]) < 0.0 { -1.0 } else { 1.0 };
+            self.tangents.extend_from_slice(&[tangent.x, tangent.y, tangent.z, sign]);
+        }
+
+        Ok(())
+    }
+
+    fn normal(&self, vertex: usize) -> glm::Vec3 {
+        normalize_or_zero(&glm::vec3(self.normals[vertex * 3], self.normals[vertex * 3 + 1], self.normals[vertex * 3 + 2]))
+    }
+
+    fn uv(&self, vertex: usize) -> glm::Vec2 {
+        glm::vec2(self.uvs[vertex * 2], self.uvs[vertex * 2 + 1])
+    }
+
+    // Twice the signed area of a triangle in UV space. Negative if the UVs are mirrored.
+    fn uv_area(&self, corners: &[u32]) -> f32 {
+        let a = self.uv(corners[0] as usize);
+        let b = self.uv(corners[1] as usize);
+        let c = self.uv(corners[2] as usize);
+        (b.x - a.x) * (c.y - a.y) - (c.x - a.x) * (b.y - a.y)
+    }
+
+    // The directions in which U and V increase across a triangle
+    fn uv_directions(&self, corners: &[u32]) -> Option<(glm::Vec3, glm::Vec3)> {
+        let area = self.uv_area(corners);
+        if area.abs() <= f32::EPSILON {
+            return None;
+        }
+        let p0 = self.position(corners[0] as usize);
+        let e1 = self.position(corners[1] as usize) - p0;
+        let e2 = self.position(corners[2] as usize) - p0;
+        let uv0 = self.uv(corners[0] as usize);
+        let d1 = self.uv(corners[1] as usize) - uv0;
+        let d2 = self.uv(corners[2] as usize) - uv0;
+
+        // The sign of the area is kept on purpose, it makes the bitangent point the right way on mirrored triangles
+        let direction_s = (e1 * d2.y - e2 * d1.y) / area;
+        let direction_t = (e2 * d1.x - e1 * d2.x) / area;
+        Some((direction_s, direction_t))
+    }
+
+    // Give the mirrored triangles their own copy of any vertex they share with unmirrored ones
+    fn split_mirrored_vertices(&mut self) {
+        let vertex_count = self.vertices.len() / 3;
+        let mirrored: Vec<Option<bool>> = self.indices.chunks_exact(3)
+            .map(|corners| {
+                let area = self.uv_area(corners);
+                if area.abs() <= f32::EPSILON { None } else { Some(area < 0.0) }
+            })
+            .collect();
+
+        let mut used_unmirrored = vec![false; vertex_count];
+        for (corners, &mirrored) in self.indices.chunks_exact(3).zip(&mirrored) {
+            if mirrored == Some(false) {
+                for &v in corners {
+                    used_unmirrored[v as usize] = true;
+                }
+            }
+        }
+
+        let mut copy_of: HashMap<u32, u32> = HashMap::new();
+        for (triangle, &is_mirrored) in mirrored.iter().enumerate() {
+            if is_mirrored != Some(true) {
+                continue;
+            }
+            for k in 0..3 {
+                let vertex = self.indices[triangle * 3 + k];
+                if used_unmirrored[vertex as usize] {
+                    let copy = match copy_of.get(&vertex) {
+                        Some(&copy) => copy,
+                        None => {
+                            let copy = self.duplicate_vertex(vertex as usize);
+                            copy_of.insert(vertex, copy);
+                            copy
+                        }
+                    };
+                    self.indices[triangle * 3 + k] = copy;
+                }
+            }
+        }
+    }
+
+    // Append a copy of a vertex with all of its attributes, and return the index of the copy
+    fn duplicate_vertex(&mut self, vertex: usize) -> u32 {
+        fn copy(data: &mut Vec<f32>, width: usize, vertex: usize) {
+            if data.len() >= (vertex + 1) * width {
+                data.extend_from_within(vertex * width..(vertex + 1) * width);
+            }
+        }
+        copy(&mut self.vertices, 3, vertex);
+        copy(&mut self.colors, 4, vertex);
+        copy(&mut self.normals, 3, vertex);
+        copy(&mut self.uvs, 2, vertex);
+        copy(&mut self.tangents, 4, vertex);
+        (self.vertices.len() / 3 - 1) as u32
+    }
+}
+
+// The part of `v` lying in the plane with unit normal `normal`
+fn project(v: &glm::Vec3, normal: &glm::Vec3) -> glm::Vec3 {
+    v - normal * glm::dot(normal, v)
+}
+
+fn normalize_or_zero(v: &glm::Vec3) -> glm::Vec3 {
+    let length = glm::length(v);
+    if length > f32::EPSILON { v / length } else { glm::Vec3::zeros() }
+}
+
+fn any_perpendicular(normal: &glm::Vec3) -> glm::Vec3 {
+    let axis = if normal.x.abs() < 0.9 { glm::vec3(1.0, 0.0, 0.0) } else { glm::vec3(0.0, 1.0, 0.0) };
+    glm::normalize(&project(&axis, normal))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::fixtures;
+
+    fn tangent(mesh: &Mesh, vertex: usize) -> (glm::Vec3, f32) {
+        let t = &mesh.tangents[vertex * 4..vertex * 4 + 4];
+        (glm::vec3(t[0], t[1], t[2]), t[3])
+    }
+
+    fn check_orthonormal(mesh: &Mesh) {
+        assert_eq!(mesh.tangents.len(), mesh.vertices.len() / 3 * 4);
+        for vertex in 0..mesh.vertices.len() / 3 {
+            let (t, sign) = tangent(mesh, vertex);
+            let n = glm::normalize(&mesh.normal(vertex));
+            assert!((glm::length(&t) - 1.0).abs() < 1e-5, "vertex {}: {}", vertex, t);
+            assert!(glm::dot(&t, &n).abs() < 1e-5, "vertex {}: {} against {}", vertex, t, n);
+            assert!(sign == 1.0 || sign == -1.0);
+        }
+    }
+
+    #[test]
+    fn tangents_are_orthogonal_to_flat_normals() {
+        let mut cube = fixtures::cube();
+        cube.generate_tangents().unwrap();
+        check_orthonormal(&cube);
+    }
+
+    #[test]
+    fn tangents_are_orthogonal_to_smooth_normals() {
+        // Normals pointing out from the middle, so they're not perpendicular to any face
+        let mut cube = fixtures::cube();
+        cube.normals = cube.vertices.chunks_exact(3)
+            .flat_map(|p| glm::normalize(&glm::vec3(p[0], p[1], p[2])).as_slice().to_vec())
+            .collect();
+        cube.generate_tangents().unwrap();
+        check_orthonormal(&cube);
+    }
+
+    #[test]
+    fn tangents_follow_the_uvs() {
+        let mut grid = fixtures::grid(2);
+        grid.generate_tangents().unwrap();
+        for vertex in 0..grid.vertices.len() / 3 {
+            // U runs along +X and V along +Z, which the bitangent is rebuilt as
+            let (t, sign) = tangent(&grid, vertex);
+            assert!(glm::distance(&t, &glm::vec3(1.0, 0.0, 0.0)) < 1e-5, "{}", t);
+            let bitangent = glm::cross(&grid.normal(vertex), &t) * sign;
+            assert!(glm::distance(&bitangent, &glm::vec3(0.0, 0.0, 1.0)) < 1e-5, "{}", bitangent);
+        }
+    }
+
+    #[test]
+    fn mirrored_uvs_get_their_own_vertices() {
+        // The right half of the grid has its U running backwards
+        let mut grid = fixtures::grid(2);
+        for uv in grid.uvs.chunks_exact_mut(2) {
+            uv[0] = 1.0 - (2.0 * uv[0] - 1.0).abs();
+        }
+        let vertices_before = grid.vertices.len() / 3;
+        grid.generate_tangents().unwrap();
+        assert!(grid.vertices.len() / 3 > vertices_before);
+        check_orthonormal(&grid);
+        assert!(grid.tangents.chunks_exact(4).any(|t| t[3] == 1.0) && grid.tangents.chunks_exact(4).any(|t| t[3] == -1.0));
+    }
+
+    #[test]
+    fn needs_uvs_and_normals() {
+        let mut cube = fixtures::cube();
+        cube.uvs.clear();
+        assert!(cube.generate_tangents().is_err());
+        let mut cube = fixtures::cube();
+        cube.normals.pop();
+        assert!(cube.generate_tangents().is_err());
+    }
+}
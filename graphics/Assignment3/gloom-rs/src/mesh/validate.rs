@@ -0,0 +1,449 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use super::Mesh;
+
+// Checks for the kinds of broken geometry that otherwise show up as GL errors or silent garbage
+// on screen, and a repair pass for the ones that can be fixed automatically.
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MeshIssue {
+    // `index_count` doesn't match the number of indices, so too much or too little is drawn
+    IndexCountMismatch { index_count: i32, indices: usize },
+    // The index buffer doesn't hold a whole number of triangles
+    IncompleteTriangle { indices: usize },
+    IndexOutOfRange { triangle: usize, index: u32, vertex_count: usize },
+    // A triangle using the same vertex twice
+    DegenerateTriangle { triangle: usize },
+    // A triangle with three distinct vertices, but with no area
+    ZeroAreaTriangle { triangle: usize },
+    NonFinitePosition { vertex: usize },
+    // An attribute with the wrong number of values for the number of vertices
+    AttributeLengthMismatch { attribute: &'static str, expected: usize, found: usize },
+    // An edge shared by more than two triangles
+    NonManifoldEdge { a: u32, b: u32, triangles: usize },
+    // Two neighbouring triangles going around their shared edge in the same direction
+    InconsistentWinding { a: u32, b: u32 },
+}
+
+impl fmt::Display for MeshIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MeshIssue::IndexCountMismatch { index_count, indices } =>
+                write!(f, "index_count is {}, but there are {} indices", index_count, indices),
+            MeshIssue::IncompleteTriangle { indices } =>
+                write!(f, "{} indices is not a multiple of 3", indices),
+            MeshIssue::IndexOutOfRange { triangle, index, vertex_count } =>
+                write!(f, "triangle {} uses vertex {}, but there are only {} vertices", triangle, index, vertex_count),
+            MeshIssue::DegenerateTriangle { triangle } =>
+                write!(f, "triangle {} uses the same vertex more than once", triangle),
+            MeshIssue::ZeroAreaTriangle { triangle } =>
+                write!(f, "triangle {} has no area", triangle),
+            MeshIssue::NonFinitePosition { vertex } =>
+                write!(f, "vertex {} has a NaN or infinite position", vertex),
+            MeshIssue::AttributeLengthMismatch { attribute, expected, found } =>
+                write!(f, "{} has {} values, expected {}", attribute, found, expected),
+            MeshIssue::NonManifoldEdge { a, b, triangles } =>
+                write!(f, "edge {}-{} is shared by {} triangles", a, b, triangles),
+            MeshIssue::InconsistentWinding { a, b } =>
+                write!(f, "the triangles sharing edge {}-{} face opposite ways", a, b),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ValidationReport {
+    pub issues: Vec<MeshIssue>,
+}
+
+impl ValidationReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+}
+
+impl fmt::Display for ValidationReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.issues.is_empty() {
+            return write!(f, "No issues found");
+        }
+        writeln!(f, "{} issue(s) found:", self.issues.len())?;
+        for issue in &self.issues {
+            writeln!(f, "  {}", issue)?;
+        }
+        Ok(())
+    }
+}
+
+// What `Mesh::repair` did
+#[derive(Clone, Debug, Default)]
+pub struct RepairSummary {
+    pub removed_invalid    : usize, // Triangles with out of range indices or non-finite positions
+    pub removed_degenerate : usize, // Triangles with repeated vertices or no area
+    pub welded_vertices    : usize, // Vertices merged into an identical one
+    pub flipped_triangles  : usize, // Triangles turned around to match their neighbours
+    pub fixed_attributes   : Vec<&'static str>,
+}
+
+// Below this, a triangle is considered to have no area
+const AREA_EPSILON: f32 = 1e-12;
+
+impl Mesh {
+    pub fn validate(&self) -> ValidationReport {
+        let mut issues = vec![];
+        let vertex_count = self.vertices.len() / 3;
+
+        if !self.vertices.len().is_multiple_of(3) {
+            issues.push(MeshIssue::AttributeLengthMismatch {
+                attribute: "vertices",
+                expected: vertex_count * 3,
+                found: self.vertices.len(),
+            });
+        }
+        for (attribute, data, width, optional) in self.attributes() {
+            if data.len() != vertex_count * width && !(optional && data.is_empty()) {
+                issues.push(MeshIssue::AttributeLengthMismatch { attribute, expected: vertex_count * width, found: data.len() });
+            }
+        }
+
+        if self.index_count < 0 || self.index_count as usize != self.indices.len() {
+            issues.push(MeshIssue::IndexCountMismatch { index_count: self.index_count, indices: self.indices.len() });
+        }
+        if !self.indices.len().is_multiple_of(3) {
+            issues.push(MeshIssue::IncompleteTriangle { indices: self.indices.len() });
+        }
+
+        for vertex in 0..vertex_count {
+            if !self.position(vertex).iter().all(|v| v.is_finite()) {
+                issues.push(MeshIssue::NonFinitePosition { vertex });
+            }
+        }
+
+        for (triangle, corners) in self.indices.chunks_exact(3).enumerate() {
+            let mut in_range = true;
+            for &index in corners {
+                if index as usize >= vertex_count {
+                    issues.push(MeshIssue::IndexOutOfRange { triangle, index, vertex_count });
+                    in_range = false;
+                }
+            }
+            if !in_range {
+                continue;
+            }
+            if corners[0] == corners[1] || corners[1] == corners[2] || corners[0] == corners[2] {
+                issues.push(MeshIssue::DegenerateTriangle { triangle });
+            } else if self.triangle_area(corners) <= AREA_EPSILON {
+                issues.push(MeshIssue::ZeroAreaTriangle { triangle });
+            }
+        }
+
+        // Topology is checked on positions rather than indices, since a vertex is split in two
+        // wherever its normal or color changes, which would otherwise look like a hole
+        let welded = self.position_ids();
+        for ((a, b), uses) in self.edge_uses(&welded) {
+            if uses.len() > 2 {
+                issues.push(MeshIssue::NonManifoldEdge { a, b, triangles: uses.len() });
+            } else if uses.len() == 2 && uses[0].1 == uses[1].1 {
+                issues.push(MeshIssue::InconsistentWinding { a, b });
+            }
+        }
+
+        ValidationReport { issues }
+    }
+
+    // Fix what can be fixed: drop unusable and degenerate triangles, pad or clear attributes of the
+    // wrong length, weld identical vertices and turn triangles around to a consistent winding
+    pub fn repair(&mut self) -> RepairSummary {
+        let mut summary = RepairSummary::default();
+        let vertex_count = self.vertices.len() / 3;
+        self.vertices.truncate(vertex_count * 3);
+
+        // Colors are required, so those are padded with white. The rest are thrown away,
+        // as the shader falls back to defaults for attributes that are missing entirely.
+        if self.colors.len() != vertex_count * 4 {
+            self.colors.resize(vertex_count * 4, 1.0);
+            summary.fixed_attributes.push("colors");
+        }
+        for (name, data, width) in [
+            ("normals",  &mut self.normals,  3),
+            ("uvs",      &mut self.uvs,      2),
+            ("tangents", &mut self.tangents, 4),
+        ] {
+            if !data.is_empty() && data.len() != vertex_count * width {
+                data.clear();
+                summary.fixed_attributes.push(name);
+            }
+        }
+
+        // Drop triangles we can't draw at all
+        let mut triangles: Vec<[u32; 3]> = self.indices.chunks_exact(3)
+            .map(|c| [c[0], c[1], c[2]])
+            .collect();
+        let before = triangles.len();
+        triangles.retain(|t| t.iter().all(|&i| {
+            (i as usize) < vertex_count && self.position(i as usize).iter().all(|v| v.is_finite())
+        }));
+        summary.removed_invalid = before - triangles.len();
+
+        // Weld vertices which are identical in every attribute
+        let mut remap: Vec<u32> = (0..vertex_count as u32).collect();
+        let mut seen: HashMap<Vec<u32>, u32> = HashMap::new();
+        for (vertex, target) in remap.iter_mut().enumerate() {
+            let first = *seen.entry(self.vertex_key(vertex)).or_insert(vertex as u32);
+            if first != vertex as u32 {
+                *target = first;
+                summary.welded_vertices += 1;
+            }
+        }
+        for triangle in &mut triangles {
+            for index in triangle.iter_mut() {
+                *index = remap[*index as usize];
+            }
+        }
+
+        let before = triangles.len();
+        triangles.retain(|t| t[0] != t[1] && t[1] != t[2] && t[0] != t[2] && self.triangle_area(t) > AREA_EPSILON);
+        summary.removed_degenerate = before - triangles.len();
+
+        self.indices = triangles.iter().flatten().cloned().collect();
+        summary.flipped_triangles = self.unify_winding();
+        self.remove_unused_vertices();
+        self.index_count = self.indices.len() as i32;
+
+        summary
+    }
+
+    // (name, data, values per vertex, whether it may be left out entirely) for every attribute but the positions
+    fn attributes(&self) -> [(&'static str, &Vec<f32>, usize, bool); 4] {
+        [
+            ("colors",   &self.colors,   4, false),
+            ("normals",  &self.normals,  3, true),
+            ("uvs",      &self.uvs,      2, true),
+            ("tangents", &self.tangents, 4, true),
+        ]
+    }
+
+    pub(super) fn position(&self, vertex: usize) -> glm::Vec3 {
+        glm::vec3(self.vertices[vertex * 3], self.vertices[vertex * 3 + 1], self.vertices[vertex * 3 + 2])
+    }
+
+    fn triangle_area(&self, corners: &[u32]) -> f32 {
+        let a = self.position(corners[0] as usize);
+        let b = self.position(corners[1] as usize);
+        let c = self.position(corners[2] as usize);
+        glm::length(&glm::cross(&(b - a), &(c - a))) * 0.5
+    }
+
+    // Every attribute of a vertex, bit for bit, for finding exact duplicates
+    fn vertex_key(&self, vertex: usize) -> Vec<u32> {
+        let mut key: Vec<u32> = self.vertices[vertex * 3..vertex * 3 + 3].iter().map(|v| v.to_bits()).collect();
+        for (_, data, width, _) in self.attributes() {
+            if data.len() >= (vertex + 1) * width {
+                key.extend(data[vertex * width..(vertex + 1) * width].iter().map(|v| v.to_bits()));
+            }
+        }
+        key
+    }
+
+    // For each vertex, the lowest index of a vertex at exactly the same position
+    pub(super) fn position_ids(&self) -> Vec<u32> {
+        let mut first_at: HashMap<[u32; 3], u32> = HashMap::new();
+        (0..self.vertices.len() / 3).map(|vertex| {
+            let p = &self.vertices[vertex * 3..vertex * 3 + 3];
+            *first_at.entry([p[0].to_bits(), p[1].to_bits(), p[2].to_bits()]).or_insert(vertex as u32)
+        }).collect()
+    }
+
+    // For each undirected edge (smallest id first), the triangles using it and whether they go
+    // from the smaller to the larger id. Triangles with out of range indices are skipped.
+    fn edge_uses(&self, welded: &[u32]) -> HashMap<(u32, u32), Vec<(usize, bool)>> {
+        let mut edges: HashMap<(u32, u32), Vec<(usize, bool)>> = HashMap::new();
+        for (triangle, corners) in self.indices.chunks_exact(3).enumerate() {
+            if corners.iter().any(|&i| i as usize >= welded.len()) {
+                continue;
+            }
+            for k in 0..3 {
+                let a = welded[corners[k] as usize];
+                let b = welded[corners[(k + 1) % 3] as usize];
+                if a != b {
+                    edges.entry((a.min(b), a.max(b))).or_default().push((triangle, a < b));
+                }
+            }
+        }
+        edges
+    }
+
+    // Flip triangles so that every pair of neighbours goes around their shared edge in opposite
+    // directions. Each connected piece keeps the winding of most of its triangles, or the one
+    // agreeing with the vertex normals if there are any. Returns the number of triangles flipped.
+    fn unify_winding(&mut self) -> usize {
+        let triangle_count = self.indices.len() / 3;
+        let welded = self.position_ids();
+        let edges = self.edge_uses(&welded);
+
+        let mut neighbours: Vec<Vec<(usize, bool)>> = vec![vec![]; triangle_count];
+        for uses in edges.values() {
+            // Leave non-manifold edges alone, there's no right answer for them
+            if let [(t0, forward0), (t1, forward1)] = uses[..] {
+                // Consistent if they cross the edge in opposite directions
+                let same_direction = forward0 == forward1;
+                neighbours[t0].push((t1, same_direction));
+                neighbours[t1].push((t0, same_direction));
+            }
+        }
+
+        let mut flip: Vec<Option<bool>> = vec![None; triangle_count];
+        let mut flipped = 0;
+        for seed in 0..triangle_count {
+            if flip[seed].is_some() {
+                continue;
+            }
+            let mut piece = vec![seed];
+            let mut queue = VecDeque::from(vec![seed]);
+            flip[seed] = Some(false);
+            while let Some(triangle) = queue.pop_front() {
+                for &(neighbour, same_direction) in &neighbours[triangle] {
+                    if flip[neighbour].is_none() {
+                        flip[neighbour] = Some(flip[triangle].unwrap() ^ same_direction);
+                        piece.push(neighbour);
+                        queue.push_back(neighbour);
+                    }
+                }
+            }
+
+            // Decide which of the two windings the piece should end up with
+            let flip_count = piece.iter().filter(|&&t| flip[t] == Some(true)).count();
+            let mut flip_all = flip_count * 2 > piece.len();
+            if self.normals.len() == self.vertices.len() {
+                let agreeing = piece.iter().filter(|&&t| self.agrees_with_normals(t) != flip[t].unwrap()).count();
+                flip_all = agreeing * 2 < piece.len();
+            }
+            for &triangle in &piece {
+                if flip[triangle].unwrap() != flip_all {
+                    self.indices.swap(triangle * 3 + 1, triangle * 3 + 2);
+                    flipped += 1;
+                }
+            }
+        }
+        flipped
+    }
+
+    // Whether the counter-clockwise face normal of a triangle points the same way as its vertex normals
+    fn agrees_with_normals(&self, triangle: usize) -> bool {
+        let corners = &self.indices[triangle * 3..triangle * 3 + 3];
+        let a = self.position(corners[0] as usize);
+        let b = self.position(corners[1] as usize);
+        let c = self.position(corners[2] as usize);
+        let face_normal = glm::cross(&(b - a), &(c - a));
+        let vertex_normal: glm::Vec3 = corners.iter()
+            .map(|&i| glm::vec3(self.normals[i as usize * 3], self.normals[i as usize * 3 + 1], self.normals[i as usize * 3 + 2]))
+            .sum();
+        glm::dot(&face_normal, &vertex_normal) >= 0.0
+    }
+
+    // Drop vertices no triangle refers to, keeping the rest in order
+    pub(super) fn remove_unused_vertices(&mut self) {
+        let vertex_count = self.vertices.len() / 3;
+        let mut used = vec![false; vertex_count];
+        for &index in &self.indices {
+            used[index as usize] = true;
+        }
+
+        let mut new_index = vec![0u32; vertex_count];
+        let mut next = 0;
+        for vertex in 0..vertex_count {
+            if used[vertex] {
+                new_index[vertex] = next;
+                next += 1;
+            }
+        }
+        for index in &mut self.indices {
+            *index = new_index[*index as usize];
+        }
+
+        fn keep(data: &mut Vec<f32>, width: usize, used: &[bool]) {
+            if data.is_empty() {
+                return;
+            }
+            let mut vertex = 0;
+            data.retain(|_| {
+                let keep = used[vertex / width];
+                vertex += 1;
+                keep
+            });
+        }
+        keep(&mut self.vertices, 3, &used);
+        keep(&mut self.colors, 4, &used);
+        keep(&mut self.normals, 3, &used);
+        keep(&mut self.uvs, 2, &used);
+        keep(&mut self.tangents, 4, &used);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mesh::fixtures;
+
+    // A cube with a triangle using one vertex twice, and a copy of vertex 0 used by one of its corners
+    fn broken_cube() -> Mesh {
+        let mut cube = fixtures::cube();
+        for (data, width) in [(&mut cube.vertices, 3), (&mut cube.normals, 3), (&mut cube.colors, 4), (&mut cube.uvs, 2)] {
+            let copy = data[..width].to_vec();
+            data.extend(copy);
+        }
+        cube.indices[3] = 24;
+        cube.indices.extend_from_slice(&[0, 1, 0]);
+        cube.index_count = cube.indices.len() as i32;
+        cube
+    }
+
+    #[test]
+    fn a_clean_cube_has_no_issues() {
+        let report = fixtures::cube().validate();
+        assert!(report.is_ok(), "{}", report);
+    }
+
+    #[test]
+    fn finds_the_degenerate_triangle() {
+        // Its edge 0-1 also doubles up with the cube's own. The copy of vertex 0 is fine as it is.
+        let report = broken_cube().validate();
+        assert_eq!(report.issues, vec![
+            MeshIssue::DegenerateTriangle { triangle: 12 },
+            MeshIssue::NonManifoldEdge { a: 0, b: 1, triangles: 4 },
+        ]);
+    }
+
+    #[test]
+    fn finds_mismatched_attributes_and_indices() {
+        let mut cube = fixtures::cube();
+        cube.normals.pop();
+        cube.indices.push(99);
+        let issues = cube.validate().issues;
+        assert!(issues.contains(&MeshIssue::AttributeLengthMismatch { attribute: "normals", expected: 72, found: 71 }));
+        assert!(issues.contains(&MeshIssue::IndexCountMismatch { index_count: 36, indices: 37 }));
+        assert!(issues.contains(&MeshIssue::IncompleteTriangle { indices: 37 }));
+    }
+
+    #[test]
+    fn repair_drops_the_degenerate_triangle_and_welds_the_copy() {
+        let mut cube = broken_cube();
+        let summary = cube.repair();
+        assert_eq!(summary.removed_degenerate, 1);
+        assert_eq!(summary.welded_vertices, 1);
+        assert_eq!(summary.flipped_triangles, 0);
+        assert_eq!(cube.vertices, fixtures::cube().vertices);
+        assert_eq!(cube.indices, fixtures::cube().indices);
+        assert!(cube.validate().is_ok(), "{}", cube.validate());
+    }
+
+    #[test]
+    fn repair_turns_a_flipped_triangle_back_around() {
+        let mut cube = fixtures::cube();
+        cube.indices.swap(1, 2);
+        assert!(cube.validate().issues.iter().any(|issue| matches!(issue, MeshIssue::InconsistentWinding { .. })));
+        let summary = cube.repair();
+        assert_eq!(summary.flipped_triangles, 1);
+        assert_eq!(cube.indices, fixtures::cube().indices);
+    }
+}
@@ -0,0 +1,304 @@
+use crate::bounds::Aabb;
+use crate::scene_graph::traversal::Visitor;
+use crate::scene_graph::SceneNode;
+
+// Finding what's under the mouse: turn the cursor into a ray through the scene, skip the
+// subtrees whose bounds it misses, and test the triangles of the rest.
+
+#[derive(Clone, Copy, Debug)]
+pub struct Ray {
+    pub origin    : glm::Vec3,
+    pub direction : glm::Vec3,   // Not necessarily of unit length, distances are in multiples of it
+}
+
+// Where a ray hit a triangle. `barycentric` holds the weights of the triangle's three corners,
+// so `a * w.x + b * w.y + c * w.z` is the point that was hit, and works for any vertex attribute.
+pub struct Hit<'a> {
+    pub node        : &'a SceneNode,
+    pub triangle    : usize,       // Index of the first of the triangle's corners in `Mesh::indices`, divided by 3
+    pub distance    : f32,         // Along the ray, in multiples of its direction
+    pub barycentric : glm::Vec3,
+    pub point       : glm::Vec3,   // In world space
+}
+
+impl Ray {
+    pub fn new(origin: glm::Vec3, direction: glm::Vec3) -> Self {
+        Ray { origin, direction }
+    }
+
+    // The ray from the camera through the cursor, with its position in pixels from the top left
+    // of the window. The direction has unit length, so distances along it are in world units.
+    pub fn from_cursor(x: f32, y: f32, width: f32, height: f32, view_projection_matrix: &glm::Mat4) -> Self {
+        let ndc_x = 2.0 * x / width - 1.0;
+        let ndc_y = 1.0 - 2.0 * y / height;
+        let inverse = glm::inverse(view_projection_matrix);
+        let unproject = |z: f32| {
+            let p = inverse * glm::vec4(ndc_x, ndc_y, z, 1.0);
+            p.xyz() / p.w
+        };
+        let near = unproject(-1.0);
+        let far = unproject(1.0);
+        Ray::new(near, glm::normalize(&(far - near)))
+    }
+
+    pub fn at(&self, distance: f32) -> glm::Vec3 {
+        self.origin + self.direction * distance
+    }
+
+    // The same ray in another space. The direction is not renormalized, so that distances stay
+    // comparable with those along the original ray.
+    pub fn transformed(&self, matrix: &glm::Mat4) -> Ray {
+        let origin = matrix * glm::vec4(self.origin.x, self.origin.y, self.origin.z, 1.0);
+        let direction = matrix * glm::vec4(self.direction.x, self.direction.y, self.direction.z, 0.0);
+        Ray::new(origin.xyz() / origin.w, direction.xyz())
+    }
+
+    // The distance at which the ray enters the box, or 0 if it starts inside it
+    pub fn intersect_aabb(&self, aabb: &Aabb) -> Option<f32> {
+        if aabb.is_empty() {
+            return None;
+        }
+        let (mut near, mut far) = (0.0f32, f32::INFINITY);
+        for axis in 0..3 {
+            let inverse = 1.0 / self.direction[axis];
+            let mut t0 = (aabb.min[axis] - self.origin[axis]) * inverse;
+            let mut t1 = (aabb.max[axis] - self.origin[axis]) * inverse;
+            if t0 > t1 {
+                std::mem::swap(&mut t0, &mut t1);
+            }
+            // NaN from 0 * infinity, when the ray runs along a face, leaves the bound as it is
+            near = if t0 > near { t0 } else { near };
+            far = if t1 < far { t1 } else { far };
+            if near > far {
+                return None;
+            }
+        }
+        Some(near)
+    }
+
+    // Möller–Trumbore. Returns the distance and the barycentric weights of `a`, `b` and `c`.
+    // Hits both sides of the triangle.
+    pub fn intersect_triangle(&self, a: &glm::Vec3, b: &glm::Vec3, c: &glm::Vec3) -> Option<(f32, glm::Vec3)> {
+        let edge1 = b - a;
+        let edge2 = c - a;
+        let p = glm::cross(&self.direction, &edge2);
+        let determinant = glm::dot(&edge1, &p);
+        if determinant.abs() < 1e-12 {
+            return None; // Parallel to the triangle
+        }
+        let inverse = 1.0 / determinant;
+
+        let s = self.origin - a;
+        let u = glm::dot(&s, &p) * inverse;
+        if !(0.0..=1.0).contains(&u) {
+            return None;
+        }
+        let q = glm::cross(&s, &edge1);
+        let v = glm::dot(&self.direction, &q) * inverse;
+        if v < 0.0 || u + v > 1.0 {
+            return None;
+        }
+        let t = glm::dot(&edge2, &q) * inverse;
+        if t < 0.0 {
+            return None;
+        }
+        Some((t, glm::vec3(1.0 - u - v, u, v)))
+    }
+}
+
+// The closest triangle the ray hits below `root`. Only nodes with a CPU side mesh (`SceneNode::mesh`)
+// can be hit, using their BVH if they have one, and the world matrices and bounds are those of the last `update_world_matrices`.
+pub fn pick<'a>(root: &'a SceneNode, ray: &Ray) -> Option<Hit<'a>> {
+    let mut picker = Picker { ray: *ray, best: None };
+    root.accept(&mut picker);
+    picker.best.map(|best| Hit {
+        node        : unsafe { &*best.node },
+        triangle    : best.triangle,
+        distance    : best.distance,
+        barycentric : best.barycentric,
+        point       : ray.at(best.distance),
+    })
+}
+
+struct BestHit {
+    node        : *const SceneNode,
+    triangle    : usize,
+    distance    : f32,
+    barycentric : glm::Vec3,
+}
+
+struct Picker {
+    ray  : Ray,
+    best : Option<BestHit>,
+}
+
+impl Picker {
+    fn best_distance(&self) -> f32 {
+        self.best.as_ref().map_or(f32::INFINITY, |best| best.distance)
+    }
+}
+
+impl Visitor for Picker {
+    fn enter(&mut self, node: &SceneNode, _depth: usize) -> bool {
+        // Nothing below here can beat what we have if the bounds are missed or further away
+        if let Some(bounds) = node.world_bounds() {
+            match self.ray.intersect_aabb(&bounds) {
+                Some(distance) if distance <= self.best_distance() => {}
+                _ => return false,
+            }
+        }
+
+        let mesh = match (&node.mesh, node.is_drawable()) {
+            (Some(mesh), true) => mesh,
+            _ => return true,
+        };
+        let local_ray = self.ray.transformed(&node.world_to_local());
+        if let Some(bvh) = &node.bvh {
+            if let Some(hit) = bvh.ray_cast(&local_ray, self.best_distance()) {
+                self.best = Some(BestHit { node, triangle: hit.triangle, distance: hit.distance, barycentric: hit.barycentric });
+            }
+            return true;
+        }
+        for (triangle, corners) in mesh.indices.chunks_exact(3).enumerate() {
+            let corner = |i: usize| {
+                let v = corners[i] as usize;
+                glm::vec3(mesh.vertices[v * 3], mesh.vertices[v * 3 + 1], mesh.vertices[v * 3 + 2])
+            };
+            if let Some((distance, barycentric)) = local_ray.intersect_triangle(&corner(0), &corner(1), &corner(2)) {
+                if distance < self.best_distance() {
+                    self.best = Some(BestHit { node, triangle, distance, barycentric });
+                }
+            }
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::gl_objects::placeholder_vao;
+    use crate::mesh::fixtures;
+    use crate::scene_graph::Node;
+
+    fn corners() -> (glm::Vec3, glm::Vec3, glm::Vec3) {
+        (glm::vec3(0.0, 0.0, 0.0), glm::vec3(1.0, 0.0, 0.0), glm::vec3(0.0, 1.0, 0.0))
+    }
+
+    #[test]
+    fn ray_hits_a_triangle_where_expected() {
+        let (a, b, c) = corners();
+        let (distance, barycentric) = Ray::new(glm::vec3(0.25, 0.5, 5.0), glm::vec3(0.0, 0.0, -1.0))
+            .intersect_triangle(&a, &b, &c)
+            .unwrap();
+        assert!((distance - 5.0).abs() < 1e-6);
+        assert!(glm::distance(&barycentric, &glm::vec3(0.25, 0.25, 0.5)) < 1e-6, "{}", barycentric);
+        let point = a * barycentric.x + b * barycentric.y + c * barycentric.z;
+        assert!(glm::distance(&point, &glm::vec3(0.25, 0.5, 0.0)) < 1e-6);
+
+        // Distances are in multiples of the direction
+        let (distance, _) = Ray::new(glm::vec3(0.25, 0.5, 5.0), glm::vec3(0.0, 0.0, -2.0)).intersect_triangle(&a, &b, &c).unwrap();
+        assert!((distance - 2.5).abs() < 1e-6);
+    }
+
+    #[test]
+    fn ray_hits_the_back_of_a_triangle_too() {
+        let (a, b, c) = corners();
+        let (distance, barycentric) = Ray::new(glm::vec3(0.25, 0.25, -3.0), glm::vec3(0.0, 0.0, 1.0))
+            .intersect_triangle(&a, &b, &c)
+            .unwrap();
+        assert!((distance - 3.0).abs() < 1e-6);
+        assert!(glm::distance(&barycentric, &glm::vec3(0.5, 0.25, 0.25)) < 1e-6, "{}", barycentric);
+    }
+
+    #[test]
+    fn ray_misses_a_triangle() {
+        let (a, b, c) = corners();
+        // Beside it, pointing away from it, and running along its plane
+        assert!(Ray::new(glm::vec3(0.8, 0.8, 5.0), glm::vec3(0.0, 0.0, -1.0)).intersect_triangle(&a, &b, &c).is_none());
+        assert!(Ray::new(glm::vec3(-0.1, 0.5, 5.0), glm::vec3(0.0, 0.0, -1.0)).intersect_triangle(&a, &b, &c).is_none());
+        assert!(Ray::new(glm::vec3(0.25, 0.25, 5.0), glm::vec3(0.0, 0.0, 1.0)).intersect_triangle(&a, &b, &c).is_none());
+        assert!(Ray::new(glm::vec3(-1.0, 0.25, 0.0), glm::vec3(1.0, 0.0, 0.0)).intersect_triangle(&a, &b, &c).is_none());
+        assert!(Ray::new(glm::vec3(-1.0, 0.25, 1.0), glm::vec3(1.0, 0.0, 0.0)).intersect_triangle(&a, &b, &c).is_none());
+    }
+
+    #[test]
+    fn cursor_in_the_middle_looks_straight_ahead() {
+        let eye = glm::vec3(1.0, 2.0, 3.0);
+        let forward = glm::normalize(&glm::vec3(3.0, -1.0, -4.0));
+        let view = glm::look_at(&eye, &(eye + forward), &glm::vec3(0.0, 1.0, 0.0));
+        let projection = glm::perspective(800.0 / 600.0, 1.0, 0.5, 100.0);
+        let view_projection = projection * view;
+
+        let ray = Ray::from_cursor(400.0, 300.0, 800.0, 600.0, &view_projection);
+        assert!(glm::distance(&ray.direction, &forward) < 1e-4, "{}", ray.direction);
+        assert!(glm::distance(&ray.origin, &(eye + forward * 0.5)) < 1e-3, "{}", ray.origin);
+
+        // Towards the top left, the ray leans up and to the camera's left
+        let corner = Ray::from_cursor(0.0, 0.0, 800.0, 600.0, &view_projection);
+        let right = glm::normalize(&glm::cross(&forward, &glm::vec3(0.0, 1.0, 0.0)));
+        let up = glm::cross(&right, &forward);
+        assert!(glm::dot(&corner.direction, &right) < 0.0);
+        assert!(glm::dot(&corner.direction, &up) > 0.0);
+        assert!((glm::length(&corner.direction) - 1.0).abs() < 1e-5);
+    }
+
+    // A unit cube, drawable as far as picking is concerned
+    fn cube(name: &str) -> Node {
+        let mesh = fixtures::cube();
+        let mut node = SceneNode::named(name);
+        node.set_vao(placeholder_vao(mesh.index_count));
+        node.set_mesh(Rc::new(mesh));
+        node
+    }
+
+    #[test]
+    fn picks_the_nearer_node() {
+        let mut root = SceneNode::named("root");
+        let mut near = cube("near");
+        let mut far = cube("far");
+        near.position = glm::vec3(0.0, 0.0, -5.0);
+        far.position = glm::vec3(0.0, 0.0, -10.0);
+        // Added far first, so the order they're visited in doesn't decide it
+        root.add_child(&far);
+        root.add_child(&near);
+        root.update_world_matrices(&glm::identity(), false);
+
+        let hit = pick(&root, &Ray::new(glm::vec3(0.0, 0.0, 0.0), glm::vec3(0.0, 0.0, -1.0))).unwrap();
+        assert_eq!(hit.node.name.as_deref(), Some("near"));
+        assert!((hit.distance - 4.5).abs() < 1e-5, "{}", hit.distance);
+        assert!(glm::distance(&hit.point, &glm::vec3(0.0, 0.0, -4.5)) < 1e-5);
+
+        // Off to the side, only the far one is there
+        near.position.x = 3.0;
+        root.update_world_matrices(&glm::identity(), false);
+        let hit = pick(&root, &Ray::new(glm::vec3(0.0, 0.0, 0.0), glm::vec3(0.0, 0.0, -1.0))).unwrap();
+        assert_eq!(hit.node.name.as_deref(), Some("far"));
+
+        assert!(pick(&root, &Ray::new(glm::vec3(0.0, 0.0, 0.0), glm::vec3(0.0, 0.0, 1.0))).is_none());
+    }
+
+    #[test]
+    fn picks_through_the_node_transform() {
+        let mut root = SceneNode::named("root");
+        let mut turned = cube("turned");
+        // Twice as big, and turned so an edge faces the ray
+        turned.position = glm::vec3(5.0, 0.0, -10.0);
+        turned.scale = glm::vec3(2.0, 2.0, 2.0);
+        turned.rotation = glm::quat_angle_axis(std::f32::consts::FRAC_PI_4, &glm::vec3(0.0, 1.0, 0.0));
+        root.add_child(&turned);
+        root.update_world_matrices(&glm::identity(), false);
+
+        let hit = pick(&root, &Ray::new(glm::vec3(5.0, 0.5, 10.0), glm::vec3(0.0, 0.0, -1.0))).unwrap();
+        assert_eq!(hit.node.name.as_deref(), Some("turned"));
+        let edge = -10.0 + std::f32::consts::SQRT_2;
+        assert!((hit.distance - (10.0 - edge)).abs() < 1e-4, "{}", hit.distance);
+        assert!(glm::distance(&hit.point, &glm::vec3(5.0, 0.5, edge)) < 1e-4, "{}", hit.point);
+
+        // Where the unscaled cube would have ended, the bigger one is still there
+        assert!(pick(&root, &Ray::new(glm::vec3(5.0, 0.8, 10.0), glm::vec3(0.0, 0.0, -1.0))).is_some());
+        assert!(pick(&root, &Ray::new(glm::vec3(5.0, 1.2, 10.0), glm::vec3(0.0, 0.0, -1.0))).is_none());
+    }
+}
@@ -0,0 +1,231 @@
+// Helpers for the unit quaternions used for `SceneNode::rotation`.
+//
+// Euler angles are handy for typing in and for reading back, but compose in an order that is easy
+// to get wrong and lock up when the middle axis hits ±90°. So they are only used at the edges,
+// converted with an explicit `EulerOrder`, and all of the actual work happens on quaternions.
+
+// Which order the three axis rotations are applied in. `XYZ` means the matrix Rx * Ry * Rz, i.e.
+// rotating about Z first and X last in the parent's frame, or X first and Z last in the node's own.
+// The angles themselves are always given as (x, y, z), whatever the order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum EulerOrder {
+    XYZ,
+    XZY,
+    YXZ,
+    YZX,
+    ZXY,
+    ZYX,
+}
+
+impl EulerOrder {
+    // The axes as indices, outermost first
+    fn axes(self) -> [usize; 3] {
+        match self {
+            EulerOrder::XYZ => [0, 1, 2],
+            EulerOrder::XZY => [0, 2, 1],
+            EulerOrder::YXZ => [1, 0, 2],
+            EulerOrder::YZX => [1, 2, 0],
+            EulerOrder::ZXY => [2, 0, 1],
+            EulerOrder::ZYX => [2, 1, 0],
+        }
+    }
+}
+
+fn axis(index: usize) -> glm::Vec3 {
+    let mut axis = glm::Vec3::zeros();
+    axis[index] = 1.0;
+    axis
+}
+
+pub fn from_euler(angles: &glm::Vec3, order: EulerOrder) -> glm::Quat {
+    let [i, j, k] = order.axes();
+    glm::quat_angle_axis(angles[i], &axis(i))
+        * glm::quat_angle_axis(angles[j], &axis(j))
+        * glm::quat_angle_axis(angles[k], &axis(k))
+}
+
+// The angles which `from_euler` turns back into `rotation`. At gimbal lock only the sum of the
+// outer two is known, so the innermost angle is set to zero.
+pub fn to_euler(rotation: &glm::Quat, order: EulerOrder) -> glm::Vec3 {
+    let [i, j, k] = order.axes();
+    let m = glm::quat_to_mat3(&glm::quat_normalize(rotation));
+    // Rotating in cyclic order (XYZ, YZX, ZXY) flips the signs of the off-diagonal terms
+    let sign = if (j + 3 - i) % 3 == 1 { 1.0 } else { -1.0 };
+
+    let mut angles = glm::Vec3::zeros();
+    let sin_middle = (sign * m[(i, k)]).clamp(-1.0, 1.0);
+    angles[j] = sin_middle.asin();
+    if sin_middle.abs() < 0.9999 {
+        angles[i] = (-sign * m[(j, k)]).atan2(m[(k, k)]);
+        angles[k] = (-sign * m[(i, j)]).atan2(m[(i, i)]);
+    } else {
+        angles[i] = (sign * m[(k, j)]).atan2(m[(j, j)]);
+        angles[k] = 0.0;
+    }
+    angles
+}
+
+// Interpolate along the shorter of the two arcs between `from` and `to`
+pub fn slerp(from: &glm::Quat, to: &glm::Quat, t: f32) -> glm::Quat {
+    let to = if glm::quat_dot(from, to) < 0.0 { -to } else { *to };
+    glm::quat_slerp(from, &to, t)
+}
+
+// The rotation turning a node's forward direction (-Z, like the camera and the helicopter)
+// to point along `direction`, keeping its top towards `up`
+pub fn look_at(direction: &glm::Vec3, up: &glm::Vec3) -> glm::Quat {
+    let forward = glm::normalize(direction);
+    let mut right = glm::cross(&forward, up);
+    if glm::length(&right) <= f32::EPSILON {
+        // Looking straight up or down, so `up` says nothing about the roll. Any will do.
+        let other_up = if forward.x.abs() < 0.9 { glm::vec3(1.0, 0.0, 0.0) } else { glm::vec3(0.0, 0.0, 1.0) };
+        right = glm::cross(&forward, &other_up);
+    }
+    let right = glm::normalize(&right);
+    let top = glm::cross(&right, &forward);
+
+    // The node's X, Y and Z axes end up along these
+    let basis = glm::mat3(
+        right.x, top.x, -forward.x,
+        right.y, top.y, -forward.y,
+        right.z, top.z, -forward.z,
+    );
+    glm::to_quat(&glm::mat3_to_mat4(&basis))
+}
+
+// The angle, in radians, of the smallest rotation taking `from` to `to`
+pub fn angle_between(from: &glm::Quat, to: &glm::Quat) -> f32 {
+    2.0 * glm::quat_dot(from, to).abs().min(1.0).acos()
+}
+
+// Turn `from` towards `to` by at most `max_angle` radians, e.g. `turn_rate * delta_time`
+pub fn rotate_towards(from: &glm::Quat, to: &glm::Quat, max_angle: f32) -> glm::Quat {
+    let angle = angle_between(from, to);
+    if angle <= max_angle || angle <= f32::EPSILON {
+        return *to;
+    }
+    slerp(from, to, max_angle / angle)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+
+    const ORDERS: [EulerOrder; 6] = [
+        EulerOrder::XYZ, EulerOrder::XZY, EulerOrder::YXZ, EulerOrder::YZX, EulerOrder::ZXY, EulerOrder::ZYX,
+    ];
+
+    // q and -q are the same rotation
+    fn same_rotation(a: &glm::Quat, b: &glm::Quat) -> bool {
+        glm::quat_dot(a, b).abs() > 1.0 - 1e-6
+    }
+
+    fn close(a: &glm::Vec3, b: &glm::Vec3, tolerance: f32) -> bool {
+        glm::distance(a, b) < tolerance
+    }
+
+    #[test]
+    fn euler_angles_survive_a_round_trip() {
+        let angles = [
+            glm::vec3(0.3, -0.7, 1.1),
+            glm::vec3(-2.5, 1.2, -0.4),
+            glm::vec3(3.0, 0.0, -3.0),
+            glm::vec3(0.1, FRAC_PI_2 - 0.02, 0.2), // Close to gimbal lock, but not yet there
+        ];
+        for order in ORDERS {
+            for angles in &angles {
+                // The angles are given as (x, y, z), so the middle one depends on the order
+                let [_, j, _] = order.axes();
+                let mut angles = *angles;
+                angles.swap_rows(1, j);
+
+                let back = to_euler(&from_euler(&angles, order), order);
+                assert!(close(&back, &angles, 1e-3), "{:?}: {:?} came back as {:?}", order, angles, back);
+            }
+        }
+    }
+
+    #[test]
+    fn gimbal_lock_keeps_the_rotation() {
+        for order in ORDERS {
+            let [i, j, k] = order.axes();
+            for middle in [FRAC_PI_2, -FRAC_PI_2] {
+                let mut angles = glm::Vec3::zeros();
+                angles[i] = 0.4;
+                angles[j] = middle;
+                angles[k] = -0.9;
+                let rotation = from_euler(&angles, order);
+
+                let back = to_euler(&rotation, order);
+                assert_eq!(back[k], 0.0, "{:?}", order);
+                assert!(same_rotation(&from_euler(&back, order), &rotation), "{:?}: {:?} came back as {:?}", order, angles, back);
+            }
+        }
+    }
+
+    #[test]
+    fn from_euler_applies_the_outermost_axis_last() {
+        // Yaw a quarter turn, then pitch up: forward ends up pointing left and up
+        let rotation = from_euler(&glm::vec3(FRAC_PI_4, FRAC_PI_2, 0.0), EulerOrder::YXZ);
+        let forward = glm::quat_rotate_vec3(&rotation, &glm::vec3(0.0, 0.0, -1.0));
+        let diagonal = std::f32::consts::FRAC_1_SQRT_2;
+        assert!(close(&forward, &glm::vec3(-diagonal, diagonal, 0.0), 1e-5), "{:?}", forward);
+    }
+
+    #[test]
+    fn slerp_takes_the_short_way() {
+        let from = glm::quat_identity();
+        let quarter = glm::quat_angle_axis(FRAC_PI_2, &glm::vec3(0.0, 1.0, 0.0));
+        let eighth = glm::quat_angle_axis(FRAC_PI_4, &glm::vec3(0.0, 1.0, 0.0));
+        // Both signs of the target mean the same rotation, and should give the same path
+        for to in [quarter, -quarter] {
+            assert!(same_rotation(&slerp(&from, &to, 0.0), &from));
+            assert!(same_rotation(&slerp(&from, &to, 0.5), &eighth));
+            assert!(same_rotation(&slerp(&from, &to, 1.0), &quarter));
+        }
+    }
+
+    #[test]
+    fn look_at_points_forward_along_the_direction() {
+        let up = glm::vec3(0.0, 1.0, 0.0);
+        for direction in [glm::vec3(1.0, 0.0, 0.0), glm::vec3(0.0, 0.0, 1.0), glm::vec3(-2.0, 3.0, 1.0)] {
+            let rotation = look_at(&direction, &up);
+            let forward = glm::quat_rotate_vec3(&rotation, &glm::vec3(0.0, 0.0, -1.0));
+            let right = glm::quat_rotate_vec3(&rotation, &glm::vec3(1.0, 0.0, 0.0));
+            let top = glm::quat_rotate_vec3(&rotation, &glm::vec3(0.0, 1.0, 0.0));
+            assert!(close(&forward, &glm::normalize(&direction), 1e-5), "{:?} looked along {:?}", direction, forward);
+            // No roll: right stays level, and the top keeps facing up
+            assert!(right.y.abs() < 1e-5);
+            assert!(top.y > 0.0);
+        }
+
+        // Straight up, where `up` can't say which way the top goes
+        let rotation = look_at(&up, &up);
+        let forward = glm::quat_rotate_vec3(&rotation, &glm::vec3(0.0, 0.0, -1.0));
+        assert!(close(&forward, &up, 1e-5));
+    }
+
+    #[test]
+    fn angle_between_ignores_the_sign() {
+        let quarter = glm::quat_angle_axis(FRAC_PI_2, &glm::vec3(1.0, 0.0, 0.0));
+        assert!((angle_between(&glm::quat_identity(), &quarter) - FRAC_PI_2).abs() < 1e-5);
+        assert!((angle_between(&glm::quat_identity(), &-quarter) - FRAC_PI_2).abs() < 1e-5);
+        assert!(angle_between(&quarter, &-quarter) < 1e-3);
+    }
+
+    #[test]
+    fn rotate_towards_turns_at_most_max_angle() {
+        let from = glm::quat_identity();
+        let to = glm::quat_angle_axis(FRAC_PI_2, &glm::vec3(0.0, 0.0, 1.0));
+
+        let turned = rotate_towards(&from, &to, 0.1);
+        assert!((angle_between(&from, &turned) - 0.1).abs() < 1e-3);
+        assert!((angle_between(&turned, &to) - (FRAC_PI_2 - 0.1)).abs() < 1e-3);
+
+        // Close enough to get there in one step, so it lands exactly on the target, and stays there
+        assert_eq!(rotate_towards(&from, &to, 2.0), to);
+        assert!(same_rotation(&rotate_towards(&to, &to, 0.0), &to));
+    }
+}
@@ -1,7 +1,21 @@
 extern crate nalgebra_glm as glm;
 
+use std::cell::Cell;
 use std::mem::ManuallyDrop;
 use std::pin::Pin;
+use std::rc::Rc;
+
+use regex::Regex;
+
+pub mod traversal;
+
+use crate::bounds::Aabb;
+use crate::bvh::Bvh;
+use crate::gl_objects::Vao;
+use crate::mesh::Mesh;
+use crate::rotation::{self, EulerOrder};
+use crate::transform::Transform;
+use traversal::VisitorMut;
 
 // Used to create an unholy abomination upon which you should not cast your gaze. This ended up
 // being a necessity due to wanting to keep the code written by students as "straight forward" as
@@ -17,15 +31,27 @@ use std::pin::Pin;
 pub type Node = ManuallyDrop<Pin<Box<SceneNode>>>;
 
 pub struct SceneNode {
+    pub name            : Option<String>, // What scripts and tools can call me by, see `find`
     pub position        : glm::Vec3,   // Where I should be in relation to my parent
-    pub rotation        : glm::Vec3,   // How I should be rotated, around the X, the Y and the Z axes
+    pub rotation        : glm::Quat,   // How I should be rotated, as a unit quaternion
     pub scale           : glm::Vec3,   // How I should be scaled
     pub reference_point : glm::Vec3,   // The point I shall rotate and scale about
 
-    pub current_transformation_matrix : glm::Mat4,
+    pub current_transformation_matrix : glm::Mat4, // My world matrix, as of the last update
+
+    // Caches for `update_world_matrices`, so that nodes which haven't moved are left alone
+    local_matrix     : glm::Mat4,
+    cached_transform : Transform,   // What `local_matrix` was built from
+    world_inverse    : glm::Mat4,
+    world_bounds     : Option<Aabb>, // Around everything drawn by me and my children, None if unknown
+    parent_world     : glm::Mat4,   // What the last `update_world_matrices` called on me was given
+    dirty            : Cell<bool>,  // Forces an update, e.g. after being moved to a new parent
 
-    pub vao_id      : u32,             // What I should draw
+    pub vao         : Option<Rc<Vao>>, // What I should draw, shared with any other node drawing the same model
     pub index_count : i32,             // How much of it there is to draw
+    pub mesh        : Option<Rc<Mesh>>,// The same model on the CPU side, if kept around for exporting
+    pub local_bounds: Option<Aabb>,    // Around what I draw, in my own space. If unknown, I'm never culled.
+    pub bvh         : Option<Rc<Bvh>>, // For fast ray and overlap queries against my mesh, in my own space
 
     pub children: Vec<*mut SceneNode>, // Those I command
 }
@@ -34,34 +60,198 @@ impl SceneNode {
 
     pub fn new() -> Node {
         ManuallyDrop::new(Pin::new(Box::new(SceneNode {
+            name            : None,
             position        : glm::zero(),
-            rotation        : glm::zero(),
+            rotation        : glm::quat_identity(),
             scale           : glm::vec3(1.0, 1.0, 1.0),
             reference_point : glm::zero(),
             current_transformation_matrix: glm::identity(),
-            vao_id          : 0,
+            local_matrix     : glm::identity(),
+            cached_transform : Transform::identity(),
+            world_inverse    : glm::identity(),
+            world_bounds     : Some(Aabb::empty()),
+            parent_world     : glm::identity(),
+            dirty            : Cell::new(true),
+            vao             : None,
             index_count     : -1,
+            mesh            : None,
+            local_bounds    : None,
+            bvh             : None,
             children        : vec![],
         })))
     }
 
-    pub fn from_vao(vao_id: u32, index_count: i32) -> Node {
-        ManuallyDrop::new(Pin::new(Box::new(SceneNode {
-            position        : glm::zero(),
-            rotation        : glm::zero(),
-            current_transformation_matrix: glm::identity(),
-            scale           : glm::vec3(1.0, 1.0, 1.0),
-            reference_point : glm::zero(),
-            vao_id,
-            index_count,
-            children: vec![],
-        })))
+    pub fn from_vao(vao: Rc<Vao>) -> Node {
+        let mut node = SceneNode::new();
+        node.set_vao(vao);
+        node
+    }
+
+    pub fn named(name: &str) -> Node {
+        let mut node = SceneNode::new();
+        node.set_name(name);
+        node
+    }
+
+    pub fn set_name(&mut self, name: &str) {
+        self.name = Some(name.to_string());
+    }
+
+    fn has_name(&self, name: &str) -> bool {
+        self.name.as_deref() == Some(name)
+    }
+
+    // Swap in what this node draws, e.g. once the real model has finished loading in the background
+    pub fn set_vao(&mut self, vao: Rc<Vao>) {
+        self.index_count = vao.index_count();
+        self.vao = Some(vao);
+        self.mark_dirty();
+    }
+
+    // Keep the CPU copy of what this node draws, so the subtree can be exported later
+    pub fn set_mesh(&mut self, mesh: Rc<Mesh>) {
+        self.local_bounds = Some(mesh.bounding_box());
+        self.mesh = Some(mesh);
+        self.mark_dirty();
+    }
+
+    // Speed up picking, collision and height queries against this node's mesh. Must be built
+    // from the same mesh.
+    pub fn set_bvh(&mut self, bvh: Rc<Bvh>) {
+        self.bvh = Some(bvh);
+    }
+
+    // Stop drawing anything. The GPU memory is freed once no other node shares the VAO.
+    pub fn unload(&mut self) {
+        self.vao = None;
+        self.index_count = -1;
+        self.mesh = None;
+        self.local_bounds = None;
+        self.bvh = None;
+        self.mark_dirty();
+    }
+
+    pub fn is_drawable(&self) -> bool {
+        self.vao.is_some() && self.index_count > 0
+    }
+
+    // The meshes of this node and everything below it, each moved by its node's current
+    // transformation, so as they were posed in the last frame. Nodes without a mesh are skipped.
+    pub fn world_meshes(&self) -> Vec<Mesh> {
+        self.depth_first()
+            .filter_map(|visit| visit.node.mesh.as_ref().map(|mesh| mesh.transformed(&visit.node.current_transformation_matrix)))
+            .collect()
+    }
+
+    // The whole subtree as a single mesh, e.g. to export with `mesh::export`
+    pub fn flattened_mesh(&self) -> Mesh {
+        Mesh::merge(&self.world_meshes())
+    }
+
+    // Position, rotation, scale and reference point as one value
+    pub fn transform(&self) -> Transform {
+        Transform {
+            translation : self.position,
+            rotation    : self.rotation,
+            scale       : self.scale,
+            pivot       : self.reference_point,
+        }
+    }
+
+    pub fn set_transform(&mut self, transform: &Transform) {
+        self.position        = transform.translation;
+        self.rotation        = transform.rotation;
+        self.scale           = transform.scale;
+        self.reference_point = transform.pivot;
+    }
+
+    // Set the rotation from Euler angles around X, Y and Z, applied in the given order
+    pub fn set_euler(&mut self, angles: glm::Vec3, order: EulerOrder) {
+        self.rotation = rotation::from_euler(&angles, order);
+    }
+
+    pub fn euler(&self, order: EulerOrder) -> glm::Vec3 {
+        rotation::to_euler(&self.rotation, order)
+    }
+
+    // Turn so that the node's forward direction (-Z) points at `target`, given in the parent's space
+    pub fn look_at(&mut self, target: &glm::Vec3, up: &glm::Vec3) {
+        let direction = target - self.position;
+        if glm::length(&direction) > f32::EPSILON {
+            self.rotation = rotation::look_at(&direction, up);
+        }
+    }
+
+    // Turn towards `target` by at most `max_angle` radians, for smoothly tracking something
+    pub fn rotate_towards(&mut self, target: &glm::Quat, max_angle: f32) {
+        self.rotation = rotation::rotate_towards(&self.rotation, target, max_angle);
     }
 
     pub fn add_child(&mut self, child: &SceneNode) {
+        // The child's world matrix was relative to its old parent, if it had one
+        child.mark_dirty();
         self.children.push(child as *const SceneNode as *mut SceneNode)
     }
 
+    // Make the next `update_world_matrices` recompute this node and everything below it.
+    // Changing the transform fields is noticed without this.
+    pub fn mark_dirty(&self) {
+        self.dirty.set(true);
+    }
+
+    // Recompute the cached local and world matrices of this node and its subtree. Subtrees where
+    // neither the node nor any ancestor has changed since the last call are skipped. A different
+    // `parent_world` than last time counts as a change, as does `parent_changed`.
+    // Returns how many nodes had their world matrix recomputed.
+    pub fn update_world_matrices(&mut self, parent_world: &glm::Mat4, parent_changed: bool) -> usize {
+        let changed = parent_changed || *parent_world != self.parent_world;
+        self.parent_world = *parent_world;
+        let parent = ParentState { world: *parent_world, changed, subtree_changed: false };
+        let mut update = WorldMatrixUpdate { parents: vec![parent], updated: 0 };
+        self.accept_mut(&mut update);
+        update.updated
+    }
+
+    // The accessors below are as of the last `update_world_matrices`
+
+    pub fn local_matrix(&self) -> glm::Mat4 {
+        self.local_matrix
+    }
+
+    pub fn world_matrix(&self) -> glm::Mat4 {
+        self.current_transformation_matrix
+    }
+
+    // Where my origin is in the world
+    pub fn world_position(&self) -> glm::Vec3 {
+        self.current_transformation_matrix.column(3).xyz()
+    }
+
+    // Takes world coordinates into my own, e.g. for checking what's in front of me
+    pub fn world_to_local(&self) -> glm::Mat4 {
+        self.world_inverse
+    }
+
+    // A box in world space around everything drawn by this node and its children. None if some
+    // of it has no known bounds, so that it can't be culled.
+    pub fn world_bounds(&self) -> Option<Aabb> {
+        self.world_bounds
+    }
+
+    fn update_world_bounds(&mut self) {
+        let own = if !self.is_drawable() {
+            Some(Aabb::empty())
+        } else {
+            self.local_bounds.map(|bounds| bounds.transformed(&self.current_transformation_matrix))
+        };
+        self.world_bounds = self.children.iter().fold(own, |bounds, &child| {
+            match (bounds, unsafe { (*child).world_bounds }) {
+                (Some(bounds), Some(child_bounds)) => Some(bounds.union(&child_bounds)),
+                _ => None,
+            }
+        });
+    }
+
     #[allow(dead_code)]
     pub fn get_child(& mut self, index: usize) -> & mut SceneNode {
         unsafe {
@@ -69,6 +259,52 @@ impl SceneNode {
         }
     }
 
+    // Follow a path of names down from this node, e.g. "helicopter_3/main_rotor", where each part
+    // names one of the children of the node before it. Takes the first child with a matching name.
+    pub fn find(&mut self, path: &str) -> Option<&mut SceneNode> {
+        let mut node: *mut SceneNode = self;
+        for part in path.split('/').filter(|part| !part.is_empty()) {
+            node = unsafe { *(*node).children.iter().find(|&&child| (*child).has_name(part))? };
+        }
+        Some(unsafe { &mut *node })
+    }
+
+    // The first node anywhere below this one with the given name, searching depth first
+    pub fn find_first(&mut self, name: &str) -> Option<&mut SceneNode> {
+        self.depth_first_mut().skip(1).map(|visit| visit.node).find(|node| node.has_name(name))
+    }
+
+    // Every node below this one with the given name, in depth first order
+    pub fn find_all(&mut self, name: &str) -> Vec<&mut SceneNode> {
+        self.find_where(&|node| node.has_name(name))
+    }
+
+    // Every node below this one with a name matching `pattern`, e.g. `^helicopter_\d+$`
+    pub fn find_matching(&mut self, pattern: &Regex) -> Vec<&mut SceneNode> {
+        self.find_where(&|node| node.name.as_deref().is_some_and(|name| pattern.is_match(name)))
+    }
+
+    fn find_where(&mut self, predicate: &dyn Fn(&SceneNode) -> bool) -> Vec<&mut SceneNode> {
+        self.depth_first_mut().skip(1).map(|visit| visit.node).filter(|node| predicate(node)).collect()
+    }
+
+    // The path `find` would take from this node to `target`, if it's below this one and every
+    // node along the way has a name
+    pub fn path_to(&self, target: &SceneNode) -> Option<String> {
+        let visits: Vec<_> = self.depth_first().collect();
+        let mut index = visits.iter().position(|visit| std::ptr::eq(visit.node, target))?;
+        let mut names = vec![];
+        while let Some(parent) = visits[index].parent {
+            names.push(visits[index].node.name.as_deref()?);
+            index = parent;
+        }
+        if names.is_empty() {
+            return None; // `target` is this node
+        }
+        names.reverse();
+        Some(names.join("/"))
+    }
+
     #[allow(dead_code)]
     pub fn get_n_children(&self) -> usize {
         self.children.len()
@@ -78,22 +314,25 @@ impl SceneNode {
     pub fn print(&self) {
         println!(
 "SceneNode {{
+    Name:      {}
     VAO:       {}
     Indices:   {}
     Children:  {}
     Position:  [{:.2}, {:.2}, {:.2}]
-    Rotation:  [{:.2}, {:.2}, {:.2}]
+    Rotation:  [{:.2}, {:.2}, {:.2}, {:.2}]
     Reference: [{:.2}, {:.2}, {:.2}]
 }}",
-            self.vao_id,
+            self.name.as_deref().unwrap_or("-"),
+            self.vao.as_ref().map_or(0, |vao| vao.id()),
             self.index_count,
             self.children.len(),
             self.position.x,
             self.position.y,
             self.position.z,
-            self.rotation.x,
-            self.rotation.y,
-            self.rotation.z,
+            self.rotation.i,
+            self.rotation.j,
+            self.rotation.k,
+            self.rotation.w,
             self.reference_point.x,
             self.reference_point.y,
             self.reference_point.z,
@@ -103,6 +342,53 @@ impl SceneNode {
 }
 
 
+// Recomputes the cached matrices on the way down, and the bounds on the way back up, since
+// those need the children to be done first
+struct WorldMatrixUpdate {
+    parents : Vec<ParentState>,
+    updated : usize,
+}
+
+#[derive(Clone, Copy)]
+struct ParentState {
+    world           : glm::Mat4,
+    changed         : bool,   // Whether the world matrix changed this time
+    subtree_changed : bool,   // Whether anything below did, so the bounds need redoing
+}
+
+impl VisitorMut for WorldMatrixUpdate {
+    fn enter(&mut self, node: &mut SceneNode, _depth: usize) -> bool {
+        let parent = *self.parents.last().unwrap();
+
+        let transform = node.transform();
+        let local_changed = node.dirty.get() || transform != node.cached_transform;
+        if local_changed {
+            node.local_matrix = transform.to_matrix();
+            node.cached_transform = transform;
+        }
+        node.dirty.set(false);
+
+        let changed = local_changed || parent.changed;
+        if changed {
+            node.current_transformation_matrix = parent.world * node.local_matrix;
+            node.world_inverse = glm::inverse(&node.current_transformation_matrix);
+            self.updated += 1;
+        }
+        self.parents.push(ParentState { world: node.current_transformation_matrix, changed, subtree_changed: changed });
+        true
+    }
+
+    fn leave(&mut self, node: &mut SceneNode, _depth: usize) {
+        let state = self.parents.pop().unwrap();
+        if state.subtree_changed {
+            node.update_world_bounds();
+            if let Some(parent) = self.parents.last_mut() {
+                parent.subtree_changed = true;
+            }
+        }
+    }
+}
+
 // You can also use square brackets to access the children of a SceneNode
 use std::ops::{Index, IndexMut};
 impl Index<usize> for SceneNode {
@@ -120,3 +406,127 @@ impl IndexMut<usize> for SceneNode {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_moved_subtrees_are_updated() {
+        let mut root = SceneNode::named("root");
+        let mut a = SceneNode::named("a");
+        let b = SceneNode::named("b");
+        root.add_child(&a);
+        a.add_child(&b);
+
+        assert_eq!(root.update_world_matrices(&glm::identity(), false), 3);
+        assert_eq!(root.update_world_matrices(&glm::identity(), false), 0);
+        a.position = glm::vec3(1.0, 0.0, 0.0);
+        assert_eq!(root.update_world_matrices(&glm::identity(), false), 2);
+        assert_eq!(b.world_position(), glm::vec3(1.0, 0.0, 0.0));
+        assert_eq!(root.update_world_matrices(&glm::identity(), true), 3);
+    }
+
+    #[test]
+    fn a_new_parent_matrix_updates_everything() {
+        let mut root = SceneNode::named("root");
+        let child = SceneNode::named("child");
+        root.add_child(&child);
+        root.update_world_matrices(&glm::identity(), false);
+
+        let moved = glm::translation(&glm::vec3(0.0, 2.0, 0.0));
+        assert_eq!(root.update_world_matrices(&moved, false), 2);
+        assert_eq!(child.world_position(), glm::vec3(0.0, 2.0, 0.0));
+        assert_eq!(root.update_world_matrices(&moved, false), 0);
+    }
+
+    // Two helicopters, the first with its rotor on a body node, and a lamp under an unnamed node
+    struct Scene {
+        root      : Node,
+        rotor_1   : Node,
+        rotor_2   : Node,
+        tail      : Node,
+        unnamed   : Node,
+        lamp      : Node,
+    }
+
+    fn scene() -> Scene {
+        let mut root = SceneNode::named("root");
+        let mut helicopter_1 = SceneNode::named("helicopter_1");
+        let mut body = SceneNode::named("body");
+        let rotor_1 = SceneNode::named("main_rotor");
+        let mut helicopter_2 = SceneNode::named("helicopter_2");
+        let rotor_2 = SceneNode::named("main_rotor");
+        let tail = SceneNode::named("tail_rotor");
+        let mut unnamed = SceneNode::new();
+        let lamp = SceneNode::named("lamp");
+
+        root.add_child(&helicopter_1);
+        helicopter_1.add_child(&body);
+        body.add_child(&rotor_1);
+        root.add_child(&helicopter_2);
+        helicopter_2.add_child(&rotor_2);
+        helicopter_2.add_child(&tail);
+        root.add_child(&unnamed);
+        unnamed.add_child(&lamp);
+        Scene { root, rotor_1, rotor_2, tail, unnamed, lamp }
+    }
+
+    fn is(found: Option<&mut SceneNode>, node: &Node) -> bool {
+        found.is_some_and(|found| std::ptr::eq(found, &***node))
+    }
+
+    #[test]
+    fn find_follows_a_path_of_names() {
+        let mut scene = scene();
+        assert!(is(scene.root.find("helicopter_1/body/main_rotor"), &scene.rotor_1));
+        assert!(is(scene.root.find("helicopter_2/tail_rotor"), &scene.tail));
+        assert!(is(scene.root.find("/helicopter_2//main_rotor/"), &scene.rotor_2));
+        assert!(scene.root.find("helicopter_1/main_rotor").is_none());
+        assert!(scene.root.find("helicopter_3/main_rotor").is_none());
+        assert!(scene.root.find("lamp").is_none());
+    }
+
+    #[test]
+    fn find_first_and_find_all_search_the_whole_subtree() {
+        let mut scene = scene();
+        assert!(is(scene.root.find_first("main_rotor"), &scene.rotor_1));
+        assert!(is(scene.root.find_first("lamp"), &scene.lamp));
+        assert!(scene.root.find_first("root").is_none());
+
+        let rotors: Vec<*const SceneNode> = scene.root.find_all("main_rotor").into_iter()
+            .map(|node| node as *const SceneNode)
+            .collect();
+        assert_eq!(rotors, vec![&**scene.rotor_1 as *const SceneNode, &**scene.rotor_2]);
+        assert!(scene.root.find_all("cockpit").is_empty());
+    }
+
+    #[test]
+    fn find_matching_takes_a_regex() {
+        let mut scene = scene();
+        let pattern = Regex::new(r"^helicopter_\d+$").unwrap();
+        let names: Vec<_> = scene.root.find_matching(&pattern).into_iter()
+            .map(|node| node.name.clone().unwrap())
+            .collect();
+        assert_eq!(names, vec!["helicopter_1", "helicopter_2"]);
+
+        let rotors = Regex::new("rotor").unwrap();
+        assert_eq!(scene.root.find_matching(&rotors).len(), 3);
+    }
+
+    #[test]
+    fn path_to_leads_back_through_find() {
+        let mut scene = scene();
+        for node in [&scene.rotor_1, &scene.rotor_2, &scene.tail] {
+            let path = scene.root.path_to(node).unwrap();
+            assert!(is(scene.root.find(&path), node));
+        }
+        assert_eq!(scene.root.path_to(&scene.rotor_1).as_deref(), Some("helicopter_1/body/main_rotor"));
+
+        // The lamp can't be reached by name, and neither can nodes outside the tree or the root itself
+        assert_eq!(scene.root.path_to(&scene.lamp), None);
+        assert_eq!(scene.root.path_to(&scene.unnamed), None);
+        assert_eq!(scene.root.path_to(&SceneNode::named("main_rotor")), None);
+        assert_eq!(scene.root.path_to(&scene.root), None);
+    }
+}
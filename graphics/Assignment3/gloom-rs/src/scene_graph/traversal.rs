@@ -0,0 +1,328 @@
+use std::collections::VecDeque;
+
+use super::SceneNode;
+
+// Ways of walking a scene graph without writing yet another recursion over raw child pointers.
+//
+//  * `depth_first` / `breadth_first` and their `_mut` variants are iterators over every node
+//    in a subtree, the node they're called on included, telling the depth and parent of each.
+//  * `accept` / `accept_mut` drive a `Visitor`, which gets told both when a node is entered and
+//    when it's left again, and can skip a whole subtree, e.g. when it's outside the view.
+//
+// These all assume the graph is a tree, as a node reachable along two paths would be handed out
+// twice, which for the mutable versions means two `&mut` to the same node.
+
+// A node as seen from an iterator. Nodes are numbered in the order they're visited, starting at
+// 0 for the node the walk started from, and `parent` refers to that number.
+pub struct Visit<'a> {
+    pub node        : &'a SceneNode,
+    pub depth       : usize,               // 0 for the node the walk started from
+    pub index       : usize,
+    pub parent      : Option<usize>,       // The index of my parent's visit, None for the first node
+    pub parent_node : Option<&'a SceneNode>,
+}
+
+// The same for the mutable iterators. The parent itself can't be handed out at the same time as
+// its child, so it's only referred to by index.
+pub struct VisitMut<'a> {
+    pub node   : &'a mut SceneNode,
+    pub depth  : usize,
+    pub index  : usize,
+    pub parent : Option<usize>,
+}
+
+// A node waiting to be visited
+struct Pending {
+    node   : *mut SceneNode,
+    depth  : usize,
+    parent : Option<usize>,
+}
+
+// The bookkeeping shared by all four iterators. Children are read off a node before it's handed
+// out, so a visitor adding or removing children only affects the walk from the next node on.
+struct Walk {
+    pending       : VecDeque<Pending>,
+    depth_first   : bool,
+    visited       : usize,
+    parent_nodes  : Vec<*mut SceneNode>, // The node for each index handed out so far
+}
+
+impl Walk {
+    fn new(root: *mut SceneNode, depth_first: bool) -> Self {
+        let mut pending = VecDeque::new();
+        pending.push_back(Pending { node: root, depth: 0, parent: None });
+        Walk { pending, depth_first, visited: 0, parent_nodes: vec![] }
+    }
+
+    fn next(&mut self) -> Option<(Pending, usize)> {
+        let current = if self.depth_first { self.pending.pop_back()? } else { self.pending.pop_front()? };
+        let index = self.visited;
+        self.visited += 1;
+        self.parent_nodes.push(current.node);
+
+        let children = unsafe { &(*current.node).children };
+        let child = |&node: &*mut SceneNode| Pending { node, depth: current.depth + 1, parent: Some(index) };
+        if self.depth_first {
+            // Reversed, so the first child comes off the back of the stack first
+            self.pending.extend(children.iter().rev().map(child));
+        } else {
+            self.pending.extend(children.iter().map(child));
+        }
+        Some((current, index))
+    }
+}
+
+pub struct Nodes<'a> {
+    walk  : Walk,
+    _root : std::marker::PhantomData<&'a SceneNode>,
+}
+
+impl<'a> Iterator for Nodes<'a> {
+    type Item = Visit<'a>;
+
+    fn next(&mut self) -> Option<Visit<'a>> {
+        let (pending, index) = self.walk.next()?;
+        Some(Visit {
+            node        : unsafe { &*pending.node },
+            depth       : pending.depth,
+            index,
+            parent      : pending.parent,
+            parent_node : pending.parent.map(|parent| unsafe { &*self.walk.parent_nodes[parent] }),
+        })
+    }
+}
+
+pub struct NodesMut<'a> {
+    walk  : Walk,
+    _root : std::marker::PhantomData<&'a mut SceneNode>,
+}
+
+impl<'a> Iterator for NodesMut<'a> {
+    type Item = VisitMut<'a>;
+
+    fn next(&mut self) -> Option<VisitMut<'a>> {
+        let (pending, index) = self.walk.next()?;
+        Some(VisitMut {
+            node   : unsafe { &mut *pending.node },
+            depth  : pending.depth,
+            index,
+            parent : pending.parent,
+        })
+    }
+}
+
+pub trait Visitor {
+    // Called before any of the node's children. Return false to skip the children.
+    fn enter(&mut self, node: &SceneNode, depth: usize) -> bool;
+
+    // Called after all of the node's children, or right after `enter` if they were skipped
+    fn leave(&mut self, _node: &SceneNode, _depth: usize) {}
+}
+
+pub trait VisitorMut {
+    fn enter(&mut self, node: &mut SceneNode, depth: usize) -> bool;
+
+    fn leave(&mut self, _node: &mut SceneNode, _depth: usize) {}
+}
+
+impl SceneNode {
+    // Parents before children, each child's whole subtree before the next child
+    pub fn depth_first(&self) -> Nodes<'_> {
+        Nodes { walk: Walk::new(self as *const SceneNode as *mut SceneNode, true), _root: std::marker::PhantomData }
+    }
+
+    // All nodes at one depth before any at the next
+    pub fn breadth_first(&self) -> Nodes<'_> {
+        Nodes { walk: Walk::new(self as *const SceneNode as *mut SceneNode, false), _root: std::marker::PhantomData }
+    }
+
+    pub fn depth_first_mut(&mut self) -> NodesMut<'_> {
+        NodesMut { walk: Walk::new(self, true), _root: std::marker::PhantomData }
+    }
+
+    pub fn breadth_first_mut(&mut self) -> NodesMut<'_> {
+        NodesMut { walk: Walk::new(self, false), _root: std::marker::PhantomData }
+    }
+
+    pub fn accept(&self, visitor: &mut dyn Visitor) {
+        self.accept_at(visitor, 0);
+    }
+
+    fn accept_at(&self, visitor: &mut dyn Visitor, depth: usize) {
+        if visitor.enter(self, depth) {
+            for &child in &self.children {
+                unsafe { (*child).accept_at(visitor, depth + 1) };
+            }
+        }
+        visitor.leave(self, depth);
+    }
+
+    pub fn accept_mut(&mut self, visitor: &mut dyn VisitorMut) {
+        self.accept_mut_at(visitor, 0);
+    }
+
+    fn accept_mut_at(&mut self, visitor: &mut dyn VisitorMut, depth: usize) {
+        if visitor.enter(self, depth) {
+            // Copied, in case the visitor changes the children while we're going through them
+            let children = self.children.clone();
+            for child in children {
+                unsafe { (*child).accept_mut_at(visitor, depth + 1) };
+            }
+        }
+        visitor.leave(self, depth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scene_graph::Node;
+
+    // root
+    //  ├ a
+    //  │ ├ c
+    //  │ └ d
+    //  └ b
+    //    └ e
+    fn tree() -> (Node, Vec<Node>) {
+        let mut root = SceneNode::named("root");
+        let mut a = SceneNode::named("a");
+        let mut b = SceneNode::named("b");
+        let c = SceneNode::named("c");
+        let d = SceneNode::named("d");
+        let e = SceneNode::named("e");
+        root.add_child(&a);
+        root.add_child(&b);
+        a.add_child(&c);
+        a.add_child(&d);
+        b.add_child(&e);
+        (root, vec![a, b, c, d, e])
+    }
+
+    fn name(node: &SceneNode) -> &str {
+        node.name.as_deref().unwrap()
+    }
+
+    #[test]
+    fn depth_first_finishes_each_subtree_first() {
+        let (root, _nodes) = tree();
+        let visits: Vec<_> = root.depth_first()
+            .map(|visit| (name(visit.node), visit.depth, visit.index, visit.parent, visit.parent_node.map(name)))
+            .collect();
+        assert_eq!(visits, vec![
+            ("root", 0, 0, None,    None),
+            ("a",    1, 1, Some(0), Some("root")),
+            ("c",    2, 2, Some(1), Some("a")),
+            ("d",    2, 3, Some(1), Some("a")),
+            ("b",    1, 4, Some(0), Some("root")),
+            ("e",    2, 5, Some(4), Some("b")),
+        ]);
+    }
+
+    #[test]
+    fn breadth_first_goes_a_level_at_a_time() {
+        let (root, _nodes) = tree();
+        let visits: Vec<_> = root.breadth_first()
+            .map(|visit| (name(visit.node), visit.depth, visit.index, visit.parent, visit.parent_node.map(name)))
+            .collect();
+        assert_eq!(visits, vec![
+            ("root", 0, 0, None,    None),
+            ("a",    1, 1, Some(0), Some("root")),
+            ("b",    1, 2, Some(0), Some("root")),
+            ("c",    2, 3, Some(1), Some("a")),
+            ("d",    2, 4, Some(1), Some("a")),
+            ("e",    2, 5, Some(2), Some("b")),
+        ]);
+    }
+
+    #[test]
+    fn a_walk_can_start_below_the_root() {
+        let (_root, nodes) = tree();
+        let visits: Vec<_> = nodes[0].depth_first()
+            .map(|visit| (name(visit.node), visit.depth, visit.parent))
+            .collect();
+        assert_eq!(visits, vec![("a", 0, None), ("c", 1, Some(0)), ("d", 1, Some(0))]);
+    }
+
+    #[test]
+    fn mutable_walks_reach_every_node() {
+        let (mut root, nodes) = tree();
+        for visit in root.depth_first_mut() {
+            visit.node.scale = glm::vec3(2.0, 2.0, 2.0);
+        }
+        for visit in root.breadth_first_mut() {
+            visit.node.position.y = visit.depth as f32;
+        }
+        assert_eq!(root.scale, glm::vec3(2.0, 2.0, 2.0));
+        assert_eq!(root.position.y, 0.0);
+        for (node, depth) in nodes.iter().zip([1.0, 1.0, 2.0, 2.0, 2.0]) {
+            assert_eq!(node.scale, glm::vec3(2.0, 2.0, 2.0));
+            assert_eq!(node.position.y, depth);
+        }
+    }
+
+    // Writes down every call, and doesn't go into nodes named in `skip`
+    struct Recorder {
+        skip   : &'static str,
+        events : Vec<String>,
+    }
+
+    impl Visitor for Recorder {
+        fn enter(&mut self, node: &SceneNode, depth: usize) -> bool {
+            self.events.push(format!("enter {} {}", name(node), depth));
+            name(node) != self.skip
+        }
+
+        fn leave(&mut self, node: &SceneNode, depth: usize) {
+            self.events.push(format!("leave {} {}", name(node), depth));
+        }
+    }
+
+    #[test]
+    fn visitors_are_told_when_nodes_are_entered_and_left() {
+        let (root, _nodes) = tree();
+        let mut recorder = Recorder { skip: "", events: vec![] };
+        root.accept(&mut recorder);
+        assert_eq!(recorder.events, vec![
+            "enter root 0", "enter a 1", "enter c 2", "leave c 2", "enter d 2", "leave d 2", "leave a 1",
+            "enter b 1", "enter e 2", "leave e 2", "leave b 1", "leave root 0",
+        ]);
+    }
+
+    #[test]
+    fn visitors_can_skip_a_subtree() {
+        let (root, _nodes) = tree();
+        let mut recorder = Recorder { skip: "a", events: vec![] };
+        root.accept(&mut recorder);
+        assert_eq!(recorder.events, vec![
+            "enter root 0", "enter a 1", "leave a 1",
+            "enter b 1", "enter e 2", "leave e 2", "leave b 1", "leave root 0",
+        ]);
+    }
+
+    // Moves every node it enters up by its depth, stops at "b", and counts the nodes it left
+    struct Lift {
+        left : usize,
+    }
+
+    impl VisitorMut for Lift {
+        fn enter(&mut self, node: &mut SceneNode, depth: usize) -> bool {
+            node.position.y += depth as f32;
+            node.name.as_deref() != Some("b")
+        }
+
+        fn leave(&mut self, _node: &mut SceneNode, _depth: usize) {
+            self.left += 1;
+        }
+    }
+
+    #[test]
+    fn mutable_visitors_change_the_nodes_they_enter() {
+        let (mut root, nodes) = tree();
+        let mut lift = Lift { left: 0 };
+        root.accept_mut(&mut lift);
+        assert_eq!(lift.left, 5);
+        let heights: Vec<f32> = nodes.iter().map(|node| node.position.y).collect();
+        assert_eq!(heights, vec![1.0, 1.0, 2.0, 2.0, 0.0]);
+    }
+}
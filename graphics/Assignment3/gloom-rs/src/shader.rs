@@ -1,4 +1,3 @@
-use gl;
 use std::{
     ptr,
     str,
@@ -6,12 +5,14 @@ use std::{
     path::Path,
 };
 
+use crate::gl_objects::Program;
+
 pub struct Shader {
-    pub program_id: u32,
+    pub program: Program,
 }
 
 pub struct ShaderBuilder {
-    program_id: u32,
+    program: Program,
     shaders: Vec::<u32>,
 }
 
@@ -28,17 +29,21 @@ impl Shader {
     // Make sure the shader is active before calling this
     pub unsafe fn get_uniform_location(&self, name: &str) -> i32 {
         let name_cstr = CString::new(name).expect("CString::new failed");
-        gl::GetUniformLocation(self.program_id, name_cstr.as_ptr())
+        gl::GetUniformLocation(self.program.id(), name_cstr.as_ptr())
     }
 
     pub unsafe fn activate(&self) {
-        gl::UseProgram(self.program_id);
+        gl::UseProgram(self.program.id());
+    }
+
+    pub fn program_id(&self) -> u32 {
+        self.program.id()
     }
 }
 
-impl Into<gl::types::GLenum> for ShaderType {
-    fn into(self) -> gl::types::GLenum {
-        match self {
+impl From<ShaderType> for gl::types::GLenum {
+    fn from(shader_type: ShaderType) -> Self {
+        match shader_type {
             ShaderType::Vertex                  => { gl::VERTEX_SHADER          },
             ShaderType::Fragment                => { gl::FRAGMENT_SHADER        },
             ShaderType::TessellationControl     => { gl::TESS_CONTROL_SHADER    },
@@ -64,7 +69,7 @@ impl ShaderType {
 impl ShaderBuilder {
     pub unsafe fn new() -> ShaderBuilder {
         ShaderBuilder {
-            program_id: gl::CreateProgram(),
+            program: Program::new(),
             shaders: vec![],
         }
     }
@@ -75,7 +80,7 @@ impl ShaderBuilder {
             let shader_type = ShaderType::from_ext(extension)
                 .expect("Failed to parse file extension.");
             let shader_src = std::fs::read_to_string(path)
-                .expect(&format!("Failed to read shader source. {}", shader_path));
+                .unwrap_or_else(|_| panic!("Failed to read shader source. {}", shader_path));
             self.compile_shader(&shader_src, shader_type)
         } else {
             panic!("Failed to read extension of file with path: {}", shader_path);
@@ -99,8 +104,7 @@ impl ShaderBuilder {
 
     unsafe fn check_shader_errors(&self, shader_id: u32) -> bool {
         let mut success = i32::from(gl::FALSE);
-        let mut info_log = Vec::with_capacity(512);
-        info_log.set_len(512 - 1);
+        let mut info_log = vec![0u8; 512];
         gl::GetShaderiv(shader_id, gl::COMPILE_STATUS, &mut success);
         if success != i32::from(gl::TRUE) {
             gl::GetShaderInfoLog(
@@ -117,12 +121,11 @@ impl ShaderBuilder {
 
     unsafe fn check_linker_errors(&self) -> bool {
         let mut success = i32::from(gl::FALSE);
-        let mut info_log = Vec::with_capacity(512);
-        info_log.set_len(512 - 1);
-        gl::GetProgramiv(self.program_id, gl::LINK_STATUS, &mut success);
+        let mut info_log = vec![0u8; 512];
+        gl::GetProgramiv(self.program.id(), gl::LINK_STATUS, &mut success);
         if success != i32::from(gl::TRUE) {
             gl::GetProgramInfoLog(
-                self.program_id,
+                self.program.id(),
                 512,
                 ptr::null_mut(),
                 info_log.as_mut_ptr() as *mut gl::types::GLchar,
@@ -136,9 +139,9 @@ impl ShaderBuilder {
     #[must_use = "The shader program is useless if not stored in a variable."]
     pub unsafe fn link(self) -> Shader {
         for &shader in &self.shaders {
-            gl::AttachShader(self.program_id, shader);
+            gl::AttachShader(self.program.id(), shader);
         }
-        gl::LinkProgram(self.program_id);
+        gl::LinkProgram(self.program.id());
 
         // todo:: use this to make safer abstraction
         self.check_linker_errors();
@@ -148,7 +151,7 @@ impl ShaderBuilder {
         }
 
         Shader {
-            program_id: self.program_id
+            program: self.program
         }
     }
 }
@@ -1,6 +1,8 @@
 extern crate nalgebra_glm as glm;
 use std::f64::consts::PI;
 
+use crate::rotation::{self, EulerOrder};
+
 pub struct Heading {
     pub x     : f32,
     pub z     : f32,
@@ -9,6 +11,14 @@ pub struct Heading {
     pub yaw   : f32, // measured in radians
 }
 
+impl Heading {
+    // The orientation for the helicopter's `SceneNode::rotation`: yaw around the world's up axis
+    // first, then pitch the nose around the helicopter's own sideways axis, and roll last
+    pub fn rotation(&self) -> glm::Quat {
+        rotation::from_euler(&glm::vec3(self.pitch, self.yaw, self.roll), EulerOrder::YXZ)
+    }
+}
+
 pub fn simple_heading_animation(time: f32) -> Heading {
     let t             = time as f64;
     let step          = 0.05f64;
@@ -34,3 +44,310 @@ pub fn simple_heading_animation(time: f32) -> Heading {
         yaw   : yaw   as f32,
     }
 }
+
+// Designer authored routes, as smooth curves through a list of points. Positions along a path are
+// given by distance travelled rather than by the spline's own parameter, so that anything
+// following it at a constant speed really does go at that speed, however the points are spaced.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SplineKind {
+    CatmullRom,  // Through every point, with uniform spacing. Can overshoot and loop where points bunch up.
+    Centripetal, // Through every point, with the spacing following the distances. No cusps or loops.
+    Bezier,      // Cubic: point, two control points, point, two control points, and so on
+}
+
+pub struct FlightPath {
+    pub kind     : SplineKind,
+    pub points   : Vec<glm::Vec3>,
+    pub closed   : bool,   // Whether the end joins back up with the start
+    pub max_bank : f32,    // In radians
+    pub lean     : f32,    // How far the nose is tipped down when moving, in radians
+    arc_lengths  : Vec<f32>, // Distance along the path at each of `SAMPLES_PER_SEGMENT` steps of the parameter
+}
+
+const SAMPLES_PER_SEGMENT: usize = 32;
+
+// Banking is worked out as if the turns were made on Earth. With the moon's weaker gravity, every
+// turn would have the helicopter on its side.
+const BANK_GRAVITY: f32 = 9.81;
+
+impl FlightPath {
+    pub fn new(kind: SplineKind, points: Vec<glm::Vec3>, closed: bool) -> Result<Self, String> {
+        let enough = match (kind, closed) {
+            (SplineKind::Bezier, false) => points.len() >= 4 && (points.len() - 1).is_multiple_of(3),
+            (SplineKind::Bezier, true) => points.len() >= 3 && points.len().is_multiple_of(3),
+            _ => points.len() >= 2,
+        };
+        if !enough {
+            return Err(match kind {
+                SplineKind::Bezier => format!("{} Bézier path needs {} points, not {}",
+                    if closed { "a closed" } else { "an open" },
+                    if closed { "a multiple of 3" } else { "3n + 1" },
+                    points.len()),
+                _ => format!("a path needs at least 2 points, not {}", points.len()),
+            });
+        }
+
+        let mut path = FlightPath { kind, points, closed, max_bank: 0.6, lean: 0.15, arc_lengths: vec![] };
+        let samples = path.segments() * SAMPLES_PER_SEGMENT;
+        let mut length = 0.0;
+        let mut previous = path.point_at(0.0);
+        path.arc_lengths.push(0.0);
+        for i in 1..=samples {
+            let point = path.point_at(i as f32 / SAMPLES_PER_SEGMENT as f32);
+            length += glm::distance(&previous, &point);
+            path.arc_lengths.push(length);
+            previous = point;
+        }
+        Ok(path)
+    }
+
+    // A text file with one thing per line:
+    //
+    //     spline centripetal    (or catmull-rom, or bezier)
+    //     closed                (if the path should loop)
+    //     point 0 10 -20        (as many as it takes, in order)
+    //
+    // Blank lines and lines starting with # are skipped. None if there's no such file.
+    pub fn load(path: &str) -> Result<Option<Self>, String> {
+        let text = match std::fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("{}: {}", path, e)),
+        };
+        FlightPath::parse(&text).map(Some).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let mut kind = SplineKind::CatmullRom;
+        let mut closed = false;
+        let mut points = vec![];
+        for (number, line) in text.lines().enumerate() {
+            let mut words = line.split_whitespace();
+            match words.next() {
+                None => {}
+                Some(word) if word.starts_with('#') => {}
+                Some("spline") => {
+                    kind = match words.next() {
+                        Some("catmull-rom") => SplineKind::CatmullRom,
+                        Some("centripetal") => SplineKind::Centripetal,
+                        Some("bezier") => SplineKind::Bezier,
+                        other => return Err(format!("line {}: unknown spline `{}`", number + 1, other.unwrap_or(""))),
+                    };
+                }
+                Some("closed") => closed = true,
+                Some("point") => {
+                    let coordinates = words
+                        .map(|word| word.parse::<f32>().map_err(|_| format!("line {}: `{}` is not a number", number + 1, word)))
+                        .collect::<Result<Vec<f32>, String>>()?;
+                    if coordinates.len() != 3 {
+                        return Err(format!("line {}: a point needs 3 coordinates, not {}", number + 1, coordinates.len()));
+                    }
+                    points.push(glm::make_vec3(&coordinates));
+                }
+                Some(other) => return Err(format!("line {}: unknown keyword `{}`", number + 1, other)),
+            }
+        }
+        FlightPath::new(kind, points, closed)
+    }
+
+    // How many curves the path is made of
+    fn segments(&self) -> usize {
+        let n = self.points.len();
+        match (self.kind, self.closed) {
+            (SplineKind::Bezier, false) => (n - 1) / 3,
+            (SplineKind::Bezier, true) => n / 3,
+            (_, false) => n - 1,
+            (_, true) => n,
+        }
+    }
+
+    fn point(&self, i: isize) -> glm::Vec3 {
+        let n = self.points.len() as isize;
+        if self.closed {
+            return self.points[i.rem_euclid(n) as usize];
+        }
+        // Open ends are extended by mirroring the neighbouring point, so the curve runs straight out of them
+        match i {
+            -1 => 2.0 * self.points[0] - self.points[1],
+            i if i == n => 2.0 * self.points[(n - 1) as usize] - self.points[(n - 2) as usize],
+            i => self.points[i as usize],
+        }
+    }
+
+    // The point at `u` along the spline's own parameter, with each whole number a new segment
+    fn point_at(&self, u: f32) -> glm::Vec3 {
+        let segments = self.segments();
+        let segment = (u.floor().max(0.0) as usize).min(segments - 1);
+        let t = u - segment as f32;
+        let i = segment as isize;
+
+        match self.kind {
+            SplineKind::Bezier => {
+                let (p0, p1, p2, p3) = (self.point(3 * i), self.point(3 * i + 1), self.point(3 * i + 2), self.point(3 * i + 3));
+                let s = 1.0 - t;
+                p0 * (s * s * s) + p1 * (3.0 * s * s * t) + p2 * (3.0 * s * t * t) + p3 * (t * t * t)
+            }
+            SplineKind::CatmullRom => {
+                let (p0, p1, p2, p3) = (self.point(i - 1), self.point(i), self.point(i + 1), self.point(i + 2));
+                let (t2, t3) = (t * t, t * t * t);
+                (p1 * 2.0 + (p2 - p0) * t + (p0 * 2.0 - p1 * 5.0 + p2 * 4.0 - p3) * t2 + (-p0 + p1 * 3.0 - p2 * 3.0 + p3) * t3) * 0.5
+            }
+            SplineKind::Centripetal => {
+                // Barry and Goldman's pyramid, with the knots spaced by the square root of the distances
+                let p = [self.point(i - 1), self.point(i), self.point(i + 1), self.point(i + 2)];
+                let knot = |a: &glm::Vec3, b: &glm::Vec3| glm::distance(a, b).sqrt().max(1e-4);
+                let t0 = 0.0;
+                let t1 = t0 + knot(&p[0], &p[1]);
+                let t2 = t1 + knot(&p[1], &p[2]);
+                let t3 = t2 + knot(&p[2], &p[3]);
+                let t = t1 + (t2 - t1) * t;
+                let mix = |a: &glm::Vec3, b: &glm::Vec3, ta: f32, tb: f32| a * ((tb - t) / (tb - ta)) + b * ((t - ta) / (tb - ta));
+                let a1 = mix(&p[0], &p[1], t0, t1);
+                let a2 = mix(&p[1], &p[2], t1, t2);
+                let a3 = mix(&p[2], &p[3], t2, t3);
+                let b1 = mix(&a1, &a2, t0, t2);
+                let b2 = mix(&a2, &a3, t1, t3);
+                mix(&b1, &b2, t1, t2)
+            }
+        }
+    }
+
+    pub fn length(&self) -> f32 {
+        self.arc_lengths.last().cloned().unwrap_or(0.0)
+    }
+
+    // The spline's own parameter at `distance` along the path. Closed paths go round again
+    // past the end, open ones stop there.
+    fn parameter_at(&self, distance: f32) -> f32 {
+        let length = self.length();
+        let distance = if self.closed && length > 0.0 { distance.rem_euclid(length) } else { distance.clamp(0.0, length) };
+        let next = self.arc_lengths.partition_point(|&d| d <= distance).clamp(1, self.arc_lengths.len() - 1);
+        let (before, after) = (self.arc_lengths[next - 1], self.arc_lengths[next]);
+        let s = if after > before { (distance - before) / (after - before) } else { 0.0 };
+        (next - 1) as f32 / SAMPLES_PER_SEGMENT as f32 + s / SAMPLES_PER_SEGMENT as f32
+    }
+
+    pub fn position_at(&self, distance: f32) -> glm::Vec3 {
+        self.point_at(self.parameter_at(distance))
+    }
+
+    // The direction of travel at `distance` along the path, of unit length
+    pub fn tangent_at(&self, distance: f32) -> glm::Vec3 {
+        let step = (self.length() / (self.arc_lengths.len() as f32 * 4.0)).max(1e-3);
+        let (behind, ahead) = if self.closed {
+            (distance - step, distance + step)
+        } else {
+            // Stay on the path, so the ends don't get a zero tangent
+            let distance = distance.clamp(step, (self.length() - step).max(step));
+            (distance - step, distance + step)
+        };
+        let direction = self.position_at(ahead) - self.position_at(behind);
+        if glm::length(&direction) > 0.0 { glm::normalize(&direction) } else { glm::vec3(0.0, 0.0, -1.0) }
+    }
+
+    // Where and how to fly `distance` along the path at `speed`, banking into the turns like an
+    // aircraft would, and pitching up and down with the climbs and descents
+    pub fn heading_at(&self, distance: f32, speed: f32) -> Heading {
+        let position = self.position_at(distance);
+        let tangent = self.tangent_at(distance);
+
+        // How sharply, and which way, the path is turning, seen from above
+        let step = 1.0;
+        let turn = glm::cross(&self.tangent_at(distance - step), &self.tangent_at(distance + step)).y / (2.0 * step);
+        let roll = (speed * speed * turn / BANK_GRAVITY).atan().clamp(-self.max_bank, self.max_bank);
+
+        let horizontal = (tangent.x * tangent.x + tangent.z * tangent.z).sqrt();
+        let pitch = tangent.y.atan2(horizontal) - if speed > 0.0 { self.lean } else { 0.0 };
+        let yaw = std::f32::consts::PI + tangent.x.atan2(tangent.z);
+
+        Heading { x: position.x, z: position.z, roll, pitch, yaw }
+    }
+
+    // The same, for something that set off along the path `time` seconds ago
+    pub fn heading_at_time(&self, time: f32, speed: f32) -> Heading {
+        self.heading_at(time * speed, speed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Bunched up in places and spread out in others, going up and down as well
+    fn uneven_points() -> Vec<glm::Vec3> {
+        vec![
+            glm::vec3(0.0, 10.0, 0.0),
+            glm::vec3(1.0, 10.0, 0.5),
+            glm::vec3(30.0, 14.0, -5.0),
+            glm::vec3(32.0, 12.0, -20.0),
+            glm::vec3(5.0, 10.0, -40.0),
+        ]
+    }
+
+    #[test]
+    fn catmull_rom_and_centripetal_go_through_every_point() {
+        for kind in [SplineKind::CatmullRom, SplineKind::Centripetal] {
+            for closed in [false, true] {
+                let path = FlightPath::new(kind, uneven_points(), closed).unwrap();
+                for (i, point) in path.points.iter().enumerate() {
+                    let along = path.position_at(path.arc_lengths[i * SAMPLES_PER_SEGMENT]);
+                    assert!(glm::distance(&along, point) < 1e-3, "{:?} closed: {} point {}: {}", kind, closed, i, along);
+                }
+                let end = if closed { path.points[0] } else { *path.points.last().unwrap() };
+                assert!(glm::distance(&path.position_at(path.length()), &end) < 1e-3, "{:?} closed: {}", kind, closed);
+            }
+        }
+    }
+
+    #[test]
+    fn positions_are_spaced_by_distance_travelled() {
+        let path = FlightPath::new(SplineKind::Centripetal, uneven_points(), false).unwrap();
+        let step = 0.5;
+        let steps = (path.length() / step) as usize;
+        for i in 0..steps {
+            let gap = glm::distance(&path.position_at(i as f32 * step), &path.position_at((i + 1) as f32 * step));
+            assert!((gap - step).abs() < 0.02 * step, "step {}: {}", i, gap);
+        }
+    }
+
+    #[test]
+    fn closed_paths_go_round_again_and_open_ones_stop() {
+        let closed = FlightPath::new(SplineKind::CatmullRom, uneven_points(), true).unwrap();
+        let a = closed.position_at(3.0);
+        let b = closed.position_at(3.0 + closed.length());
+        assert!(glm::distance(&a, &b) < 1e-3);
+
+        let open = FlightPath::new(SplineKind::CatmullRom, uneven_points(), false).unwrap();
+        let end = *open.points.last().unwrap();
+        assert!(glm::distance(&open.position_at(open.length() + 50.0), &end) < 1e-3);
+    }
+
+    #[test]
+    fn bezier_needs_the_right_number_of_points() {
+        let points = |n: usize| (0..n).map(|i| glm::vec3(i as f32, 0.0, 0.0)).collect::<Vec<_>>();
+        assert!(FlightPath::new(SplineKind::Bezier, points(4), false).is_ok());
+        assert!(FlightPath::new(SplineKind::Bezier, points(7), false).is_ok());
+        assert!(FlightPath::new(SplineKind::Bezier, points(5), false).is_err());
+        assert!(FlightPath::new(SplineKind::Bezier, points(6), true).is_ok());
+        assert!(FlightPath::new(SplineKind::Bezier, points(4), true).is_err());
+        assert!(FlightPath::new(SplineKind::CatmullRom, points(1), false).is_err());
+
+        // A Bézier path goes through every third point
+        let path = FlightPath::new(SplineKind::Bezier, uneven_points()[..4].to_vec(), false).unwrap();
+        assert!(glm::distance(&path.position_at(path.length()), &path.points[3]) < 1e-3);
+    }
+
+    #[test]
+    fn parses_routes() {
+        let path = FlightPath::parse("# Around the crater\nspline centripetal\nclosed\n\npoint 0 10 0\npoint 10 10 0\npoint 10 12 10").unwrap();
+        assert_eq!(path.kind, SplineKind::Centripetal);
+        assert!(path.closed);
+        assert_eq!(path.points[2], glm::vec3(10.0, 12.0, 10.0));
+
+        let error = FlightPath::parse("point 0 0 0\npoint 1 2").err().unwrap();
+        assert!(error.contains("line 2"), "{}", error);
+        assert!(FlightPath::parse("spline hermite").is_err());
+        assert!(FlightPath::load("./resources/no_such_route.txt").unwrap().is_none());
+    }
+}
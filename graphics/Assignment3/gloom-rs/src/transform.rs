@@ -0,0 +1,285 @@
+use crate::rotation;
+
+// A translation, rotation and scale about a pivot point, as a value that can be combined and
+// taken apart again without dealing in raw matrices. As a matrix, it's
+//
+//     translate(translation) * translate(pivot) * rotate(rotation) * scale(scale) * translate(-pivot)
+//
+// i.e. the object is scaled and rotated about its pivot, and then moved by `translation`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Transform {
+    pub translation : glm::Vec3,
+    pub rotation    : glm::Quat,   // A unit quaternion
+    pub scale       : glm::Vec3,
+    pub pivot       : glm::Vec3,   // The point rotated and scaled about, before translating
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Transform::identity()
+    }
+}
+
+impl Transform {
+    pub fn identity() -> Self {
+        Transform {
+            translation : glm::zero(),
+            rotation    : glm::quat_identity(),
+            scale       : glm::vec3(1.0, 1.0, 1.0),
+            pivot       : glm::zero(),
+        }
+    }
+
+    pub fn from_translation(translation: glm::Vec3) -> Self {
+        Transform { translation, ..Transform::identity() }
+    }
+
+    pub fn from_rotation(rotation: glm::Quat) -> Self {
+        Transform { rotation, ..Transform::identity() }
+    }
+
+    pub fn to_matrix(self) -> glm::Mat4 {
+        glm::translation(&(self.translation + self.pivot))
+            * glm::quat_to_mat4(&self.rotation)
+            * glm::scaling(&self.scale)
+            * glm::translation(&-self.pivot)
+    }
+
+    pub fn transform_point(&self, point: &glm::Vec3) -> glm::Vec3 {
+        glm::quat_rotate_vec3(&self.rotation, &(point - self.pivot).component_mul(&self.scale)) + self.pivot + self.translation
+    }
+
+    // Directions are rotated and scaled, but not moved
+    pub fn transform_vector(&self, vector: &glm::Vec3) -> glm::Vec3 {
+        glm::quat_rotate_vec3(&self.rotation, &vector.component_mul(&self.scale))
+    }
+
+    fn has_uniform_scale(&self) -> bool {
+        let epsilon = 1e-5 * self.scale.abs().max();
+        (self.scale.x - self.scale.y).abs() <= epsilon && (self.scale.x - self.scale.z).abs() <= epsilon
+    }
+
+    // The transform undoing this one, with the pivot folded into the translation. Only exact
+    // when the scale is the same along every axis, since undoing a non-uniform scale after a
+    // rotation is a shear, which a `Transform` can't hold. Use `inverse_matrix` for those.
+    pub fn inverse(&self) -> Self {
+        let rotation = glm::quat_inverse(&self.rotation);
+        let scale = glm::vec3(1.0 / self.scale.x, 1.0 / self.scale.y, 1.0 / self.scale.z);
+        let undo = |v: &glm::Vec3| glm::quat_rotate_vec3(&rotation, v).component_mul(&scale);
+        Transform {
+            translation : self.pivot - undo(&(self.pivot + self.translation)),
+            rotation,
+            scale,
+            pivot       : glm::zero(),
+        }
+    }
+
+    pub fn inverse_matrix(&self) -> glm::Mat4 {
+        glm::translation(&self.pivot)
+            * glm::scaling(&glm::vec3(1.0 / self.scale.x, 1.0 / self.scale.y, 1.0 / self.scale.z))
+            * glm::quat_to_mat4(&glm::quat_inverse(&self.rotation))
+            * glm::translation(&-(self.translation + self.pivot))
+    }
+
+    // `child` followed by `self`, like multiplying `self.to_matrix() * child.to_matrix()`. With a
+    // non-uniform scale on `self` the product may have shear, and then only the matrix is exact.
+    pub fn compose(&self, child: &Transform) -> Self {
+        if !self.has_uniform_scale() {
+            return Transform::from_matrix(&(self.to_matrix() * child.to_matrix()));
+        }
+        Transform {
+            translation : self.transform_point(&child.transform_point(&glm::zero())),
+            rotation    : glm::quat_normalize(&(self.rotation * child.rotation)),
+            scale       : child.scale * self.scale.x,
+            pivot       : glm::zero(),
+        }
+    }
+
+    // Take an affine matrix apart into translation, rotation and scale, with the pivot at the
+    // origin. Any shear is lost. A mirroring matrix gets a negative X scale.
+    pub fn from_matrix(matrix: &glm::Mat4) -> Self {
+        let translation = glm::vec3(matrix[(0, 3)], matrix[(1, 3)], matrix[(2, 3)]);
+        let mut linear = glm::mat4_to_mat3(matrix);
+        let mut scale = glm::vec3(
+            glm::length(&linear.column(0).into_owned()),
+            glm::length(&linear.column(1).into_owned()),
+            glm::length(&linear.column(2).into_owned()),
+        );
+        if glm::determinant(&linear) < 0.0 {
+            scale.x = -scale.x;
+        }
+        for axis in 0..3 {
+            if scale[axis] != 0.0 {
+                let column = linear.column(axis) / scale[axis];
+                linear.set_column(axis, &column);
+            }
+        }
+        Transform {
+            translation,
+            rotation : glm::quat_normalize(&glm::to_quat(&glm::mat3_to_mat4(&linear))),
+            scale,
+            pivot    : glm::zero(),
+        }
+    }
+
+    // Blend from `self` at t = 0 to `other` at t = 1, turning along the shorter arc
+    pub fn interpolate(&self, other: &Transform, t: f32) -> Self {
+        Transform {
+            translation : glm::lerp(&self.translation, &other.translation, t),
+            rotation    : rotation::slerp(&self.rotation, &other.rotation, t),
+            scale       : glm::lerp(&self.scale, &other.scale, t),
+            pivot       : glm::lerp(&self.pivot, &other.pivot, t),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::rotation::EulerOrder;
+
+    fn assert_matrix_eq(a: &glm::Mat4, b: &glm::Mat4) {
+        assert!((a - b).abs().max() < 1e-4, "{} != {}", a, b);
+    }
+
+    fn assert_vec_eq(a: &glm::Vec3, b: &glm::Vec3) {
+        assert!((a - b).abs().max() < 1e-4, "{} != {}", a, b);
+    }
+
+    // The matrices `update_node_transformations` used to assemble by hand
+    fn old_node_matrix(position: glm::Vec3, angles: glm::Vec3, reference_point: glm::Vec3) -> glm::Mat4 {
+        let origin = glm::mat4(
+            1.0, 0.0, 0.0, reference_point[0],
+            0.0, 1.0, 0.0, reference_point[1],
+            0.0, 0.0, 1.0, reference_point[2],
+            0.0, 0.0, 0.0, 1.0,
+        );
+        let rotate_x = glm::mat4(
+            1.0, 0.0, 0.0, 0.0,
+            0.0, angles[0].cos(), -angles[0].sin(), 0.0,
+            0.0, angles[0].sin(), angles[0].cos(), 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        );
+        let rotate_y = glm::mat4(
+            angles[1].cos(), 0.0, angles[1].sin(), 0.0,
+            0.0, 1.0, 0.0, 0.0,
+            -angles[1].sin(), 0.0, angles[1].cos(), 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        );
+        let rotate_z = glm::mat4(
+            angles[2].cos(), -angles[2].sin(), 0.0, 0.0,
+            angles[2].sin(), angles[2].cos(), 0.0, 0.0,
+            0.0, 0.0, 1.0, 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        );
+        let inverse_origin = glm::mat4(
+            1.0, 0.0, 0.0, -reference_point[0],
+            0.0, 1.0, 0.0, -reference_point[1],
+            0.0, 0.0, 1.0, -reference_point[2],
+            0.0, 0.0, 0.0, 1.0,
+        );
+        let translation = glm::mat4(
+            1.0, 0.0, 0.0, position[0],
+            0.0, 1.0, 0.0, position[1],
+            0.0, 0.0, 1.0, position[2],
+            0.0, 0.0, 0.0, 1.0,
+        );
+        translation * origin * rotate_x * rotate_y * rotate_z * inverse_origin
+    }
+
+    fn example() -> Transform {
+        Transform {
+            translation : glm::vec3(3.0, -1.0, 2.5),
+            rotation    : rotation::from_euler(&glm::vec3(0.4, -1.2, 2.0), EulerOrder::XYZ),
+            scale       : glm::vec3(2.0, 2.0, 2.0),
+            pivot       : glm::vec3(0.35, 2.3, 10.4),
+        }
+    }
+
+    #[test]
+    fn matches_the_old_node_matrices() {
+        let cases = [
+            (glm::vec3(0.0, 0.0, 0.0), glm::vec3(0.0, 0.0, 0.0), glm::vec3(0.0, 0.0, 0.0)),
+            (glm::vec3(1.0, 2.0, 3.0), glm::vec3(0.3, 0.0, 0.0), glm::vec3(0.0, 0.0, 0.0)),
+            (glm::vec3(-4.0, 0.5, 9.0), glm::vec3(0.2, -1.1, 2.7), glm::vec3(0.35, 2.3, 10.4)),
+            (glm::vec3(0.0, 0.0, 0.0), glm::vec3(0.0, 5.0, 0.0), glm::vec3(0.0, 0.0, 0.0)),
+        ];
+        for (position, angles, reference_point) in cases {
+            let transform = Transform {
+                translation : position,
+                rotation    : rotation::from_euler(&angles, EulerOrder::XYZ),
+                scale       : glm::vec3(1.0, 1.0, 1.0),
+                pivot       : reference_point,
+            };
+            assert_matrix_eq(&transform.to_matrix(), &old_node_matrix(position, angles, reference_point));
+        }
+    }
+
+    #[test]
+    fn scale_is_about_the_pivot() {
+        let transform = Transform { scale: glm::vec3(2.0, 3.0, 4.0), pivot: glm::vec3(1.0, 1.0, 1.0), ..Transform::identity() };
+        assert_vec_eq(&transform.transform_point(&glm::vec3(1.0, 1.0, 1.0)), &glm::vec3(1.0, 1.0, 1.0));
+        assert_vec_eq(&transform.transform_point(&glm::vec3(2.0, 2.0, 2.0)), &glm::vec3(3.0, 4.0, 5.0));
+    }
+
+    #[test]
+    fn transform_point_matches_matrix() {
+        let transform = Transform { scale: glm::vec3(1.0, 0.5, 3.0), ..example() };
+        let point = glm::vec3(-2.0, 7.0, 0.5);
+        let by_matrix = transform.to_matrix() * glm::vec4(point.x, point.y, point.z, 1.0);
+        assert_vec_eq(&transform.transform_point(&point), &by_matrix.xyz());
+    }
+
+    #[test]
+    fn inverse_undoes_the_transform() {
+        let transform = example();
+        assert_matrix_eq(&(transform.inverse().to_matrix() * transform.to_matrix()), &glm::identity());
+        assert_matrix_eq(&(transform.to_matrix() * transform.inverse().to_matrix()), &glm::identity());
+
+        let stretched = Transform { scale: glm::vec3(1.0, 0.5, 3.0), ..example() };
+        assert_matrix_eq(&(stretched.inverse_matrix() * stretched.to_matrix()), &glm::identity());
+    }
+
+    #[test]
+    fn compose_matches_matrix_product() {
+        let parent = example();
+        let child = Transform {
+            translation : glm::vec3(0.0, 1.0, -2.0),
+            rotation    : rotation::from_euler(&glm::vec3(-0.3, 0.8, 0.1), EulerOrder::ZYX),
+            scale       : glm::vec3(1.0, 0.5, 3.0),
+            pivot       : glm::vec3(1.0, 0.0, 0.0),
+        };
+        assert_matrix_eq(&parent.compose(&child).to_matrix(), &(parent.to_matrix() * child.to_matrix()));
+    }
+
+    #[test]
+    fn from_matrix_round_trips() {
+        let transform = Transform { scale: glm::vec3(1.0, 0.5, 3.0), ..example() };
+        let decomposed = Transform::from_matrix(&transform.to_matrix());
+        assert_eq!(decomposed.pivot, glm::Vec3::zeros());
+        assert_vec_eq(&decomposed.scale, &transform.scale);
+        assert!(rotation::angle_between(&decomposed.rotation, &transform.rotation) < 1e-3);
+        assert_matrix_eq(&decomposed.to_matrix(), &transform.to_matrix());
+
+        let mirrored = Transform { scale: glm::vec3(-1.0, 1.0, 1.0), ..Transform::identity() };
+        assert_matrix_eq(&Transform::from_matrix(&mirrored.to_matrix()).to_matrix(), &mirrored.to_matrix());
+    }
+
+    #[test]
+    fn interpolate_hits_both_ends_and_the_middle() {
+        let from = Transform::identity();
+        let to = Transform {
+            translation : glm::vec3(2.0, 0.0, -4.0),
+            rotation    : rotation::from_euler(&glm::vec3(0.0, 1.0, 0.0), EulerOrder::XYZ),
+            scale       : glm::vec3(3.0, 3.0, 3.0),
+            pivot       : glm::zero(),
+        };
+        assert_matrix_eq(&from.interpolate(&to, 0.0).to_matrix(), &from.to_matrix());
+        assert_matrix_eq(&from.interpolate(&to, 1.0).to_matrix(), &to.to_matrix());
+
+        let halfway = from.interpolate(&to, 0.5);
+        assert_vec_eq(&halfway.translation, &glm::vec3(1.0, 0.0, -2.0));
+        assert_vec_eq(&halfway.scale, &glm::vec3(2.0, 2.0, 2.0));
+        assert!((rotation::angle_between(&from.rotation, &halfway.rotation) - 0.5).abs() < 1e-4);
+    }
+}
@@ -0,0 +1,369 @@
+use std::{mem, os::raw::c_void};
+
+use crate::gl_objects::{Buffer, Vao};
+use crate::mesh::Mesh;
+
+// Describes how the per-vertex data of a mesh is laid out in a single interleaved vertex buffer,
+// i.e. [pos color normal | pos color normal | ...], and which shader locations it feeds.
+
+// Which per-vertex array of a `Mesh` an attribute is read from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttributeName {
+    Position, // 3 floats
+    Color,    // 4 floats, RGBA
+    Normal,   // 3 floats
+    TexCoord, // 2 floats, UV
+    Tangent,  // 4 floats, XYZ and the sign of the bitangent
+}
+
+impl AttributeName {
+    // Number of floats per vertex this attribute has in a `Mesh`
+    pub fn mesh_components(&self) -> usize {
+        match self {
+            AttributeName::Position => 3,
+            AttributeName::Color    => 4,
+            AttributeName::Normal   => 3,
+            AttributeName::TexCoord => 2,
+            AttributeName::Tangent  => 4,
+        }
+    }
+
+    fn mesh_data<'a>(&self, mesh: &'a Mesh) -> &'a [f32] {
+        match self {
+            AttributeName::Position => &mesh.vertices,
+            AttributeName::Color    => &mesh.colors,
+            AttributeName::Normal   => &mesh.normals,
+            AttributeName::TexCoord => &mesh.uvs,
+            AttributeName::Tangent  => &mesh.tangents,
+        }
+    }
+
+    // What a vertex gets when the mesh doesn't have this attribute at all
+    fn default_component(&self, component: usize) -> f32 {
+        match (self, component) {
+            (AttributeName::Color, _)   => 1.0, // Opaque white
+            (AttributeName::Tangent, 3) => 1.0, // Right-handed
+            _ => 0.0,
+        }
+    }
+}
+
+// The type an attribute is stored as in the vertex buffer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AttributeType {
+    Float,
+    UnsignedByte,
+    Byte,
+}
+
+impl AttributeType {
+    pub fn size(&self) -> usize {
+        match self {
+            AttributeType::Float        => mem::size_of::<f32>(),
+            AttributeType::UnsignedByte => mem::size_of::<u8>(),
+            AttributeType::Byte         => mem::size_of::<i8>(),
+        }
+    }
+
+    pub fn gl_type(&self) -> gl::types::GLenum {
+        match self {
+            AttributeType::Float        => gl::FLOAT,
+            AttributeType::UnsignedByte => gl::UNSIGNED_BYTE,
+            AttributeType::Byte         => gl::BYTE,
+        }
+    }
+
+    fn write(&self, value: f32, normalized: bool, out: &mut Vec<u8>) {
+        match (self, normalized) {
+            (AttributeType::Float, _)            => out.extend_from_slice(&value.to_ne_bytes()),
+            (AttributeType::UnsignedByte, true)  => out.push((value.clamp(0.0, 1.0) * 255.0).round() as u8),
+            (AttributeType::UnsignedByte, false) => out.push(value as u8),
+            (AttributeType::Byte, true)          => out.push((value.clamp(-1.0, 1.0) * 127.0).round() as i8 as u8),
+            (AttributeType::Byte, false)         => out.push(value as i8 as u8),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct VertexAttribute {
+    pub name       : AttributeName,
+    pub location   : u32,           // The `layout(location = ...)` it is bound to in the shader
+    pub components : usize,         // 1 to 4
+    pub kind       : AttributeType,
+    pub normalized : bool,          // Whether integer types are mapped to [0, 1] or [-1, 1]
+    pub offset     : usize,         // Bytes from the start of a vertex, filled in by the layout
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct VertexLayout {
+    attributes : Vec<VertexAttribute>,
+    stride     : usize,
+}
+
+impl VertexLayout {
+    pub fn new() -> Self {
+        VertexLayout::default()
+    }
+
+    // Append an attribute to every vertex. Each attribute starts on a 4 byte boundary,
+    // as some drivers are slow at reading unaligned attributes.
+    pub fn with(mut self, name: AttributeName, location: u32, components: usize, kind: AttributeType, normalized: bool) -> Self {
+        assert!((1..=4).contains(&components), "An attribute must have between 1 and 4 components");
+        self.attributes.push(VertexAttribute {
+            name,
+            location,
+            components,
+            kind,
+            normalized,
+            offset: self.stride,
+        });
+        self.stride += align_to_four(components * kind.size());
+        self
+    }
+
+    // Everything `mesh` has data for, as full precision floats, at the locations the shaders expect:
+    // 0 position, 1 color, 2 normal, 3 UV, 4 tangent
+    pub fn for_mesh(mesh: &Mesh) -> Self {
+        let mut layout = VertexLayout::new()
+            .with(AttributeName::Position, 0, 3, AttributeType::Float, false)
+            .with(AttributeName::Color,    1, 4, AttributeType::Float, false);
+        if !mesh.normals.is_empty() {
+            layout = layout.with(AttributeName::Normal, 2, 3, AttributeType::Float, false);
+        }
+        if !mesh.uvs.is_empty() {
+            layout = layout.with(AttributeName::TexCoord, 3, 2, AttributeType::Float, false);
+        }
+        if !mesh.tangents.is_empty() {
+            layout = layout.with(AttributeName::Tangent, 4, 4, AttributeType::Float, false);
+        }
+        layout
+    }
+
+    pub fn attributes(&self) -> &[VertexAttribute] {
+        &self.attributes
+    }
+
+    // Size of a single vertex in bytes
+    pub fn stride(&self) -> usize {
+        self.stride
+    }
+
+    // Interleave the attributes of `mesh` into a single buffer following this layout.
+    // Attributes the mesh has no data for are filled in with defaults.
+    pub fn pack(&self, mesh: &Mesh) -> Vec<u8> {
+        self.pack_range(mesh, 0, mesh.vertices.len() / 3)
+    }
+
+    // Like `pack`, but only for the `count` vertices starting at `first`
+    pub fn pack_range(&self, mesh: &Mesh, first: usize, count: usize) -> Vec<u8> {
+        let vertex_count = mesh.vertices.len() / 3;
+        if first + count > vertex_count {
+            panic!("Tried to pack vertices {}..{} of a mesh with {} vertices", first, first + count, vertex_count);
+        }
+        let mut data = Vec::with_capacity(count * self.stride);
+
+        for attribute in &self.attributes {
+            let source = attribute.name.mesh_data(mesh);
+            if !source.is_empty() && source.len() != vertex_count * attribute.name.mesh_components() {
+                panic!("Mesh has {} values for {:?}, expected {} for {} vertices",
+                    source.len(), attribute.name, vertex_count * attribute.name.mesh_components(), vertex_count);
+            }
+        }
+
+        for vertex in first..first + count {
+            for attribute in &self.attributes {
+                let start = data.len();
+                let source = attribute.name.mesh_data(mesh);
+                let width = attribute.name.mesh_components();
+                for component in 0..attribute.components {
+                    let value = if source.is_empty() || component >= width {
+                        attribute.name.default_component(component)
+                    } else {
+                        source[vertex * width + component]
+                    };
+                    attribute.kind.write(value, attribute.normalized, &mut data);
+                }
+                // Pad up to where the next attribute starts
+                data.resize(start + align_to_four(attribute.components * attribute.kind.size()), 0);
+            }
+        }
+
+        data
+    }
+}
+
+fn align_to_four(bytes: usize) -> usize {
+    (bytes + 3) & !3
+}
+
+// Packs a mesh into one interleaved vertex buffer plus an index buffer, and sets up a VAO for them
+pub struct VertexArrayBuilder<'a> {
+    mesh   : &'a Mesh,
+    layout : VertexLayout,
+    usage  : gl::types::GLenum,
+}
+
+impl<'a> VertexArrayBuilder<'a> {
+    pub fn new(mesh: &'a Mesh) -> Self {
+        VertexArrayBuilder {
+            mesh,
+            layout: VertexLayout::for_mesh(mesh),
+            usage: gl::STATIC_DRAW,
+        }
+    }
+
+    // How often the vertex data will change: STATIC_DRAW (the default) if never, DYNAMIC_DRAW if
+    // now and then through `Vao::update_vertices`, STREAM_DRAW if every frame
+    pub fn usage(mut self, usage: gl::types::GLenum) -> Self {
+        self.usage = usage;
+        self
+    }
+
+    pub fn layout(mut self, layout: VertexLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
+    pub unsafe fn build(self) -> Vao {
+        let vertex_data = self.layout.pack(self.mesh);
+
+        let mut vao_id = 0;
+        gl::GenVertexArrays(1, &mut vao_id);
+        gl::BindVertexArray(vao_id);
+
+        let vertex_buffer = Buffer::new(gl::ARRAY_BUFFER, &vertex_data, self.usage);
+        let index_buffer = Buffer::new(gl::ELEMENT_ARRAY_BUFFER, &self.mesh.indices, gl::STATIC_DRAW);
+
+        for attribute in self.layout.attributes() {
+            gl::EnableVertexAttribArray(attribute.location);
+            gl::VertexAttribPointer(
+                attribute.location,
+                attribute.components as i32,
+                attribute.kind.gl_type(),
+                if attribute.normalized { gl::TRUE } else { gl::FALSE },
+                self.layout.stride() as i32,
+                attribute.offset as *const c_void,
+            );
+        }
+
+        Vao::from_parts(
+            vao_id,
+            vertex_buffer,
+            index_buffer,
+            self.mesh.vertices.len() / 3,
+            self.mesh.index_count,
+            self.layout,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two vertices with positions, colors and normals, but no UVs or tangents
+    fn two_vertices() -> Mesh {
+        Mesh {
+            vertices    : vec![1.0, 2.0, 3.0,  4.0, 5.0, 6.0],
+            normals     : vec![0.0, 1.0, 0.0,  0.0, -1.0, 0.0],
+            colors      : vec![1.0, 0.5, 0.0, 1.0,  0.0, 0.0, 1.0, 0.25],
+            uvs         : vec![],
+            tangents    : vec![],
+            indices     : vec![0, 1],
+            index_count : 2,
+        }
+    }
+
+    fn floats(bytes: &[u8]) -> Vec<f32> {
+        bytes.chunks_exact(4).map(|bytes| f32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])).collect()
+    }
+
+    fn offsets(layout: &VertexLayout) -> Vec<(AttributeName, u32, usize)> {
+        layout.attributes().iter().map(|attribute| (attribute.name, attribute.location, attribute.offset)).collect()
+    }
+
+    #[test]
+    fn for_mesh_only_includes_what_the_mesh_has() {
+        let mut mesh = two_vertices();
+        mesh.normals.clear();
+        let layout = VertexLayout::for_mesh(&mesh);
+        assert_eq!(offsets(&layout), vec![(AttributeName::Position, 0, 0), (AttributeName::Color, 1, 12)]);
+        assert_eq!(layout.stride(), 28);
+
+        let mut mesh = two_vertices();
+        mesh.uvs = vec![0.0, 0.0,  1.0, 1.0];
+        mesh.tangents = vec![1.0, 0.0, 0.0, 1.0,  1.0, 0.0, 0.0, -1.0];
+        let layout = VertexLayout::for_mesh(&mesh);
+        assert_eq!(offsets(&layout), vec![
+            (AttributeName::Position, 0, 0),
+            (AttributeName::Color,    1, 12),
+            (AttributeName::Normal,   2, 28),
+            (AttributeName::TexCoord, 3, 40),
+            (AttributeName::Tangent,  4, 48),
+        ]);
+        assert_eq!(layout.stride(), 64);
+    }
+
+    #[test]
+    fn pack_interleaves_the_vertices() {
+        let mesh = two_vertices();
+        let layout = VertexLayout::for_mesh(&mesh);
+        let data = layout.pack(&mesh);
+        assert_eq!(data.len(), 2 * layout.stride());
+        assert_eq!(floats(&data), vec![
+            1.0, 2.0, 3.0,  1.0, 0.5, 0.0, 1.0,   0.0, 1.0, 0.0,
+            4.0, 5.0, 6.0,  0.0, 0.0, 1.0, 0.25,  0.0, -1.0, 0.0,
+        ]);
+    }
+
+    #[test]
+    fn missing_attributes_get_defaults() {
+        let mut mesh = two_vertices();
+        mesh.colors.clear();
+        let layout = VertexLayout::new()
+            .with(AttributeName::Color,    1, 4, AttributeType::Float, false)
+            .with(AttributeName::TexCoord, 3, 2, AttributeType::Float, false)
+            .with(AttributeName::Tangent,  4, 4, AttributeType::Float, false);
+        assert_eq!(layout.stride(), 40);
+        assert_eq!(floats(&layout.pack(&mesh)), [1.0, 1.0, 1.0, 1.0,  0.0, 0.0,  0.0, 0.0, 0.0, 1.0].repeat(2));
+    }
+
+    #[test]
+    fn small_types_are_padded_to_four_bytes() {
+        let mesh = two_vertices();
+        let layout = VertexLayout::new()
+            .with(AttributeName::Position, 0, 3, AttributeType::Float,        false)
+            .with(AttributeName::Color,    1, 4, AttributeType::UnsignedByte, true)
+            .with(AttributeName::Normal,   2, 3, AttributeType::Byte,         true);
+        assert_eq!(offsets(&layout), vec![
+            (AttributeName::Position, 0, 0),
+            (AttributeName::Color,    1, 12),
+            (AttributeName::Normal,   2, 16),
+        ]);
+        assert_eq!(layout.stride(), 20);
+
+        let data = layout.pack(&mesh);
+        assert_eq!(data.len(), 40);
+        assert_eq!(floats(&data[0..12]), vec![1.0, 2.0, 3.0]);
+        assert_eq!(data[12..20], [255, 128, 0, 255,  0, 127, 0, 0]);
+        assert_eq!(floats(&data[20..32]), vec![4.0, 5.0, 6.0]);
+        assert_eq!(data[32..40], [0, 0, 255, 64,  0, -127i8 as u8, 0, 0]);
+    }
+
+    #[test]
+    fn pack_range_packs_a_slice_of_the_vertices() {
+        let mesh = two_vertices();
+        let layout = VertexLayout::for_mesh(&mesh);
+        let all = layout.pack(&mesh);
+        assert_eq!(layout.pack_range(&mesh, 1, 1), all[layout.stride()..]);
+        assert_eq!(layout.pack_range(&mesh, 0, 2), all);
+        assert!(layout.pack_range(&mesh, 2, 0).is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Tried to pack vertices 1..3")]
+    fn pack_range_past_the_end_panics() {
+        let mesh = two_vertices();
+        VertexLayout::for_mesh(&mesh).pack_range(&mesh, 1, 2);
+    }
+}